@@ -1,47 +1,256 @@
 /// Vim REST Client helper script.
 /// Parses output filtered from the .rest file by Vim.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::io::{self, BufRead};
+use std::future::Future;
+use std::io::{self, BufRead, Write};
+use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use base64::encode;
 use jq_rs;
 use openssh::{Session, SessionBuilder};
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
 use regex::{Regex, Captures};
 use serde_json::{self, Value, json};
 use tokio::runtime::Runtime;
 
+pub mod process_for;
+pub mod process_if;
 pub mod process_while;
 
 pub const ENV_FILE: &str = ".env.json";
+// base env, relative to the home directory, loaded beneath the env file
+const BASE_ENV_FILE: &str = ".rest-client/env.json";
+// schema of the env variables, next to the env file
+const ENV_SCHEMA_FILE: &str = ".env.schema.json";
 
 // SSH config vars
 const SSH_TO: &str = "sshTo";
 const SSH_CONFIG: &str = "sshConfig";
 const SSH_KEY: &str = "sshKey";
 const SSH_PORT: &str = "sshPort";
+const SSH_JUMP_HOST: &str = "sshJumpHost";
+const SSH_HEALTH_CHECK_TIMEOUT: &str = "sshHealthCheckTimeout";
+
+// default SOCKS5 proxy for requests without @proxy or @socks5
+const SOCKS5_PROXY: &str = "socks5Proxy";
+
+// time a cached SSH session has to answer before it is reconnected
+const DEFAULT_SSH_HEALTH_CHECK_TIMEOUT_MS: u64 = 5000;
+
+// built-in var with the time taken by the last request
+pub const LAST_REQUEST_MS: &str = "last_request_ms";
+
+// built-in vars with the rate limit headers of the last response, when shown
+pub const RATE_LIMIT_REMAINING: &str = "__rate_limit_remaining";
+pub const RATE_LIMIT_RESET: &str = "__rate_limit_reset";
+
+// curl options that change what is output, so the headers aren't included
+const OUTPUT_OPTIONS: [&str; 12] = [
+    "-o", "--output", "-O", "--remote-name", "-D", "--dump-header",
+    "-i", "--include", "-I", "--head", "-w", "--write-out",
+];
+
+// if requests skip verifying certificates (-k for curl) unless told otherwise
+const DEFAULT_INSECURE: bool = true;
+
+// default maximum size of a response shown in the fold
+pub const RESPONSE_BODY_MAX_BYTES: usize = 1024 * 1024;
+
+// base back-off between request retries, multiplied by the attempt number
+const RETRY_BACKOFF_MS: u64 = 500;
+
+// curl exit codes for failures that may go away, couldn't resolve host and
+// couldn't connect, and the waits before each retry of them
+const TRANSIENT_CURL_CODES: [i32; 2] = [6, 7];
+const TRANSIENT_RETRY_DELAYS_MS: [u64; 3] = [1000, 2000, 4000];
+
+// max pages requested by @paginate, including the first
+const MAX_PAGES: usize = 100;
+
+// @paginate expression following the rel="next" URL of the Link header
+const PAGINATE_LINK: &str = "link";
+
+// max size of the response body in a @log entry
+const LOG_BODY_MAX_BYTES: usize = 1024;
+
+// shortest sensitive or secret value replaced wherever it appears in the
+// output, since shorter ones like 1 or true would also hide unrelated numbers
+// and words
+const REDACT_MIN_LEN: usize = 4;
+
+// max levels of nested substitutions before giving up on a selector
+const MAX_SELECTOR_DEPTH: usize = 16;
+
+// prefix of the env key storing the expression of a computed variable
+const COMPUTED_PREFIX: &str = "__computed__";
+
+// line between the input and the output of a fold, unless --separator is set
+pub const DEFAULT_SEPARATOR: &str = "##########";
+
+// markers starting and ending a fold
+pub const FOLD_START: &str = "###{";
+pub const FOLD_END: &str = "###}";
+
+// statuses shown on the start marker of an executed fold, as a regex alternation
+pub(crate) const STATUSES: &str = "ERROR|SUCCESS|SKIPPED|FIXTURE CREATED";
+
+/// Errors that can happen while running a fold
+#[derive(Debug)]
+pub enum RestClientError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Jq(String),
+    Curl(String, i32),          // curl's stderr, and its exit code
+    Ssh(String),
+    SelectorNotFound(String),   // the selector that evaluated to null
+    ParseError(String),
+}
+
+impl fmt::Display for RestClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestClientError::Io(e) => write!(f, "{}", e),
+            RestClientError::Json(e) => write!(f, "{}", e),
+            RestClientError::Jq(e) => write!(f, "{}", e),
+            RestClientError::Curl(e, _) => write!(f, "{}", e),
+            RestClientError::Ssh(e) => write!(f, "{}", e),
+            RestClientError::SelectorNotFound(selector) => write!(f, "failed to get resource at {}", selector),
+            RestClientError::ParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RestClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RestClientError::Io(e) => Some(e),
+            RestClientError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RestClientError {
+    fn from(e: io::Error) -> RestClientError {
+        RestClientError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RestClientError {
+    fn from(e: serde_json::Error) -> RestClientError {
+        RestClientError::Json(e)
+    }
+}
+
+impl From<jq_rs::Error> for RestClientError {
+    fn from(e: jq_rs::Error) -> RestClientError {
+        RestClientError::Jq(e.to_string())
+    }
+}
+
+impl From<openssh::Error> for RestClientError {
+    fn from(e: openssh::Error) -> RestClientError {
+        RestClientError::Ssh(e.to_string())
+    }
+}
+
+impl From<regex::Error> for RestClientError {
+    fn from(e: regex::Error) -> RestClientError {
+        RestClientError::ParseError(e.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for RestClientError {
+    fn from(e: serde_yaml::Error) -> RestClientError {
+        RestClientError::ParseError(e.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for RestClientError {
+    fn from(e: toml::ser::Error) -> RestClientError {
+        RestClientError::ParseError(e.to_string())
+    }
+}
+
+/// Formats the env can be exported to
+pub enum EnvExportFormat {
+    Toml,
+    Yaml,
+    Dotenv,
+    Json,
+}
+
+impl EnvExportFormat {
+    pub fn get_match(s: &str) -> Option<EnvExportFormat> {
+        match s.to_lowercase().as_str() {
+            "toml" => Some(EnvExportFormat::Toml),
+            "yaml" | "yml" => Some(EnvExportFormat::Yaml),
+            "dotenv" | "env" => Some(EnvExportFormat::Dotenv),
+            "json" => Some(EnvExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Formats the results of a run can be rendered in
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    VimFold,    // the folds as they are in the buffer
+    Markdown,   // a report with a section for each fold
+    Json,       // an array with an object for each fold
+}
+
+impl OutputFormat {
+    pub fn get_match(s: &str) -> Option<OutputFormat> {
+        match s.to_lowercase().as_str() {
+            "vim" => Some(OutputFormat::VimFold),
+            "markdown" | "md" => Some(OutputFormat::Markdown),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How nested folds share the env with the folds around them
+#[derive(Clone, Copy, PartialEq)]
+pub enum EnvScope {
+    Inherited,  // variables set in a nested fold are kept after it
+    Isolated,   // only the variables exported by a nested fold are kept after it
+}
 
 #[derive(Clone)]
-enum Method {
+pub enum Method {
     Get,
     Post,
     Delete,
     Put,
+    Patch,
+    Head,
+    Options,
     Other(String)
 }
 
 impl Method {
-    fn get_match(s: &str) -> Method {
+    pub fn get_match(s: &str) -> Method {
         match s.to_lowercase().as_str() {
             "get" => Method::Get,
             "post" => Method::Post,
             "delete" => Method::Delete,
             "put" => Method::Put,
+            "patch" => Method::Patch,
+            "head" => Method::Head,
+            "options" => Method::Options,
             _ => Method::Other(s.to_uppercase()),
         }
     }
@@ -54,6 +263,9 @@ impl fmt::Display for Method {
             Method::Post => "POST",
             Method::Delete => "DELETE",
             Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
             Method::Other(s) => &s,
         };
         write!(f, "{}", method_str)
@@ -64,6 +276,7 @@ enum Response {
     NoSplit(String), // whole response
     NonJson(String, String), // headers, response
     Json(String, Value), // headers, JSON response
+    Saved(String, String), // headers, path the response body was saved to
 }
 impl Response {
     /// Handles cases of more than one \n\n
@@ -106,20 +319,94 @@ impl Response {
             )
     }
 
+    /// A response that is only the body, like with @no-include, which is
+    /// still parsed as JSON if it can be
+    fn body_only(body: String) -> Response {
+        serde_json::from_str::<Value>(&body)
+            .map_or_else(
+                |_| Response::NonJson(String::new(), body.clone()),
+                |r_json| Response::Json(String::new(), r_json)
+            )
+    }
+
+    /// Headers of the response, or an empty string if there were none
+    fn headers(&self) -> String {
+        match self {
+            Response::NoSplit(_) => String::new(),
+            Response::NonJson(headers, _)
+                | Response::Json(headers, _)
+                | Response::Saved(headers, _) => headers.clone(),
+        }
+    }
+
+    /// Body of the response, without the headers
+    fn body(&self) -> String {
+        match self {
+            Response::NoSplit(response) => response.clone(),
+            Response::NonJson(_, resp) => resp.clone(),
+            Response::Json(_, val) => val.to_string(),
+            Response::Saved(_, _) => String::new(),
+        }
+    }
+
     fn get_return(self) -> (String, Value) {
         match self {
             Response::NoSplit(response) => (response, json!("")),
-            Response::NonJson(headers, resp) => (format!("{}\n\n{}", headers, resp), json!(resp)),
+            Response::NonJson(headers, resp) => {
+                let xml_re = Regex::new(r"(?mi)^(?:< )?content-type:.*[/+]xml").unwrap();
+                let yaml_re = Regex::new(r"(?mi)^(?:< )?content-type:.*[/+-]yaml").unwrap();
+                if yaml_re.is_match(&headers) {
+                    if let Some((print_resp, val)) = parse_yaml(&resp) {
+                        return (with_headers(&headers, &print_resp), val);
+                    }
+                }
+                let print_resp = if xml_re.is_match(&headers) {
+                    pretty_xml(&resp).unwrap_or(resp.clone())
+                } else {
+                    resp.clone()
+                };
+                (with_headers(&headers, &print_resp), json!(resp))
+            },
             Response::Json(headers, val) => {
                 let print_json: String = serde_json::to_string_pretty(&val)
                     .or::<String>(Ok(val.to_string()))
                     .unwrap();
-                (format!("{}\n\n{}", headers, print_json), val)
+                (with_headers(&headers, &print_json), val)
+            },
+            Response::Saved(headers, path) => {
+                let saved = format!("Response saved to {}", path);
+                (with_headers(&headers, &saved), json!(path))
             },
         }
     }
 }
 
+/// The size in bytes, in B, KB or MB
+fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// The count followed by the noun, made plural unless the count is 1
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 {""} else {"s"})
+}
+
+/// The headers and the body shown below them, or only the body if there are
+/// no headers
+fn with_headers(headers: &str, body: &str) -> String {
+    if headers.is_empty() {
+        String::from(body)
+    } else {
+        format!("{}\n\n{}", headers, body)
+    }
+}
+
 struct Request {
     method: Method,
     url: String,
@@ -127,24 +414,107 @@ struct Request {
     data: Option<String>,
     multipart_forms: Vec<String>,
     options: Vec<String>,
+    timeout: Option<u64>,
+    connect_timeout: Option<String>,
+    max_response_size: usize,
+    proxy: Option<String>,
+    socks5: Option<String>,
+    interface: Option<String>,
+    dns_servers: Vec<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    cookie_jar: Option<String>,
+    follow: bool,
+    max_redirects: Option<u64>,
+    http2: bool,
+    http3: bool,
+    http_version: Option<String>,
+    retry_transient: bool,
+    colon_params: bool,
+    output: Option<String>,
+    form_encoded: bool,
+    body_file: Option<String>,
+    body_template: Option<String>,
+    insecure: bool,
+    graphql: bool,
+    graphql_variables: Option<String>,
+    sensitive: Vec<String>,
+    no_include: bool,
 }
 
 impl Request {
+    /// A request with only the method, URL, headers and body set, and the
+    /// defaults of a fold for everything else
+    fn new
+    (
+        method: Method,
+        url: &str,
+        headers: Vec<String>,
+        data: Option<String>,
+        insecure: bool,
+    ) -> Request {
+        Request {
+            method,
+            url: String::from(url),
+            headers,
+            data,
+            multipart_forms: Vec::new(),
+            options: Vec::new(),
+            timeout: None,
+            connect_timeout: None,
+            max_response_size: RESPONSE_BODY_MAX_BYTES,
+            proxy: None,
+            socks5: None,
+            interface: None,
+            dns_servers: Vec::new(),
+            cert: None,
+            key: None,
+            cookie_jar: None,
+            follow: false,
+            max_redirects: None,
+            http2: false,
+            http3: false,
+            http_version: None,
+            retry_transient: false,
+            colon_params: false,
+            output: None,
+            form_encoded: false,
+            body_file: None,
+            body_template: None,
+            insecure,
+            graphql: false,
+            graphql_variables: None,
+            sensitive: Vec::new(),
+            no_include: false,
+        }
+    }
+
+    /// The URL curl is given, with the :name path parameters and {{}}
+    /// substitutions replaced
+    fn resolved_url(&self, g_env: &mut GlobalEnv) -> Result<String, RestClientError> {
+        let url = if self.colon_params || g_env.use_colon_params {
+            replace_colon_params(g_env, &self.url)?
+        } else {
+            self.url.clone()
+        };
+        g_env.parse_selectors(&url)
+    }
+
     /// Calls curl with appropriate args to make the desired request
     /// Substitutions can happen with {{}} and a variable name, or jq-syntax for
     /// selecting fields from a variable.
-    /// Return the response headers and response body (pretty-printed, if JSON),
-    /// or the error with error cause if curl failed.
-    /// (String, Value) = (entire response string with headers, just response)
+    /// Return the response, split into headers and body, or the error with error
+    /// cause if curl failed. In debug mode the response is the curl command.
+    /// If there is an output file, the response body is saved to it instead.
     fn make_request
     (
         &self,
         g_env: &mut GlobalEnv,
         is_debug: bool,
         is_verbose: bool,
-    ) -> Result<(String, Value), Box<dyn Error>> {
+    ) -> Result<Response, RestClientError> {
         let method = self.method.to_string();
-        let url = g_env.parse_selectors(&self.url)?;
+        let url = self.resolved_url(g_env)?;
         let mut header_err: Option<String> = None;
         let basic_auth_re = Regex::new(r"^(Authorization:\s+Basic\s+)([^:]+:[^:]+)$").unwrap();
         let headers = self.headers.iter().map(|header| {
@@ -180,35 +550,202 @@ impl Request {
         if let Some(e) = &header_err {
             return Err(io_error(&e))?;
         }
-        let data = if let Some(data) = &self.data {
+        let mut data = if let Some(path) = &self.body_template {
+            // the template is read here even over SSH, since it is sent with -d
+            let path = g_env.parse_selectors(path)?;
+            let template = fs::read_to_string(&path)
+                .map_err(|_| io_error(&format!("body template {} does not exist", path)))?;
+            Some(g_env.parse_selectors(&String::from(template.trim_end()))?)
+        } else if let Some(data) = &self.data {
             Some(g_env.parse_selectors(&data)?)
         } else {
             None
         };
-        let is_verbose = is_verbose
+        if let (Some(query), true) = (&data, self.graphql) {
+            let variables = match &self.graphql_variables {
+                Some(variables) => serde_json::from_str::<Value>(&g_env.parse_selectors(variables)?)?,
+                None => json!({}),
+            };
+            data = Some(json!({"query": query, "variables": variables}).to_string());
+        }
+        // curl gets the compact body, but in debug mode it is also shown formatted
+        let debug_body = data.as_deref()
+            .filter(|_| is_debug)
+            .and_then(|d| format_json_body(d, &self.sensitive));
+        let proxy = parse_optional_selectors(g_env, &self.proxy)?;
+        if proxy.is_some() && self.socks5.is_some() {
+            return Err(io_error("@proxy and @socks5 can't both be set"));
+        }
+        // the env can set a default SOCKS5 proxy, used when the fold sets none
+        let socks5 = match (&self.socks5, &proxy) {
+            (Some(_), _) => parse_optional_selectors(g_env, &self.socks5)?,
+            (None, None) => match g_env.env.get(SOCKS5_PROXY).cloned() {
+                Some(Value::String(socks5)) => Some(g_env.parse_selectors(&socks5)?),
+                Some(_) => return Err(io_error(&format!("{} was not a string", SOCKS5_PROXY))),
+                None => None,
+            },
+            (None, Some(_)) => None,
+        };
+        let interface = parse_optional_selectors(g_env, &self.interface)?;
+        let connect_timeout = parse_optional_selectors(g_env, &self.connect_timeout)?;
+        if let Some(timeout) = &connect_timeout {
+            // curl takes fractions of a second too
+            if !timeout.parse::<f64>().is_ok_and(|secs| secs >= 0.0) {
+                return Err(io_error(&format!(
+                    "invalid @connect-timeout {}, expected a number of seconds", timeout
+                )));
+            }
+        }
+        let dns_servers = self.dns_servers.iter()
+            .map(|server| g_env.parse_selectors(server))
+            .collect::<Result<Vec<String>, RestClientError>>()?;
+        let cert = parse_optional_selectors(g_env, &self.cert)?;
+        let key = parse_optional_selectors(g_env, &self.key)?;
+        let mut cookie_jar = parse_optional_selectors(g_env, &self.cookie_jar)?;
+        if let (Some(jar), None) = (&cookie_jar, g_env.env.get(SSH_TO)) {
+            // over SSH the path is on the remote machine, so leave it as is
+            cookie_jar = Some(env::current_dir()?.join(jar).to_string_lossy().to_string());
+        }
+        let body_file = parse_optional_selectors(g_env, &self.body_file)?;
+        if let (Some(path), None) = (&body_file, g_env.env.get(SSH_TO)) {
+            // over SSH the file is on the remote machine, where curl checks it
+            if !Path::new(path).is_file() {
+                return Err(io_error(&format!("body file {} does not exist", path)));
+            }
+        }
+        let output = parse_optional_selectors(g_env, &self.output)?;
+        // over SSH curl can't write the file locally, so the body is saved here
+        let save_locally = output.is_some() && g_env.env.get(SSH_TO).is_some();
+        // without the headers, the verbose output couldn't be split either
+        let is_verbose = !self.no_include && (is_verbose
             || options.contains(&String::from("-v"))
-            || options.contains(&String::from("--verbose"));
-        let mut args = vec![String::from("-k")];
-        if is_verbose {
+            || options.contains(&String::from("--verbose")));
+        let mut args = Vec::new();
+        if self.insecure {
+            args.push(String::from("-k"));
+        }
+        if self.no_include {
+            // curl only prints the body
+        } else if is_verbose {
             args.push(String::from("-v"));
-        } else if options.is_empty() {
+        } else if output.is_some() && !save_locally {
+            // the body goes to the file, so only the headers are on stdout
+            args.push(String::from("-D"));
+            args.push(String::from("-"));
+        } else if !options.iter().any(|option| OUTPUT_OPTIONS.contains(&option.as_str())) {
+            // options that already choose what curl prints replace --include
             args.push(String::from("--include"));
         }
         args.push(String::from(url));
-        args.push(String::from("-X"));
-        args.push(String::from(method));
+        if let Some(p) = proxy {
+            args.push(String::from("-x"));
+            args.push(p);
+        }
+        if let Some(p) = socks5 {
+            args.push(String::from("--socks5"));
+            args.push(p);
+        }
+        if let Some(i) = interface {
+            args.push(String::from("--interface"));
+            args.push(i);
+        }
+        if !dns_servers.is_empty() {
+            args.push(String::from("--dns-servers"));
+            args.push(dns_servers.join(","));
+        }
+        if let Method::Head = self.method {
+            // -X HEAD makes curl wait for a body that never comes
+            args.push(String::from("--head"));
+        } else {
+            args.push(String::from("-X"));
+            args.push(String::from(method));
+        }
+        let content_type_re = Regex::new(r"(?i)^content-type:").unwrap();
+        let content_type = if self.graphql {
+            Some("application/json")
+        } else if self.form_encoded {
+            Some("application/x-www-form-urlencoded")
+        } else {
+            None
+        };
+        let set_content_type = content_type
+            .filter(|_| !headers.iter().any(|header| content_type_re.is_match(header)));
         for header in headers {
             args.push(String::from("-H"));
             args.push(String::from(header));
         }
-        if let Some(d) = data {
+        if let Some(content_type) = set_content_type {
+            args.push(String::from("-H"));
+            args.push(format!("Content-Type: {}", content_type));
+        }
+        if let (Some(d), true) = (&data, self.form_encoded && !self.graphql) {
+            // curl encodes the value of each name=value pair
+            for pair in d.split('&').filter(|pair| !pair.is_empty()) {
+                args.push(String::from("--data-urlencode"));
+                args.push(String::from(pair));
+            }
+        } else if let Some(d) = data {
             args.push(String::from("-d"));
             args.push(String::from(d));
         }
+        if let Some(path) = body_file {
+            // the body is sent as is, without curl stripping newlines
+            args.push(String::from("--data-binary"));
+            args.push(format!("@{}", path));
+        }
         for form in multipart_forms {
             args.push(String::from("-F"));
             args.push(String::from(form));
         }
+        if let Some(timeout) = self.timeout {
+            args.push(String::from("--max-time"));
+            args.push(timeout.to_string());
+        }
+        if let Some(timeout) = connect_timeout {
+            args.push(String::from("--connect-timeout"));
+            args.push(timeout);
+        }
+        if self.follow || self.max_redirects.is_some() {
+            args.push(String::from("-L"));
+        }
+        if let Some(max_redirects) = self.max_redirects {
+            args.push(String::from("--max-redirs"));
+            args.push(max_redirects.to_string());
+        }
+        if let Some(version) = &self.http_version {
+            let arg = match version.as_str() {
+                "1.0" => "--http1.0",
+                "1.1" => "--http1.1",
+                "2" => "--http2",
+                "3" => "--http3",
+                _ => return Err(io_error(&format!(
+                    "invalid @http-version {}, expected 1.0, 1.1, 2 or 3", version
+                ))),
+            };
+            args.push(String::from(arg));
+        } else if self.http2 {
+            args.push(String::from("--http2"));
+        } else if self.http3 {
+            args.push(String::from("--http3"));
+        }
+        if let Some(c) = cert {
+            args.push(String::from("--cert"));
+            args.push(c);
+        }
+        if let Some(k) = key {
+            args.push(String::from("--key"));
+            args.push(k);
+        }
+        if let Some(jar) = cookie_jar {
+            args.push(String::from("-c"));
+            args.push(jar.clone());
+            args.push(String::from("-b"));
+            args.push(jar);
+        }
+        if let (Some(o), false) = (&output, save_locally) {
+            args.push(String::from("-o"));
+            args.push(o.clone());
+        }
         for option in options {
             args.push(String::from(option));
         }
@@ -218,16 +755,189 @@ impl Request {
                 .map(|arg| match arg {
                     arg if arg.contains(" ") && arg.contains("\"") => format!("'{}'", arg),
                     arg if arg.contains(" ") => format!("\"{}\"", arg),
+                    // like the ;type= of a form, which would end the command
+                    arg if arg.contains(";") => format!("'{}'", arg),
                     arg => arg.clone(),
                 })
             .collect::<Vec<String>>();
-            return Ok((quoted.join(" "), json!("")));
+            let mut command = quoted.join(" ");
+            if let Some(body) = debug_body {
+                command.push_str(&format!("\n{}", body));
+            }
+            return Ok(Response::NoSplit(command));
+        }
+        if let (Some(path), true) = (&output, save_locally) {
+            // the body is written as curl got it, so binary files aren't changed
+            let (ret, e) = g_env.call_curl_raw(&args, self.retry_transient)?;
+            let (headers, body) = if self.no_include {
+                (String::new(), &ret[..])
+            } else if is_verbose {
+                (e, &ret[..])
+            } else {
+                split_raw_response(&ret)
+            };
+            fs::write(path, body)?;
+            return Ok(Response::Saved(headers, path.clone()));
+        }
+        if let Some(path) = output {
+            // curl writes the body to the file, and only the headers to stdout
+            let (ret, e) = g_env.call_curl(&args, usize::MAX, self.retry_transient)?;
+            let response = Response::new(ret, e, is_verbose);
+            return Ok(Response::Saved(response.headers(), path));
+        }
+        let (ret, e) = g_env.call_curl(&args, self.max_response_size, self.retry_transient)?;
+        if self.no_include {
+            return Ok(Response::body_only(ret));
+        }
+
+        Ok(Response::new(ret, e, is_verbose))
+    }
+}
+
+/// Parses the selectors in the string, if there is one
+fn parse_optional_selectors
+(
+    g_env: &mut GlobalEnv,
+    s: &Option<String>,
+) -> Result<Option<String>, RestClientError> {
+    if let Some(s) = s {
+        Ok(Some(g_env.parse_selectors(s)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses the JSON object of @vars-from-response into the variable names and
+/// the jq selectors of their values
+fn parse_var_mappings(mappings: &str) -> Result<Vec<(String, String)>, RestClientError> {
+    let mappings = match serde_json::from_str::<Value>(mappings)? {
+        Value::Object(mappings) => mappings,
+        _ => return Err(io_error("expected an object of variable names and jq selectors")),
+    };
+    mappings.into_iter()
+        .map(|(var, selector)| match selector {
+            Value::String(selector) => Ok((var, selector)),
+            _ => Err(io_error(&format!("the selector of {} is not a string", var))),
+        })
+        .collect()
+}
+
+/// Replaces the :name path parameters of the URL, the path segments that
+/// start with a colon, with the values of the variables. Colons anywhere else,
+/// like in the scheme or port, are left alone.
+fn replace_colon_params(g_env: &mut GlobalEnv, url: &str) -> Result<String, RestClientError> {
+    let param_re = Regex::new(r"/:([A-Za-z_]\w*)").unwrap();
+    let mut replaced = String::new();
+    let mut last = 0;
+    for caps in param_re.captures_iter(url) {
+        let param = caps.get(0).unwrap();
+        let val = g_env.evaluate(&format!(".{}", &caps[1]))
+            .map_err(|_| io_error(&format!("path parameter :{} is not set", &caps[1])))?;
+        replaced.push_str(&url[last..param.start()]);
+        replaced.push('/');
+        replaced.push_str(&val.as_str().map_or_else(|| val.to_string(), String::from));
+        last = param.end();
+    }
+    replaced.push_str(&url[last..]);
+    Ok(replaced)
+}
+
+/// Formats a JSON object or array body as a # Body: comment block, with the
+/// fields matching the sensitive names redacted. Returns None for any other
+/// body.
+fn format_json_body(body: &str, sensitive: &[String]) -> Option<String> {
+    let mut val = serde_json::from_str::<Value>(body).ok()
+        .filter(|val| val.is_object() || val.is_array())?;
+    redact_fields(&mut val, &sensitive_name_res(sensitive));
+    let pretty = serde_json::to_string_pretty(&val).ok()?;
+    let lines = pretty.lines()
+        .map(|line| format!("# {}", line))
+        .collect::<Vec<String>>();
+    Some(format!("# Body:\n{}", lines.join("\n")))
+}
+
+/// Replaces the value in the text with the replacement, like [REDACTED], but
+/// not where it is only part of a longer word or number, so 123 doesn't change
+/// 1234
+fn redact_value(text: &str, val: &str, replacement: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let start = if is_word(val.chars().next()) { r"\b" } else { "" };
+    let end = if is_word(val.chars().last()) { r"\b" } else { "" };
+    let val_re = Regex::new(&format!("{}{}{}", start, regex::escape(val), end)).unwrap();
+    val_re.replace_all(text, replacement).to_string()
+}
+
+/// Replaces the values of the fields whose names match, at any depth, with
+/// [REDACTED]
+fn redact_fields(val: &mut Value, name_res: &[Regex]) {
+    match val {
+        Value::Object(fields) => for (name, field) in fields.iter_mut() {
+            if name_res.iter().any(|re| re.is_match(name)) {
+                *field = json!("[REDACTED]");
+            } else {
+                redact_fields(field, name_res);
+            }
+        },
+        Value::Array(items) => for item in items.iter_mut() {
+            redact_fields(item, name_res);
+        },
+        _ => (),
+    }
+}
+
+/// Matches the sensitive names, which can be globs with *, like *_token
+fn sensitive_name_res(names: &[String]) -> Vec<Regex> {
+    names.iter()
+        .filter_map(|name| Regex::new(
+            &format!("^{}$", regex::escape(name).replace(r"\*", ".*"))
+        ).ok())
+        .collect()
+}
+
+/// Re-serializes the XML with indentation. Returns None if the XML could not
+/// be parsed.
+fn pretty_xml(xml: &str) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Eof) => break,
+            Ok(event) => writer.write_event(event).ok()?,
+            Err(_) => return None,
         }
-        let (ret, e) = g_env.call_curl(&args)?;
+    }
+    String::from_utf8(writer.into_inner()).ok()
+}
 
-        let ret_enum = Response::new(ret, e, is_verbose);
-        Ok(ret_enum.get_return())
+/// Compares the lines of the expected and actual text, in order. Each line
+/// that differs is shown as the expected line prefixed with - and the actual
+/// line prefixed with +. Returns an empty string if they match.
+fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected = expected.trim_end().lines().collect::<Vec<&str>>();
+    let actual = actual.trim_end().lines().collect::<Vec<&str>>();
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (exp, act) = (expected.get(i), actual.get(i));
+        if exp == act {
+            continue;
+        }
+        if let Some(exp) = exp {
+            diff.push_str(&format!("-{}\n", exp));
+        }
+        if let Some(act) = act {
+            diff.push_str(&format!("+{}\n", act));
+        }
     }
+    diff
+}
+
+/// Parses the YAML into a value, and re-serializes it for display. Returns
+/// None if the YAML could not be parsed.
+fn parse_yaml(yaml: &str) -> Option<(String, Value)> {
+    let val = serde_yaml::from_str::<Value>(yaml).ok()?;
+    let print_yaml = serde_yaml::to_string(&val).ok()?;
+    Some((String::from(print_yaml.trim_end()), val))
 }
 
 /// Given a header string, if it is for basic auth then automatically convert
@@ -240,6 +950,156 @@ fn handle_basic_auth(header: String, basic_auth_re: &Regex) -> String {
 }
 
 
+/// The result of executing a single fold, or the text in between folds
+pub struct FoldResult {
+    pub title: String,          // title of fold, empty for text in between folds
+    pub input: String,          // input lines of the fold
+    pub output: String,         // output lines of the fold
+    pub error: bool,            // if error occurred during execution
+    pub duration_ms: u64,       // time taken to execute the fold
+    pub is_text: bool,          // if this is the text in between folds
+    start_line: String,         // start of fold, with "executed" text
+    result_line: String,        // divider between the input and output
+    end_marker: String,         // end of fold
+}
+
+impl FoldResult {
+    /// Text in between folds, which is returned as is
+    fn text(text: &str) -> FoldResult {
+        FoldResult {
+            title: String::new(),
+            input: String::from(text),
+            output: String::new(),
+            error: false,
+            duration_ms: 0,
+            is_text: true,
+            start_line: String::new(),
+            result_line: String::new(),
+            end_marker: String::new(),
+        }
+    }
+
+    /// Splits a fold that has already been rendered, like the output of a loop,
+    /// back into its parts.
+    fn from_rendered(rendered: &str, separator: &str, error: bool, duration_ms: u64) -> FoldResult {
+        let lines = rendered.split('\n').collect::<Vec<&str>>();
+        let divider = lines.iter().position(|line| line.starts_with(separator));
+        let divider = match divider {
+            Some(divider) if divider > 0 && divider < lines.len() - 1 => divider,
+            _ => return FoldResult::text(rendered),
+        };
+        let section = |lines: &[&str]| if lines.is_empty() {
+            String::new()
+        } else {
+            format!("{}\n", lines.join("\n"))
+        };
+        let executed_re = Regex::new(&format!(r"^{}\s*|\s*executed \(({})(, \d+ms)?\)$", regex::escape(FOLD_START), STATUSES)).unwrap();
+        FoldResult {
+            title: executed_re.replace_all(lines[0], "").to_string(),
+            input: section(&lines[1..divider]),
+            output: section(&lines[divider + 1..lines.len() - 1]),
+            error,
+            duration_ms,
+            is_text: false,
+            start_line: String::from(lines[0]),
+            result_line: String::from(lines[divider]),
+            end_marker: String::from(lines[lines.len() - 1]),
+        }
+    }
+
+    /// Renders the fold with its input and output, in the format that replaces
+    /// the fold in the buffer
+    pub fn render(&self) -> String {
+        if self.start_line.is_empty() {
+            return self.input.clone();
+        }
+        let mut ret = format!("{}\n{}", self.start_line, self.input);
+        insert_newline(&mut ret);
+        ret.push_str(&format!("{}\n", self.result_line));
+        ret.push_str(&self.output);
+        insert_newline(&mut ret);
+        ret.push_str(&self.end_marker);
+        ret
+    }
+
+    /// The status of the fold on its start line, like SUCCESS or ERROR, or an
+    /// empty string for text in between folds
+    pub fn status(&self) -> String {
+        let status_re = Regex::new(&format!(r"executed \(({})", STATUSES)).unwrap();
+        status_re.captures(&self.start_line)
+            .map_or_else(String::new, |caps| String::from(&caps[1]))
+    }
+
+    /// Renders the fold as a Markdown section, with the input in an http code
+    /// block and the output in a json code block. Text in between folds is
+    /// returned as is.
+    pub fn render_markdown(&self) -> String {
+        if self.is_text {
+            return self.input.clone();
+        }
+        let mut ret = format!("## {} ({})\n\n```http\n{}", self.title, self.status(), self.input);
+        insert_newline(&mut ret);
+        ret.push_str(&format!("```\n\n```json\n{}", self.output));
+        insert_newline(&mut ret);
+        ret.push_str("```\n");
+        ret
+    }
+
+    /// The fold as a JSON object with its title, input, output, error state
+    /// and time taken
+    pub fn to_json(&self) -> Value {
+        json!({
+            "title": self.title,
+            "input": self.input,
+            "output": self.output,
+            "error": self.error,
+            "duration_ms": self.duration_ms,
+        })
+    }
+}
+
+/// Renders the fold results the way they were in the input
+pub(crate) fn render_results(results: &[FoldResult]) -> String {
+    results.iter()
+        .map(|result| result.render())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Whether the string can be used as the separator between the input and
+/// output of folds: it can't start with ###, which would be taken for a marker.
+pub fn is_valid_separator(separator: &str) -> bool {
+    !separator.starts_with("###")
+}
+
+/// Builds the regex of a block line: the fold marker, followed by the pattern
+/// of the block, like process_if::IF_START after FOLD_START.
+pub(crate) fn marker_regex(marker: &str, pattern: &str) -> Regex {
+    Regex::new(&format!("^{}{}", regex::escape(marker), pattern)).unwrap()
+}
+
+/// Sets the time taken on the status of the first line of the rendered fold,
+/// replacing the time already there, if any.
+fn with_duration(rendered: &str, ms: u64) -> String {
+    let status_re = Regex::new(&format!(r"^(.* executed \(({}))(, \d+ms)?\)", STATUSES)).unwrap();
+    status_re.replace(rendered, |caps: &Captures| format!("{}, {}ms)", &caps[1], ms)).to_string()
+}
+
+/// Colors the statuses on the start markers of the rendered folds, including
+/// nested ones, with ANSI codes: green for success, red for errors and yellow
+/// for skipped folds.
+fn color_statuses(rendered: &str) -> String {
+    let status_re = Regex::new(&format!(r"(?m)( executed \()({})([,)])", STATUSES)).unwrap();
+    status_re.replace_all(rendered, |caps: &Captures| {
+        let color = match &caps[2] {
+            "ERROR" => "31",
+            "SKIPPED" => "33",
+            _ => "32",
+        };
+        format!("{}\x1b[{}m{}\x1b[0m{}", &caps[1], color, &caps[2], &caps[3])
+    }).to_string()
+}
+
 /// Variables related to executing the content of a single fold
 struct FoldEnv {
     ret: String,                        // returned input
@@ -252,20 +1112,82 @@ struct FoldEnv {
     old_output_started: bool,           // if the output from previous execution was reached
     compiled: bool,                     // if this FoldEnv has compiled the return
     parent_fold: Option<Box<FoldEnv>>,  // if this FoldEnv is nested, contains the parent
+    snapshot: Option<Value>,            // env before the fold set any variables
+    request_ms: Option<u64>,            // time taken by the request, if curl was run
+    started: Instant,                   // when the fold started executing
 
     // request related vars
     request_started: bool,              // if the fold has started defining a request
     request_body_started: bool,         // if the fold has started the request body
     response_variable: String,          // variable to store the response
+    accumulate_vars: Vec<String>,       // array variables to append the response to
     made_request: bool,                 // if the request was made
     method: Method,                     // request method
     url: String,                        // request url
     headers: Vec<String>,               // request headers
+    bearer: Option<String>,             // token for the Authorization: Bearer header
+    if_none_match: Option<String>,      // variable with the ETag for the If-None-Match header
+    if_modified_since: Option<String>,  // variable with the date for the If-Modified-Since header
+    bearer_refresh: Option<(String, String)>, // URL to refresh the bearer token on a 401, and its variable
+    bearer_refresh_body: Option<String>, // jq expression on the env for the body of the refresh request
     multipart_forms: Vec<String>,       // forms and form data for multipart forms
+    multipart_types: HashMap<String, String>, // content types of multipart form fields
     request_body: String,               // request body
     is_debug: bool,                     // is debug flag set
     is_verbose: bool,                   // is verbose flag set
+    no_include: bool,                   // is no-include flag set
     options: Vec<String>,               // options for the curl command
+    timeout_secs: Option<u64>,          // max time in seconds for the request
+    connect_timeout: Option<String>,    // max time in seconds to connect to the host
+    retries: u64,                       // number of times to retry a failed request
+    retry_transient: bool,              // is retry-transient flag set
+    colon_params: bool,                 // is colon-params flag set
+    separator: Option<String>,          // separator between input and output, overriding --separator
+    delay_ms: Option<u64>,              // time in milliseconds to wait before each request attempt
+    rate_limit: Option<f64>,            // max requests per second, counting the requests of earlier folds
+    max_response_size: Option<usize>,   // max bytes of the response to keep
+    proxy: Option<String>,              // proxy to make the request through
+    socks5: Option<String>,             // SOCKS5 proxy (host:port) to make the request through
+    interface: Option<String>,          // network interface to make the request from
+    dns_servers: Vec<String>,           // DNS servers to resolve the host with
+    cert: Option<String>,               // client certificate file
+    key: Option<String>,                // client private key file
+    cookie_jar: Option<String>,         // file to read and write cookies
+    follow: bool,                       // is follow flag set
+    max_redirects: Option<u64>,         // max number of redirects to follow
+    force_insecure: bool,               // is no-verify flag set
+    force_verify: bool,                 // is verify flag set
+    http2: bool,                        // is http2 flag set
+    http3: bool,                        // is http3 flag set
+    http_version: Option<String>,       // HTTP version to use, overriding http2 and http3
+    output_file: Option<String>,        // file to save the response body to
+    save_headers: Option<String>,       // file to save the response headers to
+    log: Option<String>,                // file to append a log entry of the request to
+    is_form_encoded: bool,              // is form-encoded flag set
+    body_file: Option<String>,          // file to read the request body from
+    body_template: Option<String>,      // file with the request body, with selectors to substitute
+    is_graphql: bool,                   // is graphql flag set
+    graphql_variables: Option<String>,  // JSON object of variables for the GraphQL query
+    jq_filters: Vec<String>,            // jq filters to apply to the response, in order
+    assertions: Vec<String>,            // jq expressions the response must satisfy
+    captures: Vec<(String, String)>,    // regexes to apply to the response, and variables to store matches
+    capture_all: Vec<String>,           // jq expressions returning objects of variables to store
+    var_mappings: Vec<(String, String)>, // variable names with the jq selectors of the response to store in them
+    paginate: Option<String>,           // jq expression returning the URL of the next page
+    strict: bool,                       // is strict flag set
+    header_captures: Vec<(String, String)>, // response headers, and variables to store their values
+    cookie_extracts: Vec<(String, String)>, // response cookies, and variables to store their values
+    status_variable: String,            // variable to store the response status code
+    show_rate_limit: bool,              // is show-rate-limit flag set
+    show_stats: bool,                   // is stats flag set
+    fixture: Option<String>,            // file with the expected response body
+    fixture_created: bool,              // if the fixture file was written by this fold
+    schema: Option<String>,             // JSON Schema file the response body must conform to
+    sensitive: Vec<String>,             // names (or globs) of variables to hide in the output
+    scoped_env: Option<Value>,          // env to restore after an isolated nested fold
+    current_line: usize,                // number of non-empty, non-comment lines read so far
+    exports: Vec<String>,               // variables kept after an isolated nested fold
+    skip: bool,                         // if the rest of the fold should not be executed
 }
 
 impl FoldEnv {
@@ -281,45 +1203,108 @@ impl FoldEnv {
             old_output_started: false,
             compiled: false,
             parent_fold: None,
+            snapshot: None,
+            request_ms: None,
+            started: Instant::now(),
 
             request_started: false,
             request_body_started: false,
             response_variable: String::new(),
+            accumulate_vars: Vec::new(),
             made_request: false,
             method: Method::Get,
             url: String::new(),
             headers: Vec::new(),
+            bearer: None,
+            if_none_match: None,
+            if_modified_since: None,
+            bearer_refresh: None,
+            bearer_refresh_body: None,
             multipart_forms: Vec::new(),
+            multipart_types: HashMap::new(),
             request_body: String::new(),
             is_debug: false,
             is_verbose: false,
+            no_include: false,
             options: Vec::new(),
+            timeout_secs: None,
+            connect_timeout: None,
+            retries: 0,
+            retry_transient: false,
+            colon_params: false,
+            separator: None,
+            delay_ms: None,
+            rate_limit: None,
+            max_response_size: None,
+            proxy: None,
+            socks5: None,
+            interface: None,
+            dns_servers: Vec::new(),
+            cert: None,
+            key: None,
+            cookie_jar: None,
+            follow: false,
+            max_redirects: None,
+            force_insecure: false,
+            force_verify: false,
+            http2: false,
+            http3: false,
+            http_version: None,
+            output_file: None,
+            save_headers: None,
+            log: None,
+            is_form_encoded: false,
+            body_file: None,
+            body_template: None,
+            is_graphql: false,
+            graphql_variables: None,
+            jq_filters: Vec::new(),
+            assertions: Vec::new(),
+            captures: Vec::new(),
+            capture_all: Vec::new(),
+            var_mappings: Vec::new(),
+            paginate: None,
+            strict: false,
+            header_captures: Vec::new(),
+            cookie_extracts: Vec::new(),
+            status_variable: String::new(),
+            show_rate_limit: false,
+            show_stats: false,
+            fixture: None,
+            fixture_created: false,
+            schema: None,
+            sensitive: Vec::new(),
+            scoped_env: None,
+            current_line: 0,
+            exports: Vec::new(),
+            skip: false,
         }
     }
 
-    /// Collects the total string to return, including input and output
-    fn compile_return(&mut self) -> String {
-        if !self.compiled && !self.ret.is_empty() {
-            self.compiled = true;
-            let mut ret = String::new();
-            ret.push_str(&format!("{} executed ({})\n", self.start_marker,
-                if self.error {"ERROR"} else {"SUCCESS"}));
-            ret.push_str(&self.ret);
-            insert_newline(&mut ret);
-            ret.push_str(&format!("########## {}{}\n",
+    /// Collects the result to return, including input and output
+    fn compile_return(&mut self, g_env: &GlobalEnv) -> Option<FoldResult> {
+        if self.compiled || self.ret.is_empty() {
+            return None;
+        }
+        self.compiled = true;
+        Some(FoldResult {
+            title: String::from(self.title.trim_end()),
+            input: self.ret.clone(),
+            output: self.output.clone(),
+            error: self.error,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            is_text: false,
+            start_line: format!("{} executed ({})", self.start_marker, self.status()),
+            result_line: format!("{} {}{}",
+                self.separator.as_ref().unwrap_or(&g_env.separator),
                 self.title,
-                if self.error {"ERROR"} else {"RESULT"}));
-            insert_newline(&mut self.output);
-            if self.end_marker.is_empty() {
-                self.output.push_str("###}");
+                if self.error {"ERROR"} else {"RESULT"}),
+            end_marker: if self.end_marker.is_empty() {
+                String::from(FOLD_END)
             } else {
-                self.output.push_str(&self.end_marker);
-            }
-            ret.push_str(&self.output);
-            ret
-        } else {
-            String::new()
-        }
+                self.end_marker.clone()
+            },
+        })
     }
 
     /// Collects the total string to return, including input and output
@@ -328,11 +1313,10 @@ impl FoldEnv {
             self.compiled = true;
             let mut ret = String::new();
             let mut out = String::new();
-            ret.push_str(&format!("{} executed ({})\n", self.start_marker,
-                if self.error {"ERROR"} else {"SUCCESS"}));
+            ret.push_str(&format!("{} executed ({})\n", self.start_marker, self.status()));
             ret.push_str(&self.ret);
             if self.end_marker.is_empty() {
-                ret.push_str("###}");
+                ret.push_str(FOLD_END);
             } else {
                 ret.push_str(&self.end_marker);
             }
@@ -353,15 +1337,142 @@ impl FoldEnv {
         }
     }
 
+    /// If the fold failed after setting variables, rolls back the env to
+    /// before the fold, so the env file isn't left partially updated.
+    fn rollback(&mut self, g_env: &mut GlobalEnv) {
+        if !self.error {
+            return;
+        }
+        if let Some(snapshot) = self.snapshot.take() {
+            if let Err(err) = g_env.restore(snapshot) {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("{}\n", err.to_string()));
+            }
+        }
+    }
+
+    /// Restores the env from before an isolated nested fold, keeping only the
+    /// variables it exports. Exported temporary variables and secrets stay
+    /// temporary.
+    fn restore_scope(&mut self, g_env: &mut GlobalEnv) {
+        let scoped_env = match self.scoped_env.take() {
+            Some(scoped_env) => scoped_env,
+            None => return,
+        };
+        let exported = self.exports.iter()
+            .filter_map(|var| g_env.env.get(var)
+                .map(|val| (var.clone(), val.clone(), !g_env.tmp_vars.contains(var))))
+            .collect::<Vec<(String, Value, bool)>>();
+        let res = g_env.restore(scoped_env)
+            .and_then(|_| exported.iter()
+                .try_for_each(|(var, val, persist)| g_env.set_var(var, val, *persist)));
+        if let Err(err) = res {
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("{}\n", err.to_string()));
+        }
+    }
+
+    /// Hides the values of the sensitive variables in the output, where they
+    /// are replaced with [REDACTED]. Sensitive names can be globs with *, like
+    /// *_token. The variables keep their values in the env. Values shorter
+    /// than REDACT_MIN_LEN are only hidden on the lines setting them.
+    /// Values read from secrets ($$VAR) are replaced with [SECRET], unless
+    /// they are shorter than REDACT_MIN_LEN.
+    fn redact(&mut self, g_env: &GlobalEnv) {
+        for (var, _) in self.sensitive_vars(g_env) {
+            let var_line_re = Regex::new(&format!(r"(?m)^@{} = .*$", regex::escape(&var))).unwrap();
+            self.output = var_line_re
+                .replace_all(&self.output, format!("@{} = [REDACTED]", var).as_str())
+                .to_string();
+        }
+        self.output = self.redact_values(g_env, &self.output);
+    }
+
+    /// Replaces the values of secrets with [SECRET], and the values of the
+    /// sensitive variables with [REDACTED], in the text. Values shorter than
+    /// REDACT_MIN_LEN are left alone.
+    fn redact_values(&self, g_env: &GlobalEnv, text: &str) -> String {
+        let mut text = String::from(text);
+        for secret in &g_env.secrets {
+            if secret.chars().count() >= REDACT_MIN_LEN {
+                text = redact_value(&text, secret, "[SECRET]");
+            }
+        }
+        for (_, val) in self.sensitive_vars(g_env) {
+            if val.chars().count() >= REDACT_MIN_LEN {
+                text = redact_value(&text, &val, "[REDACTED]");
+            }
+        }
+        text
+    }
+
+    /// The names and values (as strings) of the variables matching the
+    /// sensitive names
+    fn sensitive_vars(&self, g_env: &GlobalEnv) -> Vec<(String, String)> {
+        if self.sensitive.is_empty() {
+            return Vec::new();
+        }
+        let name_res = sensitive_name_res(&self.sensitive);
+        g_env.list_vars().into_iter()
+            .filter(|(var, _)| name_res.iter().any(|re| re.is_match(var)))
+            .map(|(var, val)| (var, val.as_str().map_or_else(|| val.to_string(), String::from)))
+            .collect()
+    }
+
+    /// The status shown on the start marker once the fold is executed, with
+    /// the time taken by the request if one was made, or else by the fold
+    fn status(&self) -> String {
+        let status = if self.error {
+            "ERROR"
+        } else if self.skip {
+            "SKIPPED"
+        } else if self.fixture_created {
+            "FIXTURE CREATED"
+        } else {
+            "SUCCESS"
+        };
+        let ms = self.request_ms
+            .unwrap_or_else(|| self.started.elapsed().as_millis() as u64);
+        format!("{}, {}ms", status, ms)
+    }
+
     /// Builds and makes request if appropriate
     fn make_request(&mut self, g_env: &mut GlobalEnv) {
-        if self.request_started && !self.error {
-            let method = self.method.clone();
+        // a dry run only shows the curl commands, like debug mode
+        if g_env.dry_run {
+            self.is_debug = true;
+        }
+        if self.request_started && !self.error && !self.skip {
+            let method = self.method.clone();
             let url = self.url.clone();
-            let headers = self.headers.clone();
-            let multipart_forms = self.multipart_forms.clone();
+            let mut headers = self.headers.clone();
+            if let Some(token) = &self.bearer {
+                let auth_re = Regex::new(r"(?i)^authorization:").unwrap();
+                if headers.iter().any(|header| auth_re.is_match(header)) {
+                    insert_newline(&mut self.output);
+                    self.output.push_str("warning: Authorization header is set, so @bearer is ignored\n");
+                } else {
+                    headers.push(format!("Authorization: Bearer {}", token));
+                }
+            }
+            // a conditional header is only sent once its variable is set
+            for (header, var) in [
+                ("If-Modified-Since", &self.if_modified_since),
+                ("If-None-Match", &self.if_none_match),
+            ] {
+                let val = match var.as_ref().map(|var| g_env.evaluate(&format!(".{}", var))) {
+                    Some(Ok(Value::String(val))) => val,
+                    Some(Ok(val)) if !val.is_null() => val.to_string(),
+                    _ => continue,
+                };
+                headers.insert(0, format!("{}: {}", header, val));
+            }
+            let multipart_forms = self.multipart_forms.iter()
+                .map(|form| with_form_type(form, &self.multipart_types))
+                .collect::<Vec<String>>();
             let options = self.options.clone();
-            let req = Request {
+            let mut req = Request {
                 method,
                 url,
                 headers,
@@ -372,1014 +1483,5199 @@ impl FoldEnv {
                     None
                 },
                 options,
+                timeout: self.timeout_secs,
+                connect_timeout: self.connect_timeout.clone(),
+                max_response_size: self.max_response_size.unwrap_or(RESPONSE_BODY_MAX_BYTES),
+                proxy: self.proxy.clone(),
+                socks5: self.socks5.clone(),
+                interface: self.interface.clone(),
+                dns_servers: self.dns_servers.clone(),
+                cert: self.cert.clone(),
+                key: self.key.clone(),
+                cookie_jar: self.cookie_jar.clone(),
+                follow: self.follow,
+                max_redirects: self.max_redirects,
+                http2: self.http2,
+                http3: self.http3,
+                http_version: self.http_version.clone(),
+                retry_transient: self.retry_transient,
+                colon_params: self.colon_params,
+                output: self.output_file.clone(),
+                form_encoded: self.is_form_encoded,
+                body_file: self.body_file.clone(),
+                body_template: self.body_template.clone(),
+                insecure: (g_env.insecure && !self.force_verify) || self.force_insecure,
+                graphql: self.is_graphql,
+                graphql_variables: self.graphql_variables.clone(),
+                sensitive: self.sensitive.clone(),
+                no_include: self.no_include,
             };
+            if self.interface.is_some() && self.proxy.is_some() {
+                insert_newline(&mut self.output);
+                self.output.push_str("warning: @interface may be ignored for requests through a @proxy by some curl versions\n");
+            }
+            if self.http_version.is_some() && (self.http2 || self.http3) {
+                insert_newline(&mut self.output);
+                self.output.push_str("warning: @http-version is set, so @http2 and @http3 are ignored\n");
+            } else if self.http2 && self.http3 {
+                insert_newline(&mut self.output);
+                self.output.push_str("warning: both @http2 and @http3 are set, using HTTP/2\n");
+            }
             self.made_request = true;
-            req.make_request(g_env, self.is_debug, self.is_verbose)
-                .and_then(|(response, val)| {
+            g_env.last_request_ms = None;
+            g_env.transient_retries.clear();
+            let mut attempt = 0;
+            let res = loop {
+                attempt += 1;
+                self.delay();
+                self.throttle(g_env);
+                match req.make_request(g_env, self.is_debug, self.is_verbose) {
+                    Err(err) if attempt <= self.retries => {
+                        insert_newline(&mut self.output);
+                        self.output.push_str(&format!("attempt {} failed: {}\n",
+                            attempt, err.to_string().trim_end()));
+                        thread::sleep(Duration::from_millis(attempt * RETRY_BACKOFF_MS));
+                    },
+                    res => break res,
+                }
+            };
+            for retry in mem::take(&mut g_env.transient_retries) {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("{}\n", retry));
+            }
+            let res = match res {
+                Ok(response) if self.bearer_refresh.is_some()
+                    && !self.is_debug
+                    && parse_status(&response.headers()) == Some(401) => {
+                    insert_newline(&mut self.output);
+                    self.output.push_str("got 401, refreshing the bearer token\n");
+                    self.refresh_bearer(g_env, req.insecure)
+                        .and_then(|_| req.make_request(g_env, false, self.is_verbose))
+                },
+                res => res,
+            };
+            let insecure = req.insecure;
+            self.request_ms = g_env.last_request_ms;
+            res.and_then(|response| {
+                    let headers = response.headers();
+                    let body = response.body();
+                    let (response, val) = response.get_return();
+                    // the other pages are requested like the first, with only
+                    // the URL changed
+                    let is_verbose = self.is_verbose;
+                    let val = if self.paginate.is_some() && !self.is_debug {
+                        let url = req.resolved_url(g_env)?;
+                        self.fetch_pages(g_env, &url, &headers, val, |g_env, url| {
+                            req.url = String::from(url);
+                            let page = req.make_request(g_env, false, is_verbose)?;
+                            Ok((page.headers(), page.get_return().1))
+                        })?
+                    } else {
+                        val
+                    };
+                    let (response, val) = if self.jq_filters.is_empty() || self.is_debug {
+                        (response, val)
+                    } else {
+                        self.filter_response(&headers, &val)?
+                    };
+                    // a dry run has no responses to store
+                    if g_env.dry_run {
+                        self.output.push_str(&response);
+                        return Ok(());
+                    }
                     if !self.response_variable.is_empty() {
-                        let res = g_env.set_var(&self.response_variable, &val);
+                        let res = g_env.set_var(&self.response_variable, &val, true);
                         if let Err(_) = res {
                             return res;
                         }
                     }
+                    self.accumulate(g_env, &val)?;
                     self.output.push_str(&response);
+                    if self.is_debug && self.bearer_refresh.is_some() {
+                        insert_newline(&mut self.output);
+                        self.output.push_str("# on 401, refresh the bearer token with:\n");
+                        let refresh = self.refresh_bearer(g_env, insecure)?;
+                        self.output.push_str(&refresh);
+                    }
+                    if !self.is_debug {
+                        self.add_stats(&headers, &body, &val);
+                        self.capture_body(g_env, &body)?;
+                        self.capture_all_vars(g_env, &val)?;
+                        self.capture_var_mappings(g_env, &val)?;
+                        self.capture_headers(g_env, &headers)?;
+                        self.extract_cookies(g_env, &headers)?;
+                        self.save_headers(g_env, &headers)?;
+                        self.capture_status(g_env, parse_status(&headers))?;
+                        self.capture_rate_limit(g_env, &headers)?;
+                        self.check_not_modified(&headers);
+                        self.check_redirect_status(&headers);
+                        self.check_assertions(g_env, &val);
+                        self.check_fixture(g_env, &val)?;
+                        self.check_schema(g_env, &val)?;
+                        self.log_request(g_env, &req, &headers, &body)?;
+                    }
                     Ok(())
                 })
                 .or_else(|err| -> Result<(), ()>{
                     self.error = true;
                     self.output.push_str(&format!("{}\n", err.to_string()));
+                    if !self.is_debug {
+                        // there was no response, so there is no status to store
+                        let _ = self.capture_status(g_env, None);
+                    }
                     Ok(())
                 }).unwrap();
         }
     }
 
-    /// Parses flags
-    fn parse_flags(&mut self, line: &String, flags: &Flags) {
-        // check for # @name <name> which will do a variable definition on the response
-        flags.response_var_re.captures(line)
-            .and_then(|caps| caps.get(1))
-            .and_then(|var_name| {
-                self.response_variable = String::from(var_name.as_str());
-                Some(())
-            });
-        // check for # @form <form assign> which adds a multipart form arg
-        // <form assign> has the syntax
-        // - form_name=form_value
-        // - form_name=@file_path
-        flags.multi_form_re.captures(line)
-            .and_then(|caps| caps.get(1))
-            .and_then(|form| {
-                self.multipart_forms.push(String::from(form.as_str()));
-                Some(())
-            });
-        // check for # @debug which will print the curl request rather than run it
-        if flags.debug_re.is_match(line) {
-            self.is_debug = true;
+    /// Gets a new bearer token by POSTing to the refresh URL, with the env or
+    /// the refresh body expression evaluated on it as the JSON body, and stores
+    /// it in the token variable. The token is the response if it is a string,
+    /// or else its access_token or token field. In debug mode, nothing is sent,
+    /// and the curl command of the refresh request is returned instead.
+    fn refresh_bearer(&self, g_env: &mut GlobalEnv, insecure: bool) -> Result<String, RestClientError> {
+        let (url, var) = match &self.bearer_refresh {
+            Some(refresh) => refresh.clone(),
+            None => return Ok(String::new()),
+        };
+        let body = match &self.bearer_refresh_body {
+            Some(expr) => g_env.evaluate(expr)?,
+            None => g_env.env.clone(),
+        };
+        let headers = vec![String::from("Content-Type: application/json")];
+        let req = Request::new(Method::Post, &url, headers, Some(body.to_string()), insecure);
+        let response = req.make_request(g_env, self.is_debug, false)?;
+        if self.is_debug {
+            return Ok(response.get_return().0);
         }
-        // check for # @verbose which will run curl with verbose flag
-        if flags.verbose_re.is_match(line) {
-            self.is_verbose = true;
+        if let Some(status) = parse_status(&response.headers()).filter(|status| *status >= 400) {
+            return Err(io_error(&format!("bearer token refresh failed with status {}", status)));
         }
-        // check for # @options <options>
-        // - these are any options that can be used for curl, like --output filename
-        // - for now, does not support args with spaces like --output "test file.txt"
-        flags.options_re.captures(line)
-            .and_then(|caps| caps.get(1))
-            .and_then(|options| {
-                for option in options.as_str().split(' ') {
-                    self.options.push(String::from(option));
-                }
-                Some(())
-            });
+        let val = response.get_return().1;
+        let token = match &val {
+            Value::String(token) => Some(token.as_str()),
+            _ => val.get("access_token").or(val.get("token")).and_then(Value::as_str),
+        };
+        match token.filter(|token| !token.is_empty()) {
+            Some(token) => g_env.set_var(&var, &json!(token), true),
+            None => Err(io_error("bearer token refresh response has no token")),
+        }?;
+        Ok(String::new())
     }
-}
 
-pub struct SshSessions {
-    pub sessions: HashMap<String, Session>,
-}
-
-impl SshSessions {
-    pub fn new() -> SshSessions {
-        SshSessions {
-            sessions: HashMap::new(),
+    /// Waits for the delay before a request attempt, if there is one. In debug
+    /// mode, the sleep is only shown in the output.
+    fn delay(&mut self) {
+        if let Some(delay_ms) = self.delay_ms {
+            if self.is_debug {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("# sleep {}ms\n", delay_ms));
+            } else {
+                thread::sleep(Duration::from_millis(delay_ms));
+            }
         }
     }
 
-    async fn close_sessions(&mut self) {
-        for (_, session) in self.sessions.drain() {
-            session.close().await.unwrap();
+    /// Waits long enough since the last request to keep to the rate limit, if
+    /// there is one. Nothing is sent in debug mode, so there is no wait.
+    fn throttle(&self, g_env: &GlobalEnv) {
+        if self.is_debug {
+            return;
+        }
+        if let (Some(rps), Some(last_request_at)) = (self.rate_limit, g_env.last_request_at) {
+            thread::sleep(rate_limit_sleep(rps, last_request_at.elapsed()));
         }
     }
-}
 
-impl Drop for SshSessions {
-    fn drop(&mut self) {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(self.close_sessions());
+    /// Follows the pages of the response: the paginate expression is evaluated
+    /// on each page to get the URL of the next one, until it returns null or an
+    /// empty string, or there are MAX_PAGES pages. With the expression link,
+    /// the rel="next" URL of the Link header is followed instead. Relative URLs
+    /// are resolved against the URL of the page they are on, and a page that
+    /// doesn't have a 2xx status is an error. fetch requests the URL and
+    /// returns the headers and value of the page. Returns all the pages in an
+    /// array.
+    fn fetch_pages
+    (
+        &mut self,
+        g_env: &mut GlobalEnv,
+        url: &str,
+        headers: &str,
+        first: Value,
+        mut fetch: impl FnMut(&mut GlobalEnv, &str) -> Result<(String, Value), RestClientError>,
+    ) -> Result<Value, RestClientError> {
+        let expr = match &self.paginate {
+            Some(expr) => g_env.parse_selectors(expr)?,
+            None => return Ok(first),
+        };
+        let mut url = String::from(url);
+        let mut headers = String::from(headers);
+        let mut pages = vec![first];
+        loop {
+            let next = if expr.trim() == PAGINATE_LINK {
+                link_next(&headers)
+            } else {
+                match run_jq(&expr, pages.last().unwrap())? {
+                    Value::String(next) if !next.is_empty() => Some(next),
+                    _ => None,
+                }
+            };
+            let next = match next {
+                Some(next) => resolve_url(&url, &next),
+                None => break,
+            };
+            if pages.len() >= MAX_PAGES {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("warning: stopped paginating after {} pages\n", MAX_PAGES));
+                break;
+            }
+            let (page_headers, page) = fetch(g_env, &next)?;
+            if let Some(status) = parse_status(&page_headers).filter(|status| !(200..300).contains(status)) {
+                return Err(io_error(&format!("page {} ({}) returned status {}", pages.len() + 1, next, status)));
+            }
+            pages.push(page);
+            url = next;
+            headers = page_headers;
+        }
+        insert_newline(&mut self.output);
+        self.output.push_str(&format!("fetched {} page(s)\n", pages.len()));
+        Ok(Value::Array(pages))
     }
-}
 
-impl Deref for SshSessions {
-    type Target = HashMap<String, Session>;
+    /// Appends the response value to each accumulate variable, which is made
+    /// an array if it isn't one already.
+    fn accumulate(&self, g_env: &mut GlobalEnv, val: &Value) -> Result<(), RestClientError> {
+        for var in &self.accumulate_vars {
+            let mut vals = match g_env.env.get(var) {
+                Some(Value::Array(vals)) => vals.clone(),
+                _ => Vec::new(),
+            };
+            vals.push(val.clone());
+            g_env.set_var(var, &Value::Array(vals), true)?;
+        }
+        Ok(())
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.sessions
+    /// Runs the response value through the jq filters, one after the other, and
+    /// returns the output to show along with the filtered value.
+    fn filter_response(&self, headers: &str, val: &Value) -> Result<(String, Value), RestClientError> {
+        let mut val = val.clone();
+        for filter in &self.jq_filters {
+            val = run_jq(filter, &val)?;
+        }
+        let print_val = serde_json::to_string_pretty(&val)?;
+        if headers.is_empty() {
+            Ok((print_val, val))
+        } else {
+            Ok((format!("{}\n\n{}", headers, print_val), val))
+        }
     }
-}
 
-impl DerefMut for SshSessions {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.sessions
+    /// Applies each capture regex to the response body and stores the first
+    /// capture group (or the whole match, if there are no groups) in the
+    /// variable. A regex that doesn't match only adds a warning to the output,
+    /// unless strict is set, in which case it is an error.
+    fn capture_body(&mut self, g_env: &mut GlobalEnv, body: &str) -> Result<(), RestClientError> {
+        for (pattern, var) in &self.captures {
+            let re = Regex::new(pattern)?;
+            let captured = re.captures(body)
+                .and_then(|caps| caps.get(1).or(caps.get(0)))
+                .map(|m| String::from(m.as_str()));
+            if let Some(captured) = captured {
+                g_env.set_var(var, &json!(captured), true)?;
+            } else if self.strict {
+                return Err(io_error(&format!("capture {} did not match the response", pattern)))?;
+            } else {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("warning: capture {} did not match the response\n", pattern));
+            }
+        }
+        Ok(())
     }
-}
 
-/// Flags that are indicated with a syntax like so:
-/// # @flag_name
-pub struct Flags {
-    response_var_re: Regex,
-    multi_form_re: Regex,
-    debug_re: Regex,
-    verbose_re: Regex,
-    options_re: Regex,
-}
+    /// Evaluates each capture-all expression against the response value, and
+    /// stores every key of the resulting object as a variable. An expression
+    /// that doesn't return an object is an error.
+    fn capture_all_vars(&self, g_env: &mut GlobalEnv, val: &Value) -> Result<(), RestClientError> {
+        for expr in &self.capture_all {
+            let expr = g_env.parse_selectors(expr)?;
+            let vars = match run_jq(&expr, val)? {
+                Value::Object(vars) => vars,
+                _ => return Err(io_error(&format!("capture-all {} did not return an object", expr)))?,
+            };
+            for (var, val) in vars {
+                g_env.set_var(&var, &val, true)?;
+            }
+        }
+        Ok(())
+    }
 
-impl Flags {
-    fn new() -> Flags {
-        Flags {
-            response_var_re: Regex::new(r"^#\s*@name\s*([^ ]+)").unwrap(),
-            multi_form_re: Regex::new(r"^#\s*@form\s*(.+=.+)").unwrap(),
-            debug_re: Regex::new(r"^#\s*@debug").unwrap(),
-            verbose_re: Regex::new(r"^#\s*@verbose").unwrap(),
-            options_re: Regex::new(r"^#\s*@options\s*(.*)").unwrap(),
+    /// Stores the result of each selector of @vars-from-response on the
+    /// response in its variable, whose name can use selectors. A selector that
+    /// fails only gives a warning, and the other variables are still stored.
+    fn capture_var_mappings(&mut self, g_env: &mut GlobalEnv, val: &Value) -> Result<(), RestClientError> {
+        for (var, selector) in &self.var_mappings {
+            let var = g_env.parse_selectors(var)?;
+            match run_jq(selector, val) {
+                Ok(res) => g_env.set_var(&var, &res, true)?,
+                Err(err) => {
+                    insert_newline(&mut self.output);
+                    self.output.push_str(&format!("warning: could not set {}: {}\n", var, err.to_string().trim_end()));
+                },
+            }
         }
+        Ok(())
     }
-}
 
-/// Global environment that contains the sessions map and env variables map.
-pub struct GlobalEnv {
-    pub sessions: SshSessions,
-    pub env: Value,
-    filename: Option<String>,
-}
+    /// Stores the value of each captured header in its variable. Header names
+    /// are case-insensitive, and if a header appears more than once (e.g. after
+    /// a redirect) the last value is used. A missing header is stored as an
+    /// empty string. Verbose output prefixes response headers with "< ".
+    fn capture_headers(&self, g_env: &mut GlobalEnv, headers: &str) -> Result<(), RestClientError> {
+        for (name, var) in &self.header_captures {
+            let re = Regex::new(&format!(r"(?mi)^(?:< )?{}:[ \t]*(.*)$", regex::escape(name)))?;
+            let captured = re.captures_iter(headers)
+                .last()
+                .map_or_else(String::new, |caps| String::from(caps[1].trim_end()));
+            g_env.set_var(var, &json!(captured), true)?;
+        }
+        Ok(())
+    }
 
-impl GlobalEnv {
-    pub fn new(filename: Option<String>) -> GlobalEnv {
-        GlobalEnv {
-            filename: filename.clone(),
-            sessions: SshSessions::new(),
-            env: GlobalEnv::read_env(filename),
+    /// Stores the value of each extracted cookie in its variable, from the
+    /// name=value pair before the attributes of the Set-Cookie headers. If the
+    /// cookie is set more than once the last value is used. A cookie that
+    /// isn't set is stored as an empty string, with a warning.
+    fn extract_cookies(&mut self, g_env: &mut GlobalEnv, headers: &str) -> Result<(), RestClientError> {
+        if self.cookie_extracts.is_empty() {
+            return Ok(());
+        }
+        let set_cookie_re = Regex::new(r"(?mi)^(?:< )?Set-Cookie:[ \t]*(.*)$")?;
+        let cookies = set_cookie_re.captures_iter(headers)
+            .filter_map(|caps| {
+                let pair = caps[1].split(';').next().unwrap_or("");
+                pair.split_once('=')
+                    .map(|(name, val)| (String::from(name.trim()), String::from(val.trim())))
+            })
+            .collect::<Vec<(String, String)>>();
+        for (name, var) in &self.cookie_extracts {
+            let val = cookies.iter()
+                .rev()
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, val)| val.clone());
+            if val.is_none() {
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("warning: cookie {} is not set by the response\n", name));
+            }
+            g_env.set_var(var, &json!(val.unwrap_or_default()), true)?;
         }
+        Ok(())
     }
 
-    fn read_env(filename: Option<String>) -> Value {
-        let env_file = filename.as_ref()
-            .map_or_else(|| ENV_FILE, |f| f);
-        fs::read_to_string(env_file)
-            .and_then(|env_string| serde_json::from_str(&env_string)
-                  .or_else(|e| Err(io_error(&e.to_string()))))
-            .map_or_else(|_| json!({}), |val| val)
+    /// Writes the response headers to the save headers file, if set, as the
+    /// raw header block: the status line and the header lines. Verbose output
+    /// is reduced to the response headers, without their "< " prefix.
+    fn save_headers(&mut self, g_env: &mut GlobalEnv, headers: &str) -> Result<(), RestClientError> {
+        let path = match &self.save_headers {
+            Some(path) => g_env.parse_selectors(path)?,
+            None => return Ok(()),
+        };
+        let headers = if self.is_verbose {
+            headers.lines()
+                .filter_map(|line| line.strip_prefix("< "))
+                .collect::<Vec<&str>>()
+                .join("\n")
+        } else {
+            String::from(headers)
+        };
+        fs::write(&path, format!("{}\n", headers.trim_end()))?;
+        insert_newline(&mut self.output);
+        self.output.push_str(&format!("Headers saved to {}\n", path));
+        Ok(())
     }
 
-    /// Parse input lines that either define a variable or make a request
-    /// Must return the input lines, as well as appropriate output
-    /// Each block can have some variable definitions, but they must be before the
-    /// request. The request starts with the method, and it is assumed the rest of
-    /// the lines of the block are the headers of the request.
-    pub fn parse_input
+    /// Appends a log entry of the request to the log file, if set, as a line of
+    /// JSON with the time, method, URL, status, time taken and the body of the
+    /// response, truncated to LOG_BODY_MAX_BYTES. Secret and sensitive values
+    /// are redacted.
+    fn log_request
     (
-        &mut self,
-        input: &mut impl BufRead,
-        ignore_first_while: bool,
-    ) -> String {
-        let mut fold_env = FoldEnv::new();
-        let mut ret = String::new();
-        let mut fold_started = false;
+        &self,
+        g_env: &mut GlobalEnv,
+        req: &Request,
+        headers: &str,
+        body: &str,
+    ) -> Result<(), RestClientError> {
+        let path = match &self.log {
+            Some(path) => g_env.parse_selectors(path)?,
+            None => return Ok(()),
+        };
+        let url = req.resolved_url(g_env)?;
+        // redacted before it is cut, so a value across the cut isn't missed
+        let body = self.redact_values(g_env, body);
+        let mut end = body.len().min(LOG_BODY_MAX_BYTES);
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        let entry = json!({
+            "timestamp": iso_timestamp(SystemTime::now()),
+            "method": req.method.to_string(),
+            "url": self.redact_values(g_env, &url),
+            "status": parse_status(headers),
+            "duration_ms": g_env.last_request_ms,
+            "body": &body[..end],
+        });
+        let mut file = fs::OpenOptions::new().append(true).create(true).open(&path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
 
-        let start_fold_re = Regex::new(r"^(###\{\s*(.*))$").unwrap();
-        let executed_re = Regex::new(r" ?executed( \((ERROR|SUCCESS)\))?$").unwrap();
-        let while_re = Regex::new(process_while::WHILE_START).unwrap();
-        let flags = Flags::new();
-        let mut first_while = true;
-        loop {
-            let mut line = String::new();
-            let res = input.read_line(&mut line);
-            line = String::from((&line).trim_end());
-            match res {
-                Ok(0) => {
-                    break;
-                },
-                Ok(_) => (),
-                Err(e) => {
-                    fold_env.error = true;
-                    fold_env.output.push_str(&e.to_string());
-                },
-            };
-            let start_while = while_re.is_match(&line);
-            if start_while && !(ignore_first_while && first_while) {
-                let mut w = process_while::While::parse_while(&line, input, self);
-                if fold_started {
-                    let (nest_ret, nest_out) = w.compile_return();
-                    fold_env.ret.push_str(&nest_ret);
-                    fold_env.output.push_str(&nest_out);
-                    fold_env.error = fold_env.error || w.error;
-                } else {
-                    ret.push_str(&w.output);
-                }
-                first_while = false;
-                continue;
-            } else if start_while {
-                first_while = false;
-            }
-            if let Some(caps) = start_fold_re.captures(&line) {
-                if !fold_started {
-                    // previous endmarker doesn't end with newline
-                    if !ret.is_empty() {
-                        ret.push('\n');
-                    }
-                    fold_started = true;
-                    fold_env = FoldEnv::new();
-                } else {
-                    // if creating a new nested_fold, then check for request and run it
-                    if !fold_env.made_request {
-                        fold_env.make_request(self);
-                    }
-                    let mut nested_fold = FoldEnv::new();
-                    nested_fold.parent_fold = Some(Box::new(fold_env));
-                    fold_env = nested_fold;
-                }
-                if let Some(res) = caps.get(2) {
-                    let no_exec = executed_re.replace(res.as_str(), "");
-                    if !no_exec.to_string().is_empty() {
-                        fold_env.title = format!("{} ", no_exec.to_string());
-                    }
-                }
-                if let Some(res) = caps.get(1) {
-                    let no_exec = executed_re.replace(res.as_str(), "");
-                    fold_env.start_marker = no_exec.to_string();
-                } else {
-                    fold_env.start_marker = String::from("###{");
-                }
-                fold_env.first_line = false;
-                continue;
-            } else if fold_env.first_line && fold_started {
-                fold_env.start_marker = String::from("###{");
-                fold_env.first_line = false;
-            } else if !fold_started {
-                // push stuff in between folds
-                if !ret.is_empty() {
-                    ret.push('\n');
-                }
-                ret.push_str(&line);
-            }
-            if !fold_started {
-                continue;
-            }
-            if line.starts_with("##########") && fold_started {
-                fold_env.old_output_started = true;
-                continue;
-            }
-            if line.starts_with("###}") {
-                fold_env.end_marker = String::from(&line);
-                if !fold_env.made_request {
-                    fold_env.make_request(self);
-                }
-                if fold_env.parent_fold.is_some() {
-                    let (nest_ret, nest_out) = &fold_env.compile_for_parent();
-                    fold_env.parent_fold.as_mut().unwrap().ret.push_str(&nest_ret);
-                    fold_env.parent_fold.as_mut().unwrap().output.push_str(&nest_out);
-                    let mut parent_err = fold_env.parent_fold.as_mut().unwrap().error;
-                    parent_err = fold_env.error || parent_err;
-                    fold_env = *fold_env.parent_fold.take().unwrap();
-                    fold_env.error = parent_err;
-                } else {
-                    ret.push_str(&fold_env.compile_return());
-                    fold_started = false;
-                }
-                continue;
-            }
-            if fold_env.old_output_started {
-                continue;
-            }
-            insert_newline(&mut fold_env.ret);
-            fold_env.ret.push_str(&line);
-            fold_env.ret.push('\n');
-            if fold_env.error {
-                continue;
-            }
-            if line.starts_with('@') {
-                // for each line that starts with @, call define_var
-                let res_line = self.define_var(&String::from(line))
-                    .map_or_else(
-                        |err| {
-                            fold_env.error = true;
-                            format!("{}\n", err.to_string())
-                        },
-                        |res| format!("{}\n", res)
-                    );
-                insert_newline(&mut fold_env.output);
-                fold_env.output.push_str(&res_line);
-            } else if line.starts_with('#') {
-                // parse and check flags, else skip comment
-                fold_env.parse_flags(&line, &flags);
-            } else if !fold_env.request_started && line.is_empty() {
-                // line breaks should be ignored, but appear in output
-                fold_env.output.push('\n');
-                continue;
-            } else if !fold_env.request_started {
-                // parse method and URL
-                line.split_once(' ')
-                    .map_or_else(
-                        || {
-                            fold_env.error = true;
-                            insert_newline(&mut fold_env.output);
-                            fold_env.output.push_str(&format!("Could not parse line: {}\n", line));
-                            ()
-                        },
-                        |(m, url_str)| {
-                            fold_env.made_request = false;
-                            fold_env.method = Method::get_match(m);
-                            fold_env.url = String::from(url_str);
-                            ()
-                        }
-                    );
-                fold_env.request_started = true;
-            } else if !fold_env.request_body_started && !line.is_empty() {
-                fold_env.headers.push(String::from(line));
-            } else if !fold_env.request_body_started && line.is_empty() {
-                fold_env.request_body_started = true
-            } else if fold_env.request_body_started {
-                fold_env.request_body.push_str(&line);
-            }
+    /// Stores the response status code in the status variable, if set, or null
+    /// if there was no status.
+    fn capture_status(&self, g_env: &mut GlobalEnv, status: Option<u64>) -> Result<(), RestClientError> {
+        if self.status_variable.is_empty() {
+            return Ok(());
         }
+        g_env.set_var(&self.status_variable, &status.map_or(Value::Null, |code| json!(code)), true)
+    }
 
-        if !fold_env.made_request {
-            fold_env.make_request(self);
-            ret.push_str(&fold_env.compile_return());
+    /// Stores the X-RateLimit-Remaining and X-RateLimit-Reset headers of the
+    /// response in the RATE_LIMIT_REMAINING and RATE_LIMIT_RESET built-in vars,
+    /// and shows them in the output, if the fold has @show-rate-limit or rate
+    /// limit headers are shown for all folds. Numbers are stored as numbers.
+    fn capture_rate_limit(&mut self, g_env: &mut GlobalEnv, headers: &str) -> Result<(), RestClientError> {
+        if !self.show_rate_limit && !g_env.rate_limit_headers {
+            return Ok(());
+        }
+        let header = |name: &str| {
+            let re = Regex::new(&format!(r"(?mi)^(?:< )?{}:[ \t]*(.*)$", name)).unwrap();
+            re.captures_iter(headers)
+                .last()
+                .map(|caps| String::from(caps[1].trim_end()))
+        };
+        let to_json = |val: &String| val.parse::<u64>().map_or_else(|_| json!(val), |num| json!(num));
+        let mut shown = Vec::new();
+        if let Some(remaining) = header("X-RateLimit-Remaining") {
+            g_env.set_var(&String::from(RATE_LIMIT_REMAINING), &to_json(&remaining), false)?;
+            shown.push(format!("{} remaining", remaining));
+        }
+        if let Some(reset) = header("X-RateLimit-Reset") {
+            g_env.set_var(&String::from(RATE_LIMIT_RESET), &to_json(&reset), false)?;
+            shown.push(format!("resets at {}", reset));
         }
+        if !shown.is_empty() {
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("Rate limit: {}\n", shown.join(", ")));
+        }
+        Ok(())
+    }
 
-        ret
+    /// Adds the size of the response body, its number of lines as received
+    /// (not as formatted in the output), the number of keys if it is a JSON
+    /// object, and the number of headers to the output
+    fn add_stats(&mut self, headers: &str, body: &str, val: &Value) {
+        if !self.show_stats {
+            return;
+        }
+        let header_re = Regex::new(r"(?m)^(?:< )?[\w-]+:").unwrap();
+        let mut stats = vec![format_size(body.len()), plural(body.lines().count(), "line")];
+        if let Value::Object(keys) = val {
+            stats.push(plural(keys.len(), "JSON key"));
+        }
+        stats.push(plural(header_re.find_iter(headers).count(), "header"));
+        insert_newline(&mut self.output);
+        self.output.push_str(&format!("--- Stats: {} ---\n", stats.join(", ")));
     }
 
-    /// Defines and stores a variable (one line)
-    /// Parse the variable value as JSON, since the storage will basically be a JSON
-    /// file at .env.json. Should update both the file and the JSON loaded by
-    /// parse_input.
-    /// Substitutions can happen with {{}} and a variable name, or jq-syntax for
-    /// selecting fields from a variable.
-    /// If there's an error, return the error with error cause.
-    /// If successful, return the line with the value stored, with substitutions.
-    fn define_var(&mut self, var_line: &String) -> Result<String, Box<dyn Error>> {
-        let re = Regex::new(r"@([^ ]+)\s*=\s*(.+)").unwrap();
-        let caps = re.captures(var_line)
-            .ok_or(io_error(&format!("cannot parse line: {}", var_line)))?;
-        let var_name = caps.get(1).ok_or(io_error("unable to get variable"))?;
-        let value = caps.get(2).ok_or(io_error("unable to get value"))?;
+    /// Notes in the output when a conditional request gets 304 Not Modified,
+    /// meaning the copy from the earlier response is still current
+    fn check_not_modified(&mut self, headers: &str) {
+        if self.if_none_match.is_none() && self.if_modified_since.is_none() {
+            return;
+        }
+        if parse_status(headers) == Some(304) {
+            insert_newline(&mut self.output);
+            self.output.push_str("(304 Not Modified — cached)\n");
+        }
+    }
 
-        let value = self.parse_selectors(&String::from(value.as_str()))?;
-        let value_json = serde_json::from_str(&value)?;
-        self.set_var(&String::from(var_name.as_str()), &value_json)?;
-        Ok(format!("@{} = {}", var_name.as_str(), value))
+    /// When following redirects, the headers of every response are included,
+    /// and only the status of the final one matters. If it is an error status
+    /// the fold is an error.
+    fn check_redirect_status(&mut self, headers: &str) {
+        if !self.follow && self.max_redirects.is_none() {
+            return;
+        }
+        if let Some(status) = parse_status(headers).filter(|status| *status >= 400) {
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("redirect destination returned status {}\n", status));
+        }
     }
 
-    /// Given a variable and value, add it to the env and set file.
-    fn set_var(&mut self, var: &String, val: &Value) -> Result<(), Box<dyn Error>> {
-        self.env.as_object_mut()
-            .ok_or(io_error("cannot modify environment"))?
-            .insert(String::from(var), val.clone());
-        let env_file = self.filename.as_ref()
-            .map_or_else(|| ENV_FILE, |f| f);
-        fs::write(env_file, serde_json::to_string_pretty(&self.env)?)?;
+    /// Compares the response body line by line to the fixture file, if set. Any
+    /// difference is shown like a diff and marks the fold as an error. If the
+    /// fixture doesn't exist (or fixtures are being updated), the body is
+    /// written to it instead. JSON bodies are compared pretty-printed.
+    fn check_fixture(&mut self, g_env: &mut GlobalEnv, val: &Value) -> Result<(), RestClientError> {
+        let path = match &self.fixture {
+            Some(path) => g_env.parse_selectors(path)?,
+            None => return Ok(()),
+        };
+        let body = match val {
+            Value::String(body) => body.clone(),
+            val => serde_json::to_string_pretty(val)?,
+        };
+        let exists = Path::new(&path).is_file();
+        if !exists || g_env.update_fixtures {
+            fs::write(&path, format!("{}\n", body.trim_end()))?;
+            self.fixture_created = !exists;
+            return Ok(());
+        }
+        let diff = diff_lines(&fs::read_to_string(&path)?, &body);
+        if !diff.is_empty() {
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("response does not match fixture {}\n{}", path, diff));
+        }
         Ok(())
     }
 
-    /// Given a string, parses the entire string for substitutions marked by any
-    /// selectors in {{}}. If there are none, the original string is returned.
-    /// Allow substitutions to be nested.
-    pub fn parse_selectors(&mut self, s: &String) -> Result<String, Box<dyn Error>> {
-        let re = Regex::new(r"\{\{([^{}]+)\}\}").unwrap();
-        let mut replace_err: Option<String> = None;
-        let value = re.replace_all(s.as_str(), |caps: &Captures| {
+    /// Validates the response value against the JSON Schema file, if set. Every
+    /// validation error is listed and marks the fold as an error. Responses
+    /// that are not JSON are not validated.
+    fn check_schema(&mut self, g_env: &mut GlobalEnv, val: &Value) -> Result<(), RestClientError> {
+        let path = match &self.schema {
+            Some(path) => g_env.parse_selectors(path)?,
+            None => return Ok(()),
+        };
+        if val.is_string() {
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("response is not JSON, skipped schema {}\n", path));
+            return Ok(());
+        }
+        let schema = serde_json::from_str::<Value>(&fs::read_to_string(&path)?)?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|err| RestClientError::ParseError(format!("invalid schema {}: {}", path, err)))?;
+        let errors = validator.iter_errors(val)
+            .map(|err| format!("{}: {}\n", err.instance_path, err))
+            .collect::<Vec<String>>();
+        if !errors.is_empty() {
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str(&format!("response does not match schema {}\n{}", path, errors.concat()));
+        }
+        Ok(())
+    }
+
+    /// Evaluates each assertion against the response value. Selectors in {{}}
+    /// are substituted from the env first, so assertions can compare against
+    /// earlier responses. Every failed assertion is reported and marks the fold
+    /// as an error.
+    fn check_assertions(&mut self, g_env: &mut GlobalEnv, val: &Value) {
+        for assertion in &self.assertions {
+            let res = g_env.parse_selectors(assertion)
+                .and_then(|expr| run_jq(&expr, val));
+            let failure = match res {
+                Ok(Value::Bool(false)) | Ok(Value::Null) => format!("assertion failed: {}", assertion),
+                Ok(_) => continue,
+                Err(err) => format!("assertion failed: {}: {}", assertion, err.to_string().trim_end()),
+            };
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str(&failure);
+            self.output.push('\n');
+        }
+    }
+
+    /// Parses flags
+    fn parse_flags(&mut self, line: &String, flags: &Flags) {
+        // check for # @name <name> which will do a variable definition on the response
+        flags.response_var_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.response_variable = String::from(var_name.as_str());
+                Some(())
+            });
+        // check for # @accumulate <name> which appends the response to an array
+        // under the given name, usually for each iteration of a loop
+        flags.accumulate_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.accumulate_vars.push(String::from(var_name.as_str()));
+                Some(())
+            });
+        // check for # @form <form assign> which adds a multipart form arg
+        // <form assign> has the syntax
+        // - form_name=form_value
+        // - form_name=@file_path
+        // curl options for the part, like ;type=<type>, can follow the value
+        flags.multi_form_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|form| {
+                self.multipart_forms.push(String::from(form.as_str()));
+                Some(())
+            });
+        // check for # @form-type <form_name>=<type> which sets the content type
+        // of the multipart form part
+        if let Some(caps) = flags.form_type_re.captures(line) {
+            self.multipart_types.insert(String::from(&caps[1]), String::from(&caps[2]));
+        }
+        // check for # @debug which will print the curl request rather than run it
+        if flags.debug_re.is_match(line) {
+            self.is_debug = true;
+        }
+        // check for # @verbose which will run curl with verbose flag
+        if flags.verbose_re.is_match(line) {
+            self.is_verbose = true;
+        }
+        // check for # @no-include which gets only the response body, without
+        // the headers, even with @verbose
+        if flags.no_include_re.is_match(line) {
+            self.no_include = true;
+        }
+        // check for # @form-encoded which sends the body url encoded
+        if flags.form_encoded_re.is_match(line) {
+            self.is_form_encoded = true;
+        }
+        // check for # @graphql which sends the body as a GraphQL query, and
+        // # @graphql-variables <json object> which sets the query variables
+        if flags.graphql_re.is_match(line) {
+            self.is_graphql = true;
+        }
+        flags.graphql_vars_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|variables| {
+                self.graphql_variables = Some(String::from(variables.as_str()));
+                Some(())
+            });
+        // check for # @body-file <path> which reads the request body from the file
+        flags.body_file_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.body_file = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @body-template <path> which reads the request body from
+        // the file, substituting any selectors in it
+        flags.body_template_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.body_template = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @options <options>
+        // - these are any options that can be used for curl, like --output filename
+        // - split like a shell would, so args with spaces can be quoted like
+        //   --output "test file.txt"
+        flags.options_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|options| {
+                match shell_words::split(options.as_str()) {
+                    Ok(options) => self.options.extend(options),
+                    Err(err) => {
+                        self.error = true;
+                        insert_newline(&mut self.output);
+                        self.output.push_str(&format!("Could not parse options: {}\n", err));
+                    },
+                }
+                Some(())
+            });
+        // check for # @timeout <seconds> which sets the max time for the request
+        flags.timeout_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|secs| {
+                self.timeout_secs = secs.as_str().parse::<u64>().ok();
+                Some(())
+            });
+        // check for # @connect-timeout <seconds> which sets the max time for
+        // connecting to the host
+        flags.connect_timeout_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|secs| {
+                self.connect_timeout = Some(String::from(secs.as_str()));
+                Some(())
+            });
+        // check for # @retry <count> which retries a failed request up to count times
+        flags.retry_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|count| {
+                self.retries = count.as_str().parse::<u64>().unwrap_or(0);
+                Some(())
+            });
+        // check for # @retry-transient which retries curl when it can't resolve
+        // the host or connect, after waiting longer each time
+        if flags.retry_transient_re.is_match(line) {
+            self.retry_transient = true;
+        }
+        // check for # @colon-params which replaces the :name path parameters of
+        // the URL with the variables
+        if flags.colon_params_re.is_match(line) {
+            self.colon_params = true;
+        }
+        // check for # @separator <str> which sets the line between the input
+        // and the output of this fold
+        flags.separator_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|separator| {
+                if is_valid_separator(separator.as_str()) {
+                    self.separator = Some(String::from(separator.as_str()));
+                } else {
+                    self.error = true;
+                    insert_newline(&mut self.output);
+                    self.output.push_str(&format!("Invalid separator {}: it can't start with ###\n", separator.as_str()));
+                }
+                Some(())
+            });
+        // check for # @delay <milliseconds> which waits before making the request
+        flags.delay_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|ms| {
+                self.delay_ms = ms.as_str().parse::<u64>().ok();
+                Some(())
+            });
+        // check for # @rate-limit <requests per second> which spaces out the
+        // requests, including those of the folds before
+        flags.rate_limit_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|rps| {
+                self.rate_limit = rps.as_str().parse::<f64>().ok().filter(|rps| *rps > 0.0);
+                Some(())
+            });
+        // check for # @max-response-size <bytes> which overrides the max size
+        // of the response to keep
+        flags.max_response_size_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|bytes| {
+                self.max_response_size = bytes.as_str().parse::<usize>().ok();
+                Some(())
+            });
+        // check for # @bearer <token> which sets the Authorization header
+        flags.bearer_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|token| {
+                self.bearer = Some(String::from(token.as_str()));
+                Some(())
+            });
+        // check for # @if-none-match <name> and # @if-modified-since <name>
+        // which make the request conditional on the ETag or date in the variable
+        flags.if_none_match_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.if_none_match = Some(String::from(var_name.as_str()));
+                Some(())
+            });
+        flags.if_modified_since_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.if_modified_since = Some(String::from(var_name.as_str()));
+                Some(())
+            });
+        // check for # @bearer-refresh <url> <name> which gets a new token for
+        // the variable and retries the request once if it returns 401
+        if let Some(caps) = flags.bearer_refresh_re.captures(line) {
+            self.bearer_refresh = Some((String::from(&caps[1]), String::from(&caps[2])));
+        }
+        // check for # @bearer-refresh-body <jq> which sets the body of the
+        // refresh request, instead of the whole env
+        flags.bearer_refresh_body_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|expr| {
+                self.bearer_refresh_body = Some(String::from(expr.as_str()));
+                Some(())
+            });
+        // check for # @socks5 <host:port> which makes the request through the
+        // SOCKS5 proxy
+        flags.socks5_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|socks5| {
+                self.socks5 = Some(String::from(socks5.as_str()));
+                Some(())
+            });
+        // check for # @interface <name> which makes the request from the
+        // network interface
+        flags.interface_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|interface| {
+                self.interface = Some(String::from(interface.as_str()));
+                Some(())
+            });
+        // check for # @dns-server <address> which resolves the host with the
+        // DNS server, along with those of any other @dns-server lines
+        flags.dns_server_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|server| {
+                self.dns_servers.push(String::from(server.as_str()));
+                Some(())
+            });
+        // check for # @proxy <url> which makes the request through the proxy
+        flags.proxy_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|proxy| {
+                self.proxy = Some(String::from(proxy.as_str()));
+                Some(())
+            });
+        // check for # @cert <path> and # @key <path> which set the client
+        // certificate and private key for TLS client authentication
+        flags.cert_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|cert| {
+                self.cert = Some(String::from(cert.as_str()));
+                Some(())
+            });
+        flags.key_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|key| {
+                self.key = Some(String::from(key.as_str()));
+                Some(())
+            });
+        // check for # @cookie-jar <path> which reads and saves cookies in the file
+        flags.cookie_jar_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|jar| {
+                self.cookie_jar = Some(String::from(jar.as_str()));
+                Some(())
+            });
+        // check for # @output <path> which saves the response body to the file
+        flags.output_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.output_file = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @save-headers <path> which saves the response headers to
+        // the file
+        flags.save_headers_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.save_headers = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @log <path> which appends a log entry of the request to
+        // the file
+        flags.log_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.log = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @follow which follows redirects
+        if flags.follow_re.is_match(line) {
+            self.follow = true;
+        }
+        // check for # @max-redirects <n> which follows at most n redirects
+        flags.max_redirects_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|n| {
+                self.max_redirects = n.as_str().parse::<u64>().ok();
+                Some(())
+            });
+        // check for # @no-verify and # @verify which skip or do certificate
+        // verification for this fold, whatever the default is
+        if flags.no_verify_re.is_match(line) {
+            self.force_insecure = true;
+        }
+        if flags.verify_re.is_match(line) {
+            self.force_verify = true;
+        }
+        // check for # @http2 and # @http3 which force the HTTP version
+        if flags.http2_re.is_match(line) {
+            self.http2 = true;
+        }
+        if flags.http3_re.is_match(line) {
+            self.http3 = true;
+        }
+        // check for # @http-version <1.0|1.1|2|3> which sets the HTTP version
+        flags.http_version_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|version| {
+                self.http_version = Some(String::from(version.as_str()));
+                Some(())
+            });
+        // check for # @jq <filter> which filters the response through jq
+        flags.jq_filter_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|filter| {
+                self.jq_filters.push(String::from(filter.as_str()));
+                Some(())
+            });
+        // check for # @assert <expr> which must hold true for the response
+        flags.assert_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|expr| {
+                self.assertions.push(String::from(expr.as_str()));
+                Some(())
+            });
+        // check for # @capture <regex> <name> which stores the regex match on
+        // the response body under the given name
+        if let Some(caps) = flags.capture_re.captures(line) {
+            self.captures.push((String::from(&caps[1]), String::from(&caps[2])));
+        }
+        // check for # @capture-all <jq expression> which stores every key of the
+        // object returned by the expression on the response as a variable
+        flags.capture_all_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|expr| {
+                self.capture_all.push(String::from(expr.as_str()));
+                Some(())
+            });
+        // check for # @vars-from-response <json object> which stores the result
+        // of each jq selector value on the response under its key
+        flags.vars_from_response_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|mappings| {
+                match parse_var_mappings(mappings.as_str()) {
+                    Ok(mappings) => self.var_mappings.extend(mappings),
+                    Err(err) => {
+                        self.error = true;
+                        insert_newline(&mut self.output);
+                        self.output.push_str(&format!("Could not parse vars-from-response: {}\n", err));
+                    },
+                }
+                Some(())
+            });
+        // check for # @paginate <jq expression> which requests the next page at
+        // the URL returned by the expression, until there is none
+        flags.paginate_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|expr| {
+                self.paginate = Some(String::from(expr.as_str()));
+                Some(())
+            });
+        // check for # @header <header> <name> which stores the response header
+        // value under the given name
+        if let Some(caps) = flags.header_capture_re.captures(line) {
+            self.header_captures.push((String::from(&caps[1]), String::from(&caps[2])));
+        }
+        // check for # @extract-cookie <cookie> <name> which stores the value of
+        // the cookie set by the response under the given name
+        if let Some(caps) = flags.extract_cookie_re.captures(line) {
+            self.cookie_extracts.push((String::from(&caps[1]), String::from(&caps[2])));
+        }
+        // check for # @status <name> which stores the response status code
+        // under the given name
+        flags.status_var_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.status_variable = String::from(var_name.as_str());
+                Some(())
+            });
+        // check for # @show-rate-limit which shows and stores the rate limit
+        // headers of the response
+        if flags.show_rate_limit_re.is_match(line) {
+            self.show_rate_limit = true;
+        }
+        // check for # @stats which shows the size of the response
+        if flags.stats_re.is_match(line) {
+            self.show_stats = true;
+        }
+        // check for # @fixture <path> which compares the response body to the file
+        flags.fixture_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.fixture = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @export <name> which keeps the variable after an
+        // isolated nested fold
+        flags.export_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|var_name| {
+                self.exports.push(String::from(var_name.as_str()));
+                Some(())
+            });
+        // check for # @schema <path> which validates the response body against
+        // the JSON Schema file
+        flags.schema_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|path| {
+                self.schema = Some(String::from(path.as_str()));
+                Some(())
+            });
+        // check for # @sensitive <name> which hides the variable's value in the
+        // output, where the name can be a glob like *_token
+        flags.sensitive_re.captures(line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|name| {
+                self.sensitive.push(String::from(name.as_str()));
+                Some(())
+            });
+        // check for # @strict which turns capture warnings into errors
+        if flags.strict_re.is_match(line) {
+            self.strict = true;
+        }
+        // check for # @skip which skips the rest of the fold
+        if flags.skip_re.is_match(line) {
+            self.skip = true;
+        }
+    }
+
+    /// Checks for # @import <path>, which loads the variables in the JSON or
+    /// dotenv file into the env for the rest of the run, without writing them
+    /// to the env file, and # @import-dotenv <path>, which does the same for a
+    /// dotenv file as strings, expanding OS environment variables. The path can
+    /// use selectors. If the file can't be loaded the fold is an error.
+    fn check_import(&mut self, g_env: &mut GlobalEnv, line: &String, flags: &Flags) {
+        let (path, is_dotenv) = if let Some(caps) = flags.import_re.captures(line) {
+            (String::from(&caps[1]), false)
+        } else if let Some(caps) = flags.import_dotenv_re.captures(line) {
+            (String::from(&caps[1]), true)
+        } else {
+            return;
+        };
+        if self.snapshot.is_none() {
+            self.snapshot = Some(g_env.snapshot());
+        }
+        let res = g_env.parse_selectors(&path)
+            .and_then(|path| if is_dotenv {
+                g_env.import_dotenv(Path::new(&path))
+            } else {
+                g_env.merge_env(Path::new(&path))
+            }.map(|_| path));
+        insert_newline(&mut self.output);
+        match res {
+            Ok(path) => self.output.push_str(&format!("imported {}\n", path)),
+            Err(err) => {
+                self.error = true;
+                self.output.push_str(&format!("{}\n", err.to_string()));
+            },
+        }
+    }
+
+    /// Checks for # @skip-if <expr>, which skips the rest of the fold if the
+    /// jq expression is true for the env. If the expression can't be
+    /// evaluated the fold is an error.
+    fn check_skip_if(&mut self, g_env: &mut GlobalEnv, line: &String, flags: &Flags) {
+        let expr = match flags.skip_if_re.captures(line) {
+            Some(caps) => String::from(&caps[1]),
+            None => return,
+        };
+        match g_env.evaluate(&expr) {
+            Ok(val) => self.skip = val == Value::Bool(true),
+            Err(err) => {
+                self.error = true;
+                insert_newline(&mut self.output);
+                self.output.push_str(&format!("{}\n", err.to_string()));
+            },
+        }
+    }
+
+    /// Checks for # @break [label], which ends the while loop with the label,
+    /// or the innermost one without a label, after this fold. It is an error
+    /// outside of a while loop.
+    fn check_break(&mut self, g_env: &mut GlobalEnv, line: &String, flags: &Flags) {
+        let caps = match flags.break_re.captures(line) {
+            Some(caps) => caps,
+            None => return,
+        };
+        if g_env.while_depth == 0 {
+            self.error = true;
+            insert_newline(&mut self.output);
+            self.output.push_str("@break is only allowed in a while loop\n");
+            return;
+        }
+        g_env.break_label = Some(caps.get(1).map_or(String::new(), |label| String::from(label.as_str())));
+    }
+}
+
+pub struct SshSessions {
+    pub sessions: HashMap<String, Session>,
+}
+
+impl SshSessions {
+    pub fn new() -> SshSessions {
+        SshSessions {
+            sessions: HashMap::new(),
+        }
+    }
+
+    async fn close_sessions(&mut self) {
+        for (_, session) in self.sessions.drain() {
+            session.close().await.unwrap();
+        }
+    }
+}
+
+impl Drop for SshSessions {
+    fn drop(&mut self) {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(self.close_sessions());
+    }
+}
+
+impl Deref for SshSessions {
+    type Target = HashMap<String, Session>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sessions
+    }
+}
+
+impl DerefMut for SshSessions {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.sessions
+    }
+}
+
+/// A session taken out of the cache, which is put back when the guard is
+/// dropped, even if the command run with it returns early or panics
+pub(crate) struct SshSessionGuard<'a, S = Session> {
+    sessions: &'a mut HashMap<String, S>,
+    dest: String,
+    session: Option<S>,             // only None once dropped
+}
+
+impl<'a, S> SshSessionGuard<'a, S> {
+    fn new(sessions: &'a mut HashMap<String, S>, dest: &str, session: S) -> SshSessionGuard<'a, S> {
+        SshSessionGuard {
+            sessions,
+            dest: String::from(dest),
+            session: Some(session),
+        }
+    }
+}
+
+impl<'a, S> Deref for SshSessionGuard<'a, S> {
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl<'a, S> Drop for SshSessionGuard<'a, S> {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.sessions.insert(self.dest.clone(), session);
+        }
+    }
+}
+
+/// A session that is checked before it is reused from the cache
+pub(crate) trait HealthCheck {
+    /// If the session answers within the timeout
+    async fn is_alive(&self, timeout: Duration) -> bool;
+}
+
+impl HealthCheck for Session {
+    async fn is_alive(&self, timeout: Duration) -> bool {
+        // a no-op command, which a dead session can't run
+        let check = tokio::time::timeout(timeout, self.command("true").status()).await;
+        matches!(check, Ok(Ok(status)) if status.success())
+    }
+}
+
+/// Takes the cached session for the destination if it still answers the health
+/// check, or else replaces it with the session from connect, which is only
+/// awaited then. The session is put back in the cache when the returned guard
+/// is dropped.
+async fn take_or_connect<'a, S: HealthCheck>(
+    sessions: &'a mut HashMap<String, S>,
+    dest: &str,
+    timeout: Duration,
+    connect: impl Future<Output = Result<S, RestClientError>>,
+) -> Result<SshSessionGuard<'a, S>, RestClientError> {
+    if let Some(session) = sessions.remove(dest) {
+        if session.is_alive(timeout).await {
+            return Ok(SshSessionGuard::new(sessions, dest, session));
+        }
+    }
+    let session = connect.await?;
+    Ok(SshSessionGuard::new(sessions, dest, session))
+}
+
+/// Flags that are indicated with a syntax like so:
+/// # @flag_name
+pub struct Flags {
+    response_var_re: Regex,
+    accumulate_re: Regex,
+    break_re: Regex,
+    multi_form_re: Regex,
+    form_type_re: Regex,
+    form_encoded_re: Regex,
+    body_file_re: Regex,
+    body_template_re: Regex,
+    graphql_re: Regex,
+    graphql_vars_re: Regex,
+    debug_re: Regex,
+    verbose_re: Regex,
+    no_include_re: Regex,
+    options_re: Regex,
+    timeout_re: Regex,
+    connect_timeout_re: Regex,
+    retry_re: Regex,
+    retry_transient_re: Regex,
+    colon_params_re: Regex,
+    separator_re: Regex,
+    delay_re: Regex,
+    rate_limit_re: Regex,
+    max_response_size_re: Regex,
+    proxy_re: Regex,
+    socks5_re: Regex,
+    interface_re: Regex,
+    dns_server_re: Regex,
+    bearer_re: Regex,
+    if_none_match_re: Regex,
+    if_modified_since_re: Regex,
+    bearer_refresh_re: Regex,
+    bearer_refresh_body_re: Regex,
+    cert_re: Regex,
+    key_re: Regex,
+    cookie_jar_re: Regex,
+    output_re: Regex,
+    save_headers_re: Regex,
+    log_re: Regex,
+    follow_re: Regex,
+    max_redirects_re: Regex,
+    no_verify_re: Regex,
+    verify_re: Regex,
+    http2_re: Regex,
+    http3_re: Regex,
+    http_version_re: Regex,
+    jq_filter_re: Regex,
+    assert_re: Regex,
+    capture_re: Regex,
+    capture_all_re: Regex,
+    vars_from_response_re: Regex,
+    paginate_re: Regex,
+    strict_re: Regex,
+    header_capture_re: Regex,
+    extract_cookie_re: Regex,
+    status_var_re: Regex,
+    show_rate_limit_re: Regex,
+    stats_re: Regex,
+    fixture_re: Regex,
+    schema_re: Regex,
+    export_re: Regex,
+    sensitive_re: Regex,
+    skip_re: Regex,
+    skip_if_re: Regex,
+    import_re: Regex,
+    import_dotenv_re: Regex,
+}
+
+impl Flags {
+    fn new() -> Flags {
+        Flags {
+            response_var_re: Regex::new(r"^#\s*@name\s*([^ ]+)").unwrap(),
+            accumulate_re: Regex::new(process_while::ACCUMULATE).unwrap(),
+            break_re: Regex::new(process_while::BREAK).unwrap(),
+            multi_form_re: Regex::new(r"^#\s*@form\s+(.+=.+)").unwrap(),
+            form_type_re: Regex::new(r"^#\s*@form-type\s+([^ =]+)=([^ ]+)").unwrap(),
+            form_encoded_re: Regex::new(r"^#\s*@form-encoded").unwrap(),
+            body_file_re: Regex::new(r"^#\s*@body-file\s*([^ ]+)").unwrap(),
+            body_template_re: Regex::new(r"^#\s*@body-template\s*([^ ]+)").unwrap(),
+            graphql_re: Regex::new(r"^#\s*@graphql\s*$").unwrap(),
+            graphql_vars_re: Regex::new(r"^#\s*@graphql-variables\s+(.+)").unwrap(),
+            debug_re: Regex::new(r"^#\s*@debug").unwrap(),
+            verbose_re: Regex::new(r"^#\s*@verbose").unwrap(),
+            no_include_re: Regex::new(r"^#\s*@no-include\s*$").unwrap(),
+            options_re: Regex::new(r"^#\s*@options\s*(.*)").unwrap(),
+            timeout_re: Regex::new(r"^#\s*@timeout\s*(\d+)").unwrap(),
+            connect_timeout_re: Regex::new(r"^#\s*@connect-timeout\s+([^ ]+)").unwrap(),
+            retry_re: Regex::new(r"^#\s*@retry\s*(\d+)").unwrap(),
+            retry_transient_re: Regex::new(r"^#\s*@retry-transient\s*$").unwrap(),
+            colon_params_re: Regex::new(r"^#\s*@colon-params\s*$").unwrap(),
+            separator_re: Regex::new(r"^#\s*@separator\s+([^ ]+)").unwrap(),
+            delay_re: Regex::new(r"^#\s*@delay\s*(\d+)").unwrap(),
+            rate_limit_re: Regex::new(r"^#\s*@rate-limit\s*(\d+(?:\.\d+)?)").unwrap(),
+            max_response_size_re: Regex::new(r"^#\s*@max-response-size\s*(\d+)").unwrap(),
+            proxy_re: Regex::new(r"^#\s*@proxy\s*([^ ]+)").unwrap(),
+            socks5_re: Regex::new(r"^#\s*@socks5\s*([^ ]+)").unwrap(),
+            interface_re: Regex::new(r"^#\s*@interface\s*([^ ]+)").unwrap(),
+            dns_server_re: Regex::new(r"^#\s*@dns-server\s*([^ ]+)").unwrap(),
+            bearer_re: Regex::new(r"^#\s*@bearer\s+([^ ]+)").unwrap(),
+            if_none_match_re: Regex::new(r"^#\s*@if-none-match\s*([^ ]+)").unwrap(),
+            if_modified_since_re: Regex::new(r"^#\s*@if-modified-since\s*([^ ]+)").unwrap(),
+            bearer_refresh_re: Regex::new(r"^#\s*@bearer-refresh\s+([^ ]+)\s+([^ ]+)").unwrap(),
+            bearer_refresh_body_re: Regex::new(r"^#\s*@bearer-refresh-body\s+(.+)$").unwrap(),
+            cert_re: Regex::new(r"^#\s*@cert\s*([^ ]+)").unwrap(),
+            key_re: Regex::new(r"^#\s*@key\s*([^ ]+)").unwrap(),
+            cookie_jar_re: Regex::new(r"^#\s*@cookie-jar\s*([^ ]+)").unwrap(),
+            output_re: Regex::new(r"^#\s*@output\s*([^ ]+)").unwrap(),
+            save_headers_re: Regex::new(r"^#\s*@save-headers\s*([^ ]+)").unwrap(),
+            log_re: Regex::new(r"^#\s*@log\s*([^ ]+)").unwrap(),
+            follow_re: Regex::new(r"^#\s*@follow").unwrap(),
+            max_redirects_re: Regex::new(r"^#\s*@max-redirects\s*(\d+)").unwrap(),
+            no_verify_re: Regex::new(r"^#\s*@no-verify\s*$").unwrap(),
+            verify_re: Regex::new(r"^#\s*@verify\s*$").unwrap(),
+            http2_re: Regex::new(r"^#\s*@http2").unwrap(),
+            http3_re: Regex::new(r"^#\s*@http3").unwrap(),
+            http_version_re: Regex::new(r"^#\s*@http-version\s*([^ ]+)").unwrap(),
+            jq_filter_re: Regex::new(r"^#\s*@jq\s+(.+)").unwrap(),
+            assert_re: Regex::new(r"^#\s*@assert\s*(.+)").unwrap(),
+            capture_re: Regex::new(r"^#\s*@capture\s+(.+)\s+([^ ]+)$").unwrap(),
+            capture_all_re: Regex::new(r"^#\s*@capture-all\s+(.+)$").unwrap(),
+            vars_from_response_re: Regex::new(r"^#\s*@vars-from-response\s+(.+)$").unwrap(),
+            paginate_re: Regex::new(r"^#\s*@paginate\s+(.+)$").unwrap(),
+            strict_re: Regex::new(r"^#\s*@strict").unwrap(),
+            header_capture_re: Regex::new(r"^#\s*@header\s+([^ ]+)\s+([^ ]+)").unwrap(),
+            extract_cookie_re: Regex::new(r"^#\s*@extract-cookie\s+([^ ]+)\s+([^ ]+)").unwrap(),
+            status_var_re: Regex::new(r"^#\s*@status\s*([^ ]+)").unwrap(),
+            show_rate_limit_re: Regex::new(r"^#\s*@show-rate-limit\s*$").unwrap(),
+            stats_re: Regex::new(r"^#\s*@stats\s*$").unwrap(),
+            fixture_re: Regex::new(r"^#\s*@fixture\s*([^ ]+)").unwrap(),
+            schema_re: Regex::new(r"^#\s*@schema\s*([^ ]+)").unwrap(),
+            export_re: Regex::new(r"^#\s*@export\s*([^ ]+)").unwrap(),
+            sensitive_re: Regex::new(r"^#\s*@sensitive\s*([^ ]+)").unwrap(),
+            skip_re: Regex::new(r"^#\s*@skip\s*$").unwrap(),
+            skip_if_re: Regex::new(r"^#\s*@skip-if\s+(.+)").unwrap(),
+            import_re: Regex::new(r"^#\s*@import\s+(.+)").unwrap(),
+            import_dotenv_re: Regex::new(r"^#\s*@import-dotenv\s+(.+)").unwrap(),
+        }
+    }
+}
+
+/// Global environment that contains the sessions map and env variables map.
+pub struct GlobalEnv {
+    pub sessions: SshSessions,
+    pub env: Value,
+    filename: Option<String>,
+    tmp_vars: HashSet<String>,
+    secrets: HashSet<String>,
+    secret_vars: HashSet<String>,
+    secret_read: bool,
+    computing: HashSet<String>,
+    base_env: Value,
+    initial_env: Value,
+    last_request_ms: Option<u64>,
+    last_request_at: Option<Instant>,
+    pub(crate) while_depth: usize,
+    pub(crate) break_label: Option<String>,
+    pub(crate) accumulating: HashSet<String>,
+    transient_retries: Vec<String>,
+    pub update_fixtures: bool,
+    pub color: bool,
+    pub dry_run: bool,
+    pub insecure: bool,
+    pub session_health_check_timeout_ms: u64,
+    pub rate_limit_headers: bool,
+    pub output_format: OutputFormat,
+    pub retry_transient: bool,
+    pub use_colon_params: bool,
+    pub separator: String,
+    pub scope: EnvScope,
+}
+
+impl GlobalEnv {
+    pub fn new(filename: Option<&str>) -> GlobalEnv {
+        let base_env = env::var("HOME")
+            .map_or_else(
+                |_| json!({}),
+                |home| GlobalEnv::read_env(Some(&format!("{}/{}", home, BASE_ENV_FILE)))
+            );
+        // use the env file of the closest directory that has one, so the
+        // client can be run from anywhere in a project
+        let filename = env::current_dir().ok()
+            .and_then(|cwd| find_env_file(&cwd, filename.unwrap_or(ENV_FILE)))
+            .map(|path| path.to_string_lossy().to_string())
+            .or(filename.map(String::from));
+        let mut env = GlobalEnv::read_env(filename.as_deref());
+        if let (Some(vars), Some(base_vars)) = (env.as_object_mut(), base_env.as_object()) {
+            for (var, val) in base_vars {
+                vars.entry(var.clone()).or_insert(val.clone());
+            }
+        }
+        GlobalEnv {
+            filename,
+            sessions: SshSessions::new(),
+            initial_env: env.clone(),
+            env,
+            tmp_vars: HashSet::new(),
+            secrets: HashSet::new(),
+            secret_vars: HashSet::new(),
+            secret_read: false,
+            computing: HashSet::new(),
+            last_request_ms: None,
+            last_request_at: None,
+            while_depth: 0,
+            break_label: None,
+            accumulating: HashSet::new(),
+            transient_retries: Vec::new(),
+            update_fixtures: false,
+            color: false,
+            dry_run: false,
+            insecure: DEFAULT_INSECURE,
+            session_health_check_timeout_ms: DEFAULT_SSH_HEALTH_CHECK_TIMEOUT_MS,
+            rate_limit_headers: false,
+            output_format: OutputFormat::VimFold,
+            retry_transient: false,
+            use_colon_params: false,
+            separator: String::from(DEFAULT_SEPARATOR),
+            scope: EnvScope::Inherited,
+            base_env,
+        }
+    }
+
+    fn read_env(filename: Option<&str>) -> Value {
+        let env_file = filename.unwrap_or(ENV_FILE);
+        fs::read_to_string(env_file)
+            .map_err(RestClientError::from)
+            .and_then(|env_string| serde_json::from_str(&env_string)
+                  .map_err(RestClientError::from))
+            .map_or_else(|_| json!({}), |val| val)
+    }
+
+    /// Overlays the variables in the JSON file at path onto the env. The
+    /// variables are treated like temporary variables, so the env file is not
+    /// changed by the merge, and they are only written to it if they are set
+    /// again. Files that don't end in .json are read as dotenv files.
+    /// Returns an error if the file can't be read or isn't a JSON object.
+    pub fn merge_env(&mut self, path: &Path) -> Result<(), RestClientError> {
+        let contents = fs::read_to_string(path)?;
+        let overlay: Value = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)?
+        } else {
+            parse_dotenv(&contents)
+        };
+        let overlay = overlay.as_object()
+            .ok_or_else(|| io_error(&format!("{} is not a JSON object", path.display())))?;
+        let vars = self.env.as_object_mut()
+            .ok_or(io_error("cannot modify environment"))?;
+        for (var, val) in overlay {
+            vars.insert(var.clone(), val.clone());
+            self.tmp_vars.insert(var.clone());
+        }
+        Ok(())
+    }
+
+    /// Loads the KEY=VALUE lines of the dotenv file at path into the env as
+    /// strings, like merge_env, so the env file is not changed. Quotes around
+    /// values are removed, and $VAR or ${VAR} in values are expanded from the
+    /// OS environment, except in single quoted values.
+    pub fn import_dotenv(&mut self, path: &Path) -> Result<(), RestClientError> {
+        let contents = fs::read_to_string(path)?;
+        let vars = self.env.as_object_mut()
+            .ok_or(io_error("cannot modify environment"))?;
+        for (var, val) in contents.lines().filter_map(parse_dotenv_line) {
+            let val = match val.strip_prefix('\'').and_then(|val| val.strip_suffix('\'')) {
+                Some(val) => String::from(val),
+                None => expand_os_vars(val.trim_matches('"')),
+            };
+            vars.insert(String::from(var), json!(val));
+            self.tmp_vars.insert(String::from(var));
+        }
+        Ok(())
+    }
+
+    /// Parse input lines that either define a variable or make a request
+    /// Must return the input lines, as well as appropriate output, as a result
+    /// for each fold and for the text in between folds
+    /// Each block can have some variable definitions, but they must be before the
+    /// request. The request starts with the method, and it is assumed the rest of
+    /// the lines of the block are the headers of the request.
+    /// With an isolated scope, variables set in nested folds are discarded at
+    /// the end of the nested fold, unless exported. This includes the folds in
+    /// while, for and if blocks.
+    pub fn parse_input
+    (
+        &mut self,
+        input: &mut impl BufRead,
+        ignore_first_loop: bool,
+    ) -> Vec<FoldResult> {
+        self.parse_fold_stream(input, ignore_first_loop).collect()
+    }
+
+    /// Like parse_input, but returns the result of each fold as soon as it is
+    /// executed.
+    pub fn parse_fold_stream<R: BufRead>
+    (
+        &mut self,
+        input: R,
+        ignore_first_loop: bool,
+    ) -> FoldStream<'_, R> {
+        FoldStream::new(input, self, ignore_first_loop)
+    }
+
+    /// Makes a request without a fold, returning the output shown for it and
+    /// the response value. The URL, headers and body can use {{}}
+    /// substitutions, like in a fold.
+    pub fn execute_fold
+    (
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: Vec<String>,
+        body: Option<String>,
+    ) -> Result<(String, Value), RestClientError> {
+        let req = Request::new(method, url, headers, body, self.insecure);
+        Ok(req.make_request(self, false, false)?.get_return())
+    }
+
+    /// Like execute_fold, but only returns the curl command of the request,
+    /// as in debug mode.
+    pub fn execute_fold_debug
+    (
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: Vec<String>,
+        body: Option<String>,
+    ) -> Result<String, RestClientError> {
+        let req = Request::new(method, url, headers, body, self.insecure);
+        Ok(req.make_request(self, true, false)?.get_return().0)
+    }
+
+    /// Renders the results of parse_input for the output, in the output
+    /// format, with the statuses colored if color is set and the folds are
+    /// rendered for Vim.
+    pub fn render(&self, results: &[FoldResult]) -> String {
+        let rendered = self.render_uncolored(results);
+        if self.color && self.output_format == OutputFormat::VimFold {
+            color_statuses(&rendered)
+        } else {
+            rendered
+        }
+    }
+
+    /// Renders the results of parse_input in the output format, without
+    /// colors. Text in between folds is left out of the JSON format.
+    pub fn render_uncolored(&self, results: &[FoldResult]) -> String {
+        match self.output_format {
+            OutputFormat::VimFold => render_results(results),
+            OutputFormat::Markdown => results.iter()
+                .map(|result| String::from(result.render_markdown().trim()))
+                .filter(|rendered| !rendered.is_empty())
+                .collect::<Vec<String>>()
+                .join("\n\n"),
+            OutputFormat::Json => {
+                let folds = results.iter()
+                    .filter(|result| !result.is_text)
+                    .map(|result| result.to_json())
+                    .collect::<Vec<Value>>();
+                serde_json::to_string_pretty(&folds).unwrap_or_default()
+            },
+        }
+    }
+
+    /// Defines and stores a variable (one line)
+    /// Parse the variable value as JSON, since the storage will basically be a JSON
+    /// file at .env.json. Should update both the file and the JSON loaded by
+    /// parse_input.
+    /// Substitutions can happen with {{}} and a variable name, or jq-syntax for
+    /// selecting fields from a variable.
+    /// If there's an error, return the error with error cause.
+    /// If successful, return the line with the value stored, with substitutions.
+    /// A line like @unset varname deletes the variable instead.
+    /// A line like @tmp varname = <value> defines a temporary variable, which is
+    /// usable for the rest of the session but never written to the file.
+    /// A value that reads a secret ($$VAR), or a variable set from one, makes
+    /// the variable temporary too, and its value is shown as [SECRET].
+    /// A line like @computed varname = <value> stores the value unsubstituted,
+    /// and it is substituted again each time the variable is used.
+    /// A line like @varname += <value> appends the value to the array, which is
+    /// created if the variable isn't set, or merges it into the object. It
+    /// can't be used with @tmp or @computed, or on a computed variable.
+    /// Variable names can't end with +, so @name+= 1 always appends.
+    fn define_var(&mut self, var_line: &String) -> Result<String, RestClientError> {
+        let modifier_append_re = Regex::new(r"^@(tmp|computed)\s+[^ =]+\s*\+=").unwrap();
+        if let Some(caps) = modifier_append_re.captures(var_line) {
+            return Err(RestClientError::ParseError(format!("@{} can't be used with +=: {}", &caps[1], var_line)));
+        }
+        let append_re = Regex::new(r"^@([^ =]*[^ =+])\s*\+=\s*(.+)$").unwrap();
+        if let Some(caps) = append_re.captures(var_line) {
+            return self.append_var(&String::from(&caps[1]), &String::from(&caps[2]));
+        }
+        let unset_re = Regex::new(r"^@unset\s+([^ =]+)\s*$").unwrap();
+        if let Some(caps) = unset_re.captures(var_line) {
+            let var_name = caps.get(1).ok_or(io_error("unable to get variable"))?;
+            let computed_name = format!("{}{}", COMPUTED_PREFIX, var_name.as_str());
+            if self.env.get(&computed_name).is_some() {
+                self.delete_var(&computed_name)?;
+            } else {
+                self.delete_var(&String::from(var_name.as_str()))?;
+            }
+            return Ok(format!("@{} (deleted)", var_name.as_str()));
+        }
+        let re = Regex::new(r"@(tmp\s+|computed\s+)?([^ ]*[^ +])\s*=\s*(.+)").unwrap();
+        let caps = re.captures(var_line)
+            .ok_or(RestClientError::ParseError(format!("cannot parse line: {}", var_line)))?;
+        let is_tmp = caps.get(1).is_some_and(|m| m.as_str().starts_with("tmp"));
+        let is_computed = caps.get(1).is_some_and(|m| m.as_str().starts_with("computed"));
+        let var_name = caps.get(2).ok_or(io_error("unable to get variable"))?;
+        let value = caps.get(3).ok_or(io_error("unable to get value"))?;
+
+        let computed_name = format!("{}{}", COMPUTED_PREFIX, var_name.as_str());
+        if is_computed {
+            if self.env.get(var_name.as_str()).is_some() {
+                self.delete_var(&String::from(var_name.as_str()))?;
+            }
+            self.set_var(&computed_name, &json!(value.as_str()), true)?;
+            return Ok(format!("@{} = {} (computed)", var_name.as_str(), value.as_str()));
+        }
+        if self.env.get(&computed_name).is_some() {
+            self.delete_var(&computed_name)?;
+        }
+
+        self.secret_read = false;
+        let value = self.parse_selectors(&String::from(value.as_str()))?;
+        let value_json = serde_json::from_str(&value)?;
+        let is_secret = self.secret_read;
+        self.set_var(&String::from(var_name.as_str()), &value_json, !is_tmp && !is_secret)?;
+        if is_secret {
+            self.secret_vars.insert(String::from(var_name.as_str()));
+        } else {
+            self.secret_vars.remove(var_name.as_str());
+        }
+        if is_secret {
+            Ok(format!("@{} = [SECRET]", var_name.as_str()))
+        } else if is_tmp {
+            Ok(format!("@{} = {} (tmp)", var_name.as_str(), value))
+        } else {
+            Ok(format!("@{} = {}", var_name.as_str(), value))
+        }
+    }
+
+    /// Appends the value, once substituted, to the array variable, or merges it
+    /// into the object variable if both are objects. A variable that isn't set
+    /// becomes an array of the value. A temporary variable stays temporary.
+    fn append_var(&mut self, var: &String, value: &String) -> Result<String, RestClientError> {
+        if self.env.get(&format!("{}{}", COMPUTED_PREFIX, var)).is_some() {
+            return Err(io_error(&format!("can't append to the computed variable {}", var)));
+        }
+        self.secret_read = false;
+        let value = self.parse_selectors(value)?;
+        let value_json = serde_json::from_str::<Value>(&value)?;
+        let is_secret = self.secret_read || self.secret_vars.contains(var);
+        let appended = match (self.env.get(var).cloned(), value_json) {
+            (None, value_json) => json!([value_json]),
+            (Some(Value::Array(mut items)), value_json) => {
+                items.push(value_json);
+                Value::Array(items)
+            },
+            (Some(Value::Object(mut fields)), Value::Object(new_fields)) => {
+                fields.extend(new_fields);
+                Value::Object(fields)
+            },
+            (Some(Value::Object(_)), _) => {
+                return Err(io_error(&format!("only an object can be merged into {}", var)));
+            },
+            (Some(_), _) => return Err(io_error(&format!("{} is not an array or object", var))),
+        };
+        let is_tmp = self.tmp_vars.contains(var);
+        self.set_var(var, &appended, !is_tmp && !is_secret)?;
+        if is_secret {
+            self.secret_vars.insert(var.clone());
+        }
+        if is_secret {
+            Ok(format!("@{} += [SECRET]", var))
+        } else {
+            Ok(format!("@{} += {}", var, value))
+        }
+    }
+
+    /// Serializes the set variables (as listed by list_vars) in the format. For
+    /// dotenv, strings are written as is and other values as JSON.
+    pub fn export_env(&self, format: EnvExportFormat) -> Result<String, RestClientError> {
+        let vars = self.list_vars();
+        let env = Value::Object(vars.iter().cloned().collect());
+        match format {
+            EnvExportFormat::Toml => Ok(toml::to_string(&env)?),
+            EnvExportFormat::Yaml => Ok(serde_yaml::to_string(&env)?),
+            EnvExportFormat::Json => Ok(serde_json::to_string_pretty(&env)?),
+            EnvExportFormat::Dotenv => Ok(vars.iter()
+                .map(|(var, val)| match val {
+                    Value::String(s) => format!("{}={}\n", var, s),
+                    val => format!("{}={}\n", var, val),
+                })
+                .collect()),
+        }
+    }
+
+    /// Checks the env variables against the schema file next to the env file,
+    /// returning a message for each problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let path = Path::new(self.filename.as_deref().unwrap_or(ENV_FILE))
+            .with_file_name(ENV_SCHEMA_FILE);
+        let schema = fs::read_to_string(&path)
+            .map_err(RestClientError::from)
+            .and_then(|schema| Ok(serde_json::from_str::<Value>(&schema)?));
+        match schema {
+            Ok(schema) => self.validate_schema(&schema),
+            Err(err) => vec![format!("could not read schema {}: {}", path.display(), err)],
+        }
+    }
+
+    /// Checks the syntax of the input without running anything, returning a
+    /// message with the line number for each problem found: folds and blocks
+    /// that aren't closed (or closed by the wrong end marker), variable values
+    /// that aren't valid JSON once substituted, and request lines without a
+    /// method and URL. Substitutions are not evaluated, but stand in as 0.
+    pub fn validate_syntax(&self, input: &mut impl BufRead) -> Vec<String> {
+        // kind of block, line it started on, if its request started, and if
+        // the output of an earlier run is being skipped
+        struct Block {
+            kind: &'static str,
+            line: usize,
+            request_started: bool,
+            old_output: bool,
+        }
+        // the same markers as the parser, so a fold titled like a block isn't
+        // taken for one
+        let while_re = marker_regex(FOLD_START, process_while::WHILE_START);
+        let for_re = marker_regex(FOLD_START, process_for::FOR_START);
+        let if_re = marker_regex(FOLD_START, process_if::IF_START);
+        let else_re = marker_regex(FOLD_START, process_if::ELSE);
+        let while_end_re = marker_regex(FOLD_END, process_while::WHILE_END);
+        let for_end_re = marker_regex(FOLD_END, process_for::FOR_END);
+        let if_end_re = marker_regex(FOLD_END, process_if::IF_END);
+        let modifier_append_re = Regex::new(r"^@(tmp|computed)\s+[^ =]+\s*\+=").unwrap();
+        let var_re = Regex::new(r"^@(?:(?:tmp|computed)\s+)?[^ =]*[^ =+]\s*\+?=\s*(.+)$").unwrap();
+        let unset_re = Regex::new(r"^@unset\s+[^ =]+\s*$").unwrap();
+        let selector_re = Regex::new(r"\{\{[^{}]*\}\}").unwrap();
+        // the parser takes anything after the method as the URL, but curl
+        // can't take one with spaces
+        let request_re = Regex::new(r"^[A-Za-z]+ \S+$").unwrap();
+        let mut errors = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, line) in input.lines().enumerate() {
+            let line_no = i + 1;
+            let line = match line {
+                Ok(line) => String::from(line.trim_end()),
+                Err(err) => {
+                    errors.push(format!("line {}: {}", line_no, err));
+                    break;
+                },
+            };
+            if line.starts_with(FOLD_END) {
+                let kind = if while_end_re.is_match(&line) {
+                    "while"
+                } else if for_end_re.is_match(&line) {
+                    "for"
+                } else if if_end_re.is_match(&line) {
+                    "if"
+                } else {
+                    "fold"
+                };
+                match blocks.pop() {
+                    Some(block) if block.kind != kind => errors.push(format!(
+                        "line {}: {} started on line {} is closed by {}",
+                        line_no, block.kind, block.line, line
+                    )),
+                    Some(_) => (),
+                    None => errors.push(format!("line {}: {} closes nothing", line_no, line)),
+                }
+                continue;
+            }
+            if blocks.last().is_some_and(|block| block.old_output) {
+                continue;
+            }
+            if else_re.is_match(&line) {
+                if blocks.last().is_none_or(|block| block.kind != "if") {
+                    errors.push(format!("line {}: else is not in an if block", line_no));
+                }
+                continue;
+            }
+            if line.starts_with(FOLD_START) {
+                blocks.push(Block {
+                    kind: if while_re.is_match(&line) {
+                        "while"
+                    } else if for_re.is_match(&line) {
+                        "for"
+                    } else if if_re.is_match(&line) {
+                        "if"
+                    } else {
+                        "fold"
+                    },
+                    line: line_no,
+                    request_started: false,
+                    old_output: false,
+                });
+                continue;
+            }
+            let block = match blocks.last_mut() {
+                Some(block) => block,
+                None => continue,
+            };
+            if line.starts_with(&self.separator) {
+                block.old_output = true;
+                continue;
+            }
+            if block.request_started || line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('@') {
+                if unset_re.is_match(&line) {
+                    continue;
+                }
+                if let Some(caps) = modifier_append_re.captures(&line) {
+                    errors.push(format!("line {}: @{} can't be used with +=", line_no, &caps[1]));
+                    continue;
+                }
+                let mut value = var_re.captures(&line)
+                    .map_or_else(String::new, |caps| String::from(&caps[1]));
+                while selector_re.is_match(&value) {
+                    value = selector_re.replace_all(&value, "0").to_string();
+                }
+                if let Err(err) = serde_json::from_str::<Value>(&value) {
+                    errors.push(format!("line {}: invalid value for {}: {}", line_no, var_name(&line), err));
+                }
+                continue;
+            }
+            block.request_started = true;
+            if !request_re.is_match(&line) {
+                errors.push(format!("line {}: expected a method and URL: {}", line_no, line));
+            }
+        }
+        for block in blocks {
+            errors.push(format!("line {}: {} is not closed", block.line, block.kind));
+        }
+        errors
+    }
+
+    /// Checks the env variables against the schema, which maps variable names
+    /// to a type, or to an object with the type and if it is required. If the
+    /// schema has "strict": true, variables not in the schema are problems too.
+    fn validate_schema(&self, schema: &Value) -> Vec<String> {
+        let schema = match schema.as_object() {
+            Some(schema) => schema,
+            None => return vec![String::from("schema must be an object")],
+        };
+        let types = ["string", "number", "boolean", "array", "object"];
+        let mut errors = Vec::new();
+        for (var, def) in schema {
+            let (type_name, required) = match def {
+                Value::String(type_name) => (type_name.as_str(), false),
+                Value::Object(def) => (
+                    def.get("type").and_then(Value::as_str).unwrap_or(""),
+                    def.get("required") == Some(&Value::Bool(true)),
+                ),
+                Value::Bool(_) if var == "strict" => continue,
+                _ => {
+                    errors.push(format!("invalid schema for {}", var));
+                    continue;
+                },
+            };
+            if !types.contains(&type_name) {
+                errors.push(format!("unknown type \"{}\" for {}", type_name, var));
+                continue;
+            }
+            match self.env.get(var) {
+                Some(val) if value_type(val) != type_name => errors.push(
+                    format!("{} should be {} but is {}", var, type_name, value_type(val))
+                ),
+                None if required => errors.push(format!("missing required variable {}", var)),
+                _ => (),
+            }
+        }
+        if schema.get("strict") == Some(&Value::Bool(true)) {
+            for (var, _) in self.list_vars() {
+                if !schema.contains_key(&var) {
+                    errors.push(format!("unexpected variable {}", var));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Returns all set variables sorted by name, leaving out internal variables
+    /// that start with __.
+    pub fn list_vars(&self) -> Vec<(String, Value)> {
+        let mut vars = self.env.as_object()
+            .map_or_else(Vec::new, |vars| vars.iter()
+                .filter(|(var, _)| !var.starts_with("__"))
+                .map(|(var, val)| (var.clone(), val.clone()))
+                .collect::<Vec<(String, Value)>>());
+        vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+        vars
+    }
+
+    /// Returns the variables that were added or changed since the env was
+    /// loaded, with their old value (None if the variable is new) and new value.
+    /// Internal variables that start with __ are left out, as are temporary
+    /// ones, like @tmp variables and built-ins such as last_request_ms and the
+    /// loop vars, which are not saved to the env file.
+    pub fn diff_env(&self) -> HashMap<String, (Option<Value>, Value)> {
+        self.list_vars().into_iter()
+            .filter(|(var, _)| !self.tmp_vars.contains(var))
+            .filter_map(|(var, val)| match self.initial_env.get(&var) {
+                Some(old) if *old == val => None,
+                old => Some((var, (old.cloned(), val))),
+            })
+            .collect()
+    }
+
+    /// Returns a copy of the env to restore after a nested fold with the scope,
+    /// or None if the nested fold shares the env.
+    pub fn scoped_env(&self, scope: EnvScope) -> Option<Value> {
+        match scope {
+            EnvScope::Inherited => None,
+            EnvScope::Isolated => Some(self.snapshot()),
+        }
+    }
+
+    /// Returns a copy of the env, which can be restored later.
+    pub fn snapshot(&self) -> Value {
+        self.env.clone()
+    }
+
+    /// Replaces the env with the snapshot and rewrites the env file.
+    pub fn restore(&mut self, snapshot: Value) -> Result<(), RestClientError> {
+        self.env = snapshot;
+        self.write_env()
+    }
+
+    /// Given a variable and value, add it to the env. If persist is set, also
+    /// set the file, otherwise the variable only lives for this session.
+    fn set_var(&mut self, var: &String, val: &Value, persist: bool) -> Result<(), RestClientError> {
+        self.env.as_object_mut()
+            .ok_or(io_error("cannot modify environment"))?
+            .insert(String::from(var), val.clone());
+        if !persist {
+            self.tmp_vars.insert(String::from(var));
+            return Ok(());
+        }
+        self.tmp_vars.remove(var);
+        self.write_env()
+    }
+
+    /// Given a variable, remove it from the env and set file. Returns an error
+    /// if the variable is not set.
+    fn delete_var(&mut self, var: &String) -> Result<(), RestClientError> {
+        self.env.as_object_mut()
+            .ok_or(io_error("cannot modify environment"))?
+            .remove(var)
+            .ok_or(io_error(&format!("variable {} is not set", var)))?;
+        self.secret_vars.remove(var);
+        if self.tmp_vars.remove(var) {
+            return Ok(());
+        }
+        self.write_env()
+    }
+
+    /// Removes the variables whose names start with any of the prefixes, like
+    /// the responses saved with @name, from the env and the env file. Without
+    /// prefixes nothing is removed.
+    pub fn reset_session_vars(&mut self, prefixes: &[&str]) -> Result<(), RestClientError> {
+        if prefixes.is_empty() {
+            return Ok(());
+        }
+        let vars = self.env.as_object_mut()
+            .ok_or(io_error("cannot modify environment"))?;
+        let removed = vars.keys()
+            .filter(|var| prefixes.iter().any(|prefix| var.starts_with(prefix)))
+            .cloned()
+            .collect::<Vec<String>>();
+        for var in &removed {
+            vars.remove(var);
+            // the value in the file is removed too, even for temporary variables
+            self.tmp_vars.remove(var);
+        }
+        self.write_env()
+    }
+
+    /// Writes the env to the env file, leaving out temporary variables and
+    /// variables that are unchanged from the base env. A temporary variable
+    /// that shadows a variable in the file keeps the value from the file.
+    fn write_env(&self) -> Result<(), RestClientError> {
+        let env_file = self.filename.as_ref()
+            .map_or_else(|| ENV_FILE, |f| f);
+        let saved_env = GlobalEnv::read_env(Some(env_file));
+        let mut env = self.env.clone();
+        if let Some(vars) = env.as_object_mut() {
+            vars.retain(|var, val| !self.tmp_vars.contains(var)
+                && self.base_env.get(var) != Some(val));
+            for var in &self.tmp_vars {
+                if let Some(val) = saved_env.get(var) {
+                    vars.insert(var.clone(), val.clone());
+                }
+            }
+        }
+        fs::write(env_file, serde_json::to_string_pretty(&env)?)?;
+        Ok(())
+    }
+
+    /// Given a string, parses the entire string for substitutions marked by any
+    /// selectors in {{}}. If there are none, the original string is returned.
+    /// Allow substitutions to be nested.
+    pub fn parse_selectors(&mut self, s: &String) -> Result<String, RestClientError> {
+        self.parse_nested_selectors(s, &mut HashSet::new())
+    }
+
+    /// Substitutes the selectors in the string, and then in the result if it
+    /// has selectors too. Seen holds the strings already substituted, so a
+    /// variable that refers back to itself is an error instead of recursing
+    /// forever, as is nesting deeper than MAX_SELECTOR_DEPTH.
+    fn parse_nested_selectors
+    (
+        &mut self,
+        s: &String,
+        seen: &mut HashSet<String>,
+    ) -> Result<String, RestClientError> {
+        let depth = seen.len();
+        if depth >= MAX_SELECTOR_DEPTH || !seen.insert(s.clone()) {
+            return Err(RestClientError::ParseError(
+                format!("selector cycle detected at depth {}: {}", depth, s)
+            ));
+        }
+        let re = Regex::new(r"\{\{([^{}]+)\}\}").unwrap();
+        let mut replace_err: Option<RestClientError> = None;
+        let value = re.replace_all(s.as_str(), |caps: &Captures| {
             let selector = caps.get(1);
             if let None = selector {
-                replace_err = Some(String::from("unable to get selector"));
+                replace_err = Some(RestClientError::ParseError(String::from("unable to get selector")));
+                return String::from("ERR");
+            }
+            let selector = selector.unwrap();
+            let selector_val = self.evaluate(&String::from(selector.as_str()));
+            if let Err(err) = selector_val {
+                replace_err = Some(err);
                 return String::from("ERR");
             }
-            let selector = selector.unwrap();
-            let selector_val = self.evaluate(&String::from(selector.as_str()));
-            if let Err(err) = selector_val {
-                replace_err = Some(err.to_string());
-                return String::from("ERR");
+            let selector_val = selector_val.unwrap();
+            selector_val.as_str()
+                .map_or_else(
+                    || selector_val.to_string(),
+                    |s| String::from(s)
+                )
+        });
+        if let Some(err) = replace_err {
+            return Err(err);
+        }
+        let subbed = value.to_string();
+        if re.is_match(&subbed) {
+            return self.parse_nested_selectors(&subbed, seen);
+        }
+        Ok(subbed)
+    }
+
+    /// Given a particular string representing a variable or jq selection, evaluate
+    /// the value in the environment json. If there's an error, return the error
+    /// with the error cause. Due to jq returning null for out-of-bounds or no key,
+    /// this function will have a generic null error message, unless the selector
+    /// uses the alternative operator (//), in which case null is a real value.
+    /// If the selector string represents an environment variable (like $VAR) then
+    /// retrieve the value from the appropriate environment and return a json string.
+    fn evaluate(&mut self, selector: &String) -> Result<Value, RestClientError> {
+        match self.evaluate_or_null(selector)? {
+            Value::Null if !selector.contains("//") => {
+                Err(RestClientError::SelectorNotFound(selector.clone()))
+            },
+            res_val => Ok(res_val)
+        }
+    }
+
+    /// Like evaluate, but null is returned as is, for conditions where a
+    /// variable that isn't set counts as false.
+    pub(crate) fn evaluate_or_null(&mut self, selector: &String) -> Result<Value, RestClientError> {
+        if let Some(val) = self.get_env_var(selector)? {
+            return Ok(val);
+        }
+        // reading a variable set from a secret is reading the secret
+        if self.secret_vars.iter().any(|var| Regex::new(&format!(r"\.{}\b", regex::escape(var)))
+            .is_ok_and(|re| re.is_match(selector))) {
+            self.secret_read = true;
+        }
+        let res_val = match self.with_computed(selector)? {
+            Some(env) => run_jq(selector, &env),
+            None => run_jq(selector, &self.env),
+        };
+        // jq errors don't say which selector failed
+        res_val.map_err(|err| match err {
+            RestClientError::Jq(e) => RestClientError::Jq(format!("{}: {}", selector, e)),
+            err => err,
+        })
+    }
+
+    /// Returns the env with the values of the computed variables used by the
+    /// selector, substituted from the current env, or None if it uses none. A
+    /// computed variable used in its own value is left out, so it can't recurse
+    /// forever.
+    fn with_computed(&mut self, selector: &str) -> Result<Option<Value>, RestClientError> {
+        let computed = self.env.as_object()
+            .map_or_else(Vec::new, |vars| vars.iter()
+                .filter_map(|(var, val)| var.strip_prefix(COMPUTED_PREFIX)
+                    .zip(val.as_str())
+                    .map(|(var, val)| (String::from(var), String::from(val))))
+                .filter(|(var, _)| !self.computing.contains(var))
+                .filter(|(var, _)| Regex::new(&format!(r"\.{}\b", regex::escape(var)))
+                    .is_ok_and(|re| re.is_match(selector)))
+                .collect::<Vec<(String, String)>>());
+        if computed.is_empty() {
+            return Ok(None);
+        }
+        let mut env = self.env.clone();
+        for (var, expr) in computed {
+            self.computing.insert(var.clone());
+            let value = self.parse_selectors(&expr);
+            self.computing.remove(&var);
+            env[var.as_str()] = serde_json::from_str(&value?)?;
+        }
+        Ok(Some(env))
+    }
+
+    /// Given a selector, checks if it has the pattern for an environment variable,
+    /// like $VAR. If not, return None, otherwise return the value of the env var if
+    /// it exists, or an empty string. If sshTo is defined, then retrieve the
+    /// environment variable on the desired machine.
+    /// A secret like $$VAR is read the same way, but its value is remembered so
+    /// it is never written to the env file or shown in the output.
+    fn get_env_var
+    (
+        &mut self,
+        selector: &String,
+    ) -> Result<Option<Value>, RestClientError> {
+        let env_var_re = Regex::new(r"^\$(\$)?(.*)$").unwrap();
+        if let Some(caps) = env_var_re.captures(selector) {
+            if selector.contains('(') {
+                return self.command_substitution(selector);
+            }
+            let is_secret = caps.get(1).is_some();
+            let var = caps.get(2).unwrap().as_str();
+            let val = if let Some(_) = self.env.get(SSH_TO) {
+                let rt = Runtime::new()?;
+                rt.block_on(self.ssh_get_env_var(&format!("${}", var)))?
+            } else {
+                env::var(var).map_or_else(|_| json!(""), |val| json!(val))
+            };
+            if is_secret {
+                self.secret_read = true;
+                if let Some(secret) = val.as_str().filter(|secret| !secret.is_empty()) {
+                    self.secrets.insert(String::from(secret));
+                }
+            }
+            Ok(Some(val))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Substitutes with the output of a command. Allows for executing things to
+    /// get the string, like $(lsb_release -a).
+    fn command_substitution
+    (
+        &mut self,
+        selector: &String,
+    ) -> Result<Option<Value>, RestClientError> {
+        if let Some(_) = self.env.get(SSH_TO) {
+            let rt = Runtime::new()?;
+            return rt.block_on(self.ssh_command_substitution(selector));
+        }
+        let echo = Command::new("bash")
+            .arg("-c")
+            .arg(format!("echo \"{}\"", selector))
+            .output()?;
+        let e = String::from_utf8_lossy(&echo.stderr).to_string();
+        if !echo.status.success() {
+            return Err(io_error(&e))?;
+        }
+        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
+        let ret = ret.replace('\n', "");
+        Ok(Some(json!(ret)))
+    }
+
+    /// With retry_transient (or the global retry_transient), curl is run again
+    /// when it fails to resolve the host or connect, up to once for each of
+    /// TRANSIENT_RETRY_DELAYS_MS.
+    /// The output is cut to max_bytes, without carriage returns.
+    fn call_curl
+    (
+        &mut self,
+        args: &Vec<String>,
+        max_bytes: usize,
+        retry_transient: bool,
+    ) -> Result<(String, String), RestClientError> {
+        let (ret, e) = self.call_curl_raw(args, retry_transient)?;
+        let ret = String::from_utf8_lossy(&ret).replace('\r', "");
+        Ok((truncate_response(ret, max_bytes), e))
+    }
+
+    /// Like call_curl, but returns the output of curl as it is, for a body
+    /// which has to be saved untouched, like a binary download.
+    fn call_curl_raw
+    (
+        &mut self,
+        args: &Vec<String>,
+        retry_transient: bool,
+    ) -> Result<(Vec<u8>, String), RestClientError> {
+        let start = Instant::now();
+        self.last_request_at = Some(start);
+        let delays_ms: &[u64] = if retry_transient || self.retry_transient {
+            &TRANSIENT_RETRY_DELAYS_MS
+        } else {
+            &[]
+        };
+        let res = self.with_transient_retries(delays_ms, |g_env| {
+            if let Some(_) = g_env.env.get(SSH_TO) {
+                let rt = Runtime::new()?;
+                rt.block_on(g_env.ssh_curl(args))
+            } else {
+                g_env.local_curl(args)
+            }
+        });
+        self.set_last_request_ms(start.elapsed().as_millis() as u64);
+        res
+    }
+
+    /// Runs curl, and runs it again after each of the delays as long as it
+    /// fails with one of TRANSIENT_CURL_CODES. Each retry is recorded in
+    /// transient_retries, for the output of the fold.
+    fn with_transient_retries
+    (
+        &mut self,
+        delays_ms: &[u64],
+        mut curl: impl FnMut(&mut GlobalEnv) -> Result<(Vec<u8>, String), RestClientError>,
+    ) -> Result<(Vec<u8>, String), RestClientError> {
+        let mut retries = 0;
+        loop {
+            match curl(self) {
+                Err(RestClientError::Curl(_, code))
+                    if TRANSIENT_CURL_CODES.contains(&code) && retries < delays_ms.len() => {
+                    let delay_ms = delays_ms[retries];
+                    retries += 1;
+                    self.transient_retries.push(format!(
+                        "curl failed with exit code {}, retry {} of {} in {}ms",
+                        code, retries, delays_ms.len(), delay_ms
+                    ));
+                    thread::sleep(Duration::from_millis(delay_ms));
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Records the time taken by the last request, and makes it available as a
+    /// built-in variable, which isn't written to the env file
+    fn set_last_request_ms(&mut self, ms: u64) {
+        self.last_request_ms = Some(ms);
+        if let Some(vars) = self.env.as_object_mut() {
+            vars.insert(String::from(LAST_REQUEST_MS), json!(ms));
+            self.tmp_vars.insert(String::from(LAST_REQUEST_MS));
+        }
+    }
+
+    fn local_curl
+    (
+        &mut self,
+        args: &Vec<String>,
+    ) -> Result<(Vec<u8>, String), RestClientError> {
+        let curl = Command::new("curl")
+            .args(args)
+            .output()?;
+        let e = String::from_utf8_lossy(&curl.stderr).to_string();
+        if !curl.status.success() {
+            return Err(RestClientError::Curl(e, curl.status.code().unwrap_or(-1)));
+        }
+        let e = e.replace('\r', "");
+        Ok((curl.stdout, e))
+    }
+
+    /// Takes the cached session for the destination, or connects to it with the
+    /// SSH config, key and port from the env, if they are set. The session is
+    /// put back in the cache when the returned guard is dropped.
+    async fn get_or_create_session(&mut self, dest: &str) -> Result<SshSessionGuard<'_>, RestClientError> {
+        let mut session_builder = SessionBuilder::default();
+        if let Some(config) = self.env.get(SSH_CONFIG) {
+            let config = config.as_str().ok_or_else(|| RestClientError::Ssh(format!("{} was not a string", SSH_CONFIG)))?;
+            session_builder.config_file(config);
+        }
+        if let Some(key) = self.env.get(SSH_KEY) {
+            let key = key.as_str().ok_or_else(|| RestClientError::Ssh(format!("{} was not a string", SSH_KEY)))?;
+            session_builder.keyfile(key);
+        }
+        if let Some(port) = self.env.get(SSH_PORT) {
+            session_builder.port(parse_ssh_port(port)?);
+        }
+        // only a single jump host for now, multiple hops would need a comma
+        // separated list to be split here
+        if let Some(jump_host) = self.ssh_jump_host()? {
+            session_builder.jump_hosts([jump_host]);
+        }
+        // a cached session can die without notice, so it is checked first,
+        // and replaced with a new one if it doesn't answer
+        let timeout = self.ssh_health_check_timeout();
+        let connect = async { Ok(session_builder.connect_mux(dest).await?) };
+        take_or_connect(&mut self.sessions.sessions, dest, timeout, connect).await
+    }
+
+    /// Gets the time a cached session has to answer the health check, which
+    /// can be set in milliseconds with sshHealthCheckTimeout.
+    fn ssh_health_check_timeout(&self) -> Duration {
+        let timeout_ms = self.env.get(SSH_HEALTH_CHECK_TIMEOUT)
+            .and_then(|timeout| timeout.as_u64()
+                .or_else(|| timeout.as_str().and_then(|timeout| timeout.parse().ok())))
+            .unwrap_or(self.session_health_check_timeout_ms);
+        Duration::from_millis(timeout_ms)
+    }
+
+    /// Gets the jump host to connect through from the env, if it is set, with
+    /// any substitutions made
+    fn ssh_jump_host(&mut self) -> Result<Option<String>, RestClientError> {
+        match self.env.get(SSH_JUMP_HOST).cloned() {
+            Some(Value::String(jump_host)) => Ok(Some(self.parse_selectors(&jump_host)?)),
+            Some(_) => Err(RestClientError::Ssh(format!("{} was not a string", SSH_JUMP_HOST))),
+            None => Ok(None),
+        }
+    }
+
+    async fn ssh_curl
+    (
+        &mut self,
+        args: &Vec<String>,
+    ) -> Result<(Vec<u8>, String), RestClientError> {
+        let dest = self.env.get(SSH_TO)
+            .unwrap()
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| RestClientError::Ssh(format!("{} was not a string", SSH_TO)))?;
+        let session = self.get_or_create_session(&dest).await?;
+        let curl = session.command("curl")
+            .args(args)
+            .output()
+            .await?;
+        let e = String::from_utf8_lossy(&curl.stderr).to_string();
+        if !curl.status.success() {
+            return Err(RestClientError::Curl(e, curl.status.code().unwrap_or(-1)));
+        }
+        let e = e.replace('\r', "");
+        Ok((curl.stdout, e))
+    }
+
+    async fn ssh_get_env_var(&mut self, var: &String) -> Result<Value, RestClientError> {
+        let dest = self.env.get(SSH_TO)
+            .unwrap()
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| RestClientError::Ssh(format!("{} was not a string", SSH_TO)))?;
+        let session = self.get_or_create_session(&dest).await?;
+        let echo = session.command("echo")
+            .raw_arg(var)
+            .output()
+            .await?;
+        let e = String::from_utf8_lossy(&echo.stderr).to_string();
+        if !echo.status.success() {
+            return Err(RestClientError::Ssh(e));
+        }
+        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
+        let ret = ret.replace('\r', "");
+        let ret = ret.replace('\n', "");
+        Ok(json!(ret))
+    }
+
+    async fn ssh_command_substitution(&mut self, selector: &str) -> Result<Option<Value>, RestClientError> {
+        let dest = self.env.get(SSH_TO)
+            .unwrap()
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| RestClientError::Ssh(format!("{} was not a string", SSH_TO)))?;
+        let session = self.get_or_create_session(&dest).await?;
+        let echo = session.command("echo")
+            .raw_arg(selector)
+            .output()
+            .await?;
+        let e = String::from_utf8_lossy(&echo.stderr).to_string();
+        if !echo.status.success() {
+            return Err(RestClientError::Ssh(e));
+        }
+        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
+        let ret = ret.replace('\r', "");
+        let ret = ret.replace('\n', "");
+        Ok(Some(json!(ret)))
+    }
+}
+
+/// Streams the results of parse_input, one fold at a time, so each fold can be
+/// shown as soon as it is executed. Text in between folds is returned before
+/// the fold that follows it.
+pub struct FoldStream<'a, R: BufRead> {
+    reader: R,
+    g_env: &'a mut GlobalEnv,
+    ignore_first_loop: bool,            // if the first loop should be run as a plain fold
+    fold_env: FoldEnv,                  // the fold being executed
+    fold_started: bool,
+    first_loop: bool,
+    text: Option<FoldResult>,           // text in between folds read so far
+    pending: VecDeque<FoldResult>,      // results ready to be returned
+    any_results: bool,                  // if anything was returned yet
+    done: bool,                         // if the input was read to the end
+    flags: Flags,
+    start_fold_re: Regex,
+    executed_re: Regex,
+    if_re: Regex,
+    while_re: Regex,
+    for_re: Regex,
+}
+
+impl<'a, R: BufRead> FoldStream<'a, R> {
+    pub fn new
+    (
+        reader: R,
+        g_env: &'a mut GlobalEnv,
+        ignore_first_loop: bool,
+    ) -> FoldStream<'a, R> {
+        FoldStream {
+            reader,
+            g_env,
+            ignore_first_loop,
+            fold_env: FoldEnv::new(),
+            fold_started: false,
+            first_loop: true,
+            text: None,
+            pending: VecDeque::new(),
+            any_results: false,
+            done: false,
+            flags: Flags::new(),
+            start_fold_re: Regex::new(r"^(###\{\s*(.*))$").unwrap(),
+            executed_re: Regex::new(&format!(r" ?executed( \(({})(, \d+ms)?\))?$", STATUSES)).unwrap(),
+            if_re: marker_regex(FOLD_START, process_if::IF_START),
+            while_re: marker_regex(FOLD_START, process_while::WHILE_START),
+            for_re: marker_regex(FOLD_START, process_for::FOR_START),
+        }
+    }
+
+    /// Queues the result of a fold, after the text in between folds before it
+    fn push(&mut self, result: FoldResult) {
+        if let Some(text) = self.text.take() {
+            self.pending.push_back(text);
+        }
+        self.pending.push_back(result);
+        self.any_results = true;
+    }
+
+    /// Adds a line of text in between folds, leaving out leading empty lines
+    fn push_text(&mut self, line: &str) {
+        match self.text.as_mut() {
+            Some(text) => {
+                text.input.push('\n');
+                text.input.push_str(line);
+            },
+            None if line.is_empty() && !self.any_results => (),
+            None => self.text = Some(FoldResult::text(line)),
+        }
+    }
+
+    /// Reads and processes the next line of input
+    fn read_line(&mut self) {
+        let mut line = String::new();
+        let res = self.reader.read_line(&mut line);
+        line = String::from((&line).trim_end());
+        match res {
+            Ok(0) => {
+                self.finish();
+                return;
+            },
+            Ok(_) => (),
+            Err(e) => {
+                self.fold_env.error = true;
+                self.fold_env.output.push_str(&e.to_string());
+            },
+        };
+        self.process_line(line);
+    }
+
+    /// Runs the last fold if it was not ended
+    fn finish(&mut self) {
+        self.done = true;
+        if !self.fold_env.made_request {
+            self.fold_env.make_request(self.g_env);
+            self.fold_env.rollback(self.g_env);
+            self.fold_env.redact(self.g_env);
+            if let Some(result) = self.fold_env.compile_return(self.g_env) {
+                self.push(result);
+            }
+        }
+    }
+
+    fn process_line(&mut self, line: String) {
+        let start_if = self.if_re.is_match(&line);
+        let start_while = self.while_re.is_match(&line);
+        let start_for = self.for_re.is_match(&line);
+        let start_loop = start_while || start_for;
+        if start_if || (start_loop && !(self.ignore_first_loop && self.first_loop)) {
+            let started = Instant::now();
+            // the output shows the time taken by the whole block, instead
+            // of only its last run
+            let (output, (nest_ret, nest_out), error) = if start_if {
+                let mut b = process_if::IfBlock::parse_if(&line, &mut self.reader, self.g_env);
+                b.output = with_duration(&b.output, started.elapsed().as_millis() as u64);
+                (b.output.clone(), b.compile_return(), b.error)
+            } else if start_while {
+                let mut w = process_while::While::parse_while(&line, &mut self.reader, self.g_env);
+                w.output = with_duration(&w.output, started.elapsed().as_millis() as u64);
+                (w.output.clone(), w.compile_return(), w.error)
+            } else {
+                let mut f = process_for::For::parse_for(&line, &mut self.reader, self.g_env);
+                f.output = with_duration(&f.output, started.elapsed().as_millis() as u64);
+                (f.output.clone(), f.compile_return(), f.error)
+            };
+            if self.fold_started {
+                self.fold_env.ret.push_str(&nest_ret);
+                self.fold_env.output.push_str(&nest_out);
+                self.fold_env.error = self.fold_env.error || error;
+            } else {
+                let duration_ms = started.elapsed().as_millis() as u64;
+                self.push(FoldResult::from_rendered(&output, &self.g_env.separator, error, duration_ms));
+            }
+            if start_loop {
+                self.first_loop = false;
+            }
+            return;
+        } else if start_loop {
+            self.first_loop = false;
+        }
+        if let Some(caps) = self.start_fold_re.captures(&line) {
+            if !self.fold_started {
+                self.fold_started = true;
+                self.fold_env = FoldEnv::new();
+            } else {
+                // if creating a new nested_fold, then check for request and run it
+                if !self.fold_env.made_request {
+                    self.fold_env.make_request(self.g_env);
+                }
+                let mut nested_fold = FoldEnv::new();
+                // folds nested in a skipped fold are skipped as well
+                nested_fold.skip = self.fold_env.skip;
+                nested_fold.scoped_env = self.g_env.scoped_env(self.g_env.scope);
+                let parent_fold = mem::replace(&mut self.fold_env, nested_fold);
+                self.fold_env.parent_fold = Some(Box::new(parent_fold));
+            }
+            // the folds after a @break are skipped until the loop ends
+            if self.g_env.break_label.is_some() {
+                self.fold_env.skip = true;
+            }
+            if let Some(res) = caps.get(2) {
+                let no_exec = self.executed_re.replace(res.as_str(), "");
+                if !no_exec.to_string().is_empty() {
+                    self.fold_env.title = format!("{} ", no_exec.to_string());
+                }
+            }
+            if let Some(res) = caps.get(1) {
+                let no_exec = self.executed_re.replace(res.as_str(), "");
+                self.fold_env.start_marker = no_exec.to_string();
+            } else {
+                self.fold_env.start_marker = String::from(FOLD_START);
+            }
+            self.fold_env.first_line = false;
+            return;
+        } else if self.fold_env.first_line && self.fold_started {
+            self.fold_env.start_marker = String::from(FOLD_START);
+            self.fold_env.first_line = false;
+        } else if !self.fold_started {
+            // push stuff in between folds
+            self.push_text(&line);
+        }
+        if !self.fold_started {
+            return;
+        }
+        let separator = self.fold_env.separator.as_ref().unwrap_or(&self.g_env.separator);
+        if line.starts_with(separator.as_str()) && self.fold_started {
+            self.fold_env.old_output_started = true;
+            return;
+        }
+        if line.starts_with(FOLD_END) {
+            self.fold_env.end_marker = String::from(&line);
+            if !self.fold_env.made_request {
+                self.fold_env.make_request(self.g_env);
+            }
+            self.fold_env.rollback(self.g_env);
+            self.fold_env.restore_scope(self.g_env);
+            self.fold_env.redact(self.g_env);
+            if self.fold_env.parent_fold.is_some() {
+                let (nest_ret, nest_out) = &self.fold_env.compile_for_parent();
+                self.fold_env.parent_fold.as_mut().unwrap().ret.push_str(&nest_ret);
+                self.fold_env.parent_fold.as_mut().unwrap().output.push_str(&nest_out);
+                let mut parent_err = self.fold_env.parent_fold.as_mut().unwrap().error;
+                parent_err = self.fold_env.error || parent_err;
+                self.fold_env = *self.fold_env.parent_fold.take().unwrap();
+                self.fold_env.error = parent_err;
+            } else {
+                if let Some(result) = self.fold_env.compile_return(self.g_env) {
+                    self.push(result);
+                }
+                self.fold_started = false;
+            }
+            return;
+        }
+        if self.fold_env.old_output_started {
+            return;
+        }
+        let fold_env = &mut self.fold_env;
+        let g_env = &mut *self.g_env;
+        insert_newline(&mut fold_env.ret);
+        fold_env.ret.push_str(&line);
+        fold_env.ret.push('\n');
+        if !line.is_empty() && !line.starts_with('#') {
+            fold_env.current_line += 1;
+        }
+        if fold_env.error || fold_env.skip {
+            return;
+        }
+        if line.starts_with('@') {
+            // keep the env from before the first variable is set, in case
+            // the fold fails
+            if fold_env.snapshot.is_none() {
+                fold_env.snapshot = Some(g_env.snapshot());
+            }
+            // for each line that starts with @, call define_var, and show which
+            // line failed on error
+            let res_line = g_env.define_var(&String::from(&line))
+                .map_or_else(
+                    |err| {
+                        fold_env.error = true;
+                        format!("{}: line {}: {}\n", var_name(&line), fold_env.current_line, err.to_string())
+                    },
+                    |res| format!("{}\n", res)
+                );
+            insert_newline(&mut fold_env.output);
+            fold_env.output.push_str(&res_line);
+        } else if line.starts_with('#') {
+            // parse and check flags, else skip comment
+            fold_env.parse_flags(&line, &self.flags);
+            fold_env.check_import(g_env, &line, &self.flags);
+            fold_env.check_skip_if(g_env, &line, &self.flags);
+            fold_env.check_break(g_env, &line, &self.flags);
+        } else if !fold_env.request_started && line.is_empty() {
+            // line breaks should be ignored, but appear in output
+            fold_env.output.push('\n');
+        } else if !fold_env.request_started {
+            // parse method and URL
+            line.split_once(' ')
+                .map_or_else(
+                    || {
+                        fold_env.error = true;
+                        insert_newline(&mut fold_env.output);
+                        fold_env.output.push_str(&format!("Could not parse line: {}\n", line));
+                        ()
+                    },
+                    |(m, url_str)| {
+                        fold_env.made_request = false;
+                        fold_env.method = Method::get_match(m);
+                        fold_env.url = String::from(url_str);
+                        ()
+                    }
+                );
+            fold_env.request_started = true;
+        } else if !fold_env.request_body_started && !line.is_empty() {
+            fold_env.headers.push(String::from(line));
+        } else if !fold_env.request_body_started && line.is_empty() {
+            fold_env.request_body_started = true
+        } else if fold_env.request_body_started {
+            // keep the lines of a query apart, since whitespace separates
+            // GraphQL fields
+            if fold_env.is_graphql && !fold_env.request_body.is_empty() {
+                fold_env.request_body.push('\n');
+            }
+            fold_env.request_body.push_str(&line);
+        }
+    }
+}
+
+impl<'a, R: BufRead> Iterator for FoldStream<'a, R> {
+    type Item = FoldResult;
+
+    /// Reads lines until a fold is executed, and returns its result, or the
+    /// text in between folds before it
+    fn next(&mut self) -> Option<FoldResult> {
+        loop {
+            if let Some(result) = self.pending.pop_front() {
+                return Some(result);
+            }
+            if self.done {
+                return self.text.take();
+            }
+            self.read_line();
+        }
+    }
+}
+
+
+/// Time to wait before the next request to keep to the rate limit, given the
+/// time since the last request
+fn rate_limit_sleep(rps: f64, since_last: Duration) -> Duration {
+    Duration::from_secs_f64(1.0 / rps).saturating_sub(since_last)
+}
+
+/// Gets the SSH port from the env value, which can be a number or a string
+/// of digits
+fn parse_ssh_port(port: &Value) -> Result<u16, RestClientError> {
+    let parsed = match port {
+        Value::Number(n) => n.as_u64().and_then(|n| u16::try_from(n).ok()),
+        Value::String(s) => s.parse::<u16>().ok(),
+        _ => None,
+    };
+    parsed.filter(|port| *port != 0)
+        .ok_or_else(|| RestClientError::Ssh(format!("{} was not a valid port: {}", SSH_PORT, port)))
+}
+
+/// Returns the env file used for the named environment, like .env.staging.json
+pub fn named_env_file(name: &str) -> String {
+    format!(".env.{}.json", name)
+}
+
+/// Looks for the env file in the start directory and then each of its
+/// ancestors, returning the path of the first one found. An absolute filename
+/// is only looked for where it is.
+fn find_env_file(start: &Path, filename: &str) -> Option<PathBuf> {
+    start.ancestors()
+        .map(|dir| dir.join(filename))
+        .find(|path| path.is_file())
+}
+
+/// Returns an error
+pub fn io_error(err: &str) -> RestClientError {
+    RestClientError::Io(io::Error::new(io::ErrorKind::Other, err))
+}
+
+/// Parses KEY=VALUE lines into a JSON object, skipping empty lines and
+/// comments. Values that are valid JSON (like numbers) keep their type, and
+/// other values are strings, without any surrounding quotes.
+fn parse_dotenv(contents: &str) -> Value {
+    let mut vars = serde_json::Map::new();
+    for (var, val) in contents.lines().filter_map(parse_dotenv_line) {
+        let val = serde_json::from_str::<Value>(val).unwrap_or_else(|_| {
+            json!(val.trim_matches(|c| c == '\'' || c == '"'))
+        });
+        vars.insert(String::from(var), val);
+    }
+    Value::Object(vars)
+}
+
+/// Splits a dotenv line at the first =, into the trimmed name and the raw
+/// value, or None for empty lines, comments and lines without =
+fn parse_dotenv_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    line.split_once('=').map(|(var, val)| (var.trim(), val.trim()))
+}
+
+/// Replaces $VAR and ${VAR} with the value of the OS environment variable, or
+/// an empty string if it isn't set
+fn expand_os_vars(val: &str) -> String {
+    let var_re = Regex::new(r"\$(?:\{(\w+)\}|(\w+))").unwrap();
+    var_re.replace_all(val, |caps: &Captures| {
+        let var = caps.get(1).or(caps.get(2)).map_or("", |var| var.as_str());
+        env::var(var).unwrap_or_default()
+    }).to_string()
+}
+
+/// Name of the JSON type of the value, as used in the env schema
+fn value_type(val: &Value) -> &'static str {
+    match val {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Gets the status code from the last HTTP status line in the headers, which
+/// may be prefixed with "< " in verbose output.
+fn parse_status(headers: &str) -> Option<u64> {
+    let status_re = Regex::new(r"(?m)^(?:< )?HTTP/[0-9.]+ (\d{3})").unwrap();
+    status_re.captures_iter(headers)
+        .last()
+        .and_then(|caps| caps[1].parse::<u64>().ok())
+}
+
+/// Gets the URL of the Link header entry with rel="next", from the headers of
+/// the last response if there were redirects
+fn link_next(headers: &str) -> Option<String> {
+    let header_re = Regex::new(r"(?im)^(?:< )?link:\s*(.*)$").unwrap();
+    let entry_re = Regex::new(r"<([^>]*)>([^<]*)").unwrap();
+    let rel_re = Regex::new(r#"(?i)\brel\s*=\s*"?([^";,]*)"?"#).unwrap();
+    let last_response = headers.rsplit("\n\n").next().unwrap_or("");
+    let entries = header_re.captures_iter(last_response)
+        .flat_map(|caps| entry_re.captures_iter(&caps[1])
+            .map(|entry| (String::from(&entry[1]), String::from(&entry[2])))
+            .collect::<Vec<(String, String)>>())
+        .collect::<Vec<(String, String)>>();
+    entries.into_iter()
+        .find(|(_, params)| rel_re.captures(params)
+            .is_some_and(|rel| rel[1].split_whitespace().any(|rel| rel.eq_ignore_ascii_case("next"))))
+        .map(|(url, _)| url)
+}
+
+/// Resolves the URL against the URL of the page it was found on, so relative
+/// URLs like /items?page=2 or ?page=2 keep the host and path of that page.
+/// Dot segments are left for curl to remove.
+fn resolve_url(base: &str, url: &str) -> String {
+    let absolute_re = Regex::new(r"^[A-Za-z][A-Za-z0-9+.-]*://").unwrap();
+    if absolute_re.is_match(url) {
+        return String::from(url);
+    }
+    let (scheme, rest) = match base.find("://") {
+        Some(i) => base.split_at(i + 3),
+        None => ("", base),
+    };
+    if let Some(url) = url.strip_prefix("//") {
+        return format!("{}{}", if scheme.is_empty() { "//" } else { scheme }, url);
+    }
+    let path_start = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(path_start);
+    let path = &path[..path.find(['?', '#']).unwrap_or(path.len())];
+    if url.starts_with('/') {
+        format!("{}{}{}", scheme, host, url)
+    } else if url.starts_with('?') || url.starts_with('#') {
+        format!("{}{}{}{}", scheme, host, path, url)
+    } else {
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}{}{}{}", scheme, host, if dir.is_empty() { "/" } else { dir }, url)
+    }
+}
+
+/// Adds the content type for the field of the multipart form, if there is
+/// one, unless the form already sets a type
+fn with_form_type(form: &str, types: &HashMap<String, String>) -> String {
+    let field = form.split('=').next().unwrap_or("");
+    match types.get(field) {
+        Some(content_type) if !form.contains(";type=") => format!("{};type={}", form, content_type),
+        _ => String::from(form),
+    }
+}
+
+/// Formats the time as an ISO 8601 UTC timestamp, like 2024-05-01T12:30:00.123Z
+fn iso_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch, in 400 year eras starting in March
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        day_secs / 3600, day_secs % 3600 / 60, day_secs % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// Splits the output of curl --include into the headers, with a block for
+/// each response when following redirects, and the body, which is left as
+/// curl got it.
+fn split_raw_response(raw: &[u8]) -> (String, &[u8]) {
+    let mut headers = Vec::new();
+    let mut rest = raw;
+    while rest.starts_with(b"HTTP") {
+        let blank_line = rest.windows(4).position(|bytes| bytes == b"\r\n\r\n").map(|i| (i, 4))
+            .or_else(|| rest.windows(2).position(|bytes| bytes == b"\n\n").map(|i| (i, 2)));
+        let (end, next) = blank_line.map_or((rest.len(), rest.len()), |(i, len)| (i, i + len));
+        headers.push(String::from_utf8_lossy(&rest[..end]).replace('\r', ""));
+        rest = &rest[next..];
+    }
+    (headers.join("\n\n"), rest)
+}
+
+/// Truncates the response if it is larger than max_bytes, noting where it was
+/// truncated on the last line.
+fn truncate_response(mut ret: String, max_bytes: usize) -> String {
+    if ret.len() <= max_bytes {
+        return ret;
+    }
+    let mut end = max_bytes;
+    while !ret.is_char_boundary(end) {
+        end -= 1;
+    }
+    ret.truncate(end);
+    insert_newline(&mut ret);
+    ret.push_str(&format!("[response truncated at {} bytes]", max_bytes));
+    ret
+}
+
+/// Gets the variable of a variable line, like @name for @tmp name = 1
+fn var_name(var_line: &str) -> String {
+    let re = Regex::new(r"^@(?:(?:tmp|computed|unset)\s+)?([^ =]+)").unwrap();
+    re.captures(var_line)
+        .map_or_else(|| String::from(var_line), |caps| format!("@{}", &caps[1]))
+}
+
+/// Runs the jq selector against the given JSON value
+fn run_jq(selector: &str, val: &Value) -> Result<Value, RestClientError> {
+    let val_str = val.to_string();
+    let res_str = catch_jq_panic(|| jq_rs::run(selector, &val_str))??;
+    Ok(serde_json::from_str(&res_str)?)
+}
+
+/// Runs the jq call, turning a panic into an error, since jq_rs can panic on
+/// malformed filters and a panic would leave the Vim buffer half replaced
+fn catch_jq_panic<T>(jq: impl FnOnce() -> T) -> Result<T, RestClientError> {
+    panic::catch_unwind(AssertUnwindSafe(jq)).map_err(|payload| {
+        let message = payload.downcast_ref::<&str>()
+            .map(|message| String::from(*message))
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown error"));
+        RestClientError::Jq(format!("jq panicked: {}", message))
+    })
+}
+
+/// Adds a newline to the string if the last char is not a newline
+fn insert_newline(s: &mut String) {
+    if !s.is_empty() && s.chars().last().unwrap() != '\n' {
+        s.push('\n');
+    }
+}
+
+
+///////////////////////////////////////////////
+/// Unit tests
+///////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clear_env_file() {
+        if let Err(_) = fs::remove_file(ENV_FILE) {
+            println!("file doesn't exist")
+        } else {
+            println!("file deleted")
+        }
+    }
+
+    /// Removes the times from the fold statuses, since they change every run
+    pub(crate) fn strip_durations(output: &str) -> String {
+        Regex::new(r", \d+ms\)").unwrap().replace_all(output, ")").to_string()
+    }
+
+    #[test]
+    fn test_selector_cycle() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "a": "{{.a}}",
+            "b": "{{.c}}",
+            "c": "{{.b}}",
+            "d": "{{.e}}",
+            "e": "done"
+        });
+        {
+            let res = g_env.parse_selectors(&String::from("{{.a}}"));
+            match res {
+                Err(RestClientError::ParseError(e)) => assert_eq!(e, "selector cycle detected at depth 1: {{.a}}"),
+                res => assert!(false, "Expected cycle error, got {:?}", res.map_err(|e| e.to_string())),
+            }
+        }
+        {
+            let res = g_env.parse_selectors(&String::from("x{{.b}}"));
+            match res {
+                Err(RestClientError::ParseError(e)) => assert_eq!(e, "selector cycle detected at depth 2: x{{.b}}"),
+                res => assert!(false, "Expected cycle error, got {:?}", res.map_err(|e| e.to_string())),
+            }
+        }
+        {
+            let res = g_env.parse_selectors(&String::from("{{.d}}")).unwrap();
+            assert_eq!(res, "done");
+        }
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        // create dummy env (json) and call evaluate to see if it returns the
+        // right values
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "arr": ["a", "b", "c"],
+            "str": "value",
+            "num": 1,
+            "bool": true,
+            "obj": {"a": 1, "b": 2},
+            "a": "test",
+            "a1": "success"
+        });
+
+        {
+            let s = String::from("\"Some String\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            assert_eq!(res, s, "Expected {}, but got {}", s, res);
+        }
+        {
+            let s = String::from("\"Some {{.str}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"Some value\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.obj.{{.arr[0]}}}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"1\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.{{.arr[0]}}}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"test\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.{{.arr[0]}}{{.num}}}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"success\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.missing // \"fallback\"}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"fallback\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.str // \"fallback\"}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"value\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.missing // .other // \"c\"}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"c\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+        {
+            let s = String::from("\"{{.missing // .a // \"c\"}}\"");
+            let res = g_env.parse_selectors(&s).unwrap();
+            let expect = String::from("\"test\"");
+            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // create dummy env (json) and call evaluate to see if it returns the
+        // right values
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "arr": ["a", "b", "c"],
+            "str": "value",
+            "num": 1,
+            "bool": true,
+            "obj": {"a": 1, "b": 2}
+        });
+        {
+            let arr = g_env.evaluate(&String::from(".arr")).unwrap();
+            assert_eq!(arr, json!(["a", "b", "c"]), "Expected [\"a\", \"b\", \"c\"], but got {:?}", arr);
+            let arr0 = g_env.evaluate(&String::from(".arr[0]")).unwrap();
+            assert_eq!(arr0, json!("a"), "Expected \"a\", but got {:?}", arr0);
+            let arr_err = g_env.evaluate(&String::from(".arr[3]"));
+            match arr_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "failed to get resource at .arr[3]",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            let strng = g_env.evaluate(&String::from(".str")).unwrap();
+            assert_eq!(strng, json!("value"), "Expected \"value\", but got {:?}", strng);
+            let num = g_env.evaluate(&String::from(".num")).unwrap();
+            assert_eq!(num, json!(1), "Expected 1, but got {:?}", num);
+            let boolean = g_env.evaluate(&String::from(".bool")).unwrap();
+            assert_eq!(boolean, json!(true), "Expected true, but got {:?}", boolean);
+        }
+        {
+            let obj = g_env.evaluate(&String::from(".obj")).unwrap();
+            assert_eq!(obj, json!({"a": 1, "b": 2}), "Expected {{\"a\": 1, \"b\", 2}}, but got {:?}", obj);
+            let obj_a = g_env.evaluate(&String::from(".obj.a")).unwrap();
+            assert_eq!(obj_a, json!(1), "Expected 1, but got {:?}", obj_a);
+            let obj_err = g_env.evaluate(&String::from(".obj.c"));
+            match obj_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "failed to get resource at .obj.c",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            let dne = g_env.evaluate(&String::from(".DNE_KEY"));
+            match dne {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "failed to get resource at .DNE_KEY",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            let env_var = g_env.evaluate(&String::from("$SHELL")).unwrap();
+            assert_eq!(env_var, json!("/bin/bash"), "Expected \"/bin/bash\", but got {:?}", env_var);
+            let dne_env_var = g_env.evaluate(&String::from("$DNE_VAR")).unwrap();
+            assert_eq!(dne_env_var, json!(""), "Expected \"\", but got {:?}", dne_env_var);
+        }
+        {
+            let env_var = g_env.evaluate(&String::from("$(lsb_release -r | sed 's/^.*\\s\\+//')")).unwrap();
+            assert_eq!(env_var, json!("22.04"), "Expected \"22.04\", but got {:?}", env_var);
+        }
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"a": 1});
+        assert!(matches!(
+            g_env.evaluate(&String::from(".dne")),
+            Err(RestClientError::SelectorNotFound(selector)) if selector == ".dne"
+        ));
+        assert!(matches!(
+            g_env.parse_selectors(&String::from("{{.dne}}")),
+            Err(RestClientError::SelectorNotFound(_))
+        ));
+        assert!(matches!(
+            g_env.evaluate(&String::from(".a +")),
+            Err(RestClientError::Jq(_))
+        ));
+        assert!(matches!(
+            g_env.define_var(&String::from("@a")),
+            Err(RestClientError::ParseError(_))
+        ));
+        assert!(matches!(
+            g_env.define_var(&String::from("@a = {")),
+            Err(RestClientError::Json(_))
+        ));
+        assert_eq!(
+            RestClientError::SelectorNotFound(String::from(".dne")).to_string(),
+            "failed to get resource at .dne"
+        );
+    }
+
+    #[test]
+    fn test_define_var() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"init": "test"});
+        fn verify_sub(var: &str, in_val: &str, sub_val: &str, g_env: &mut GlobalEnv) {
+            let test_in = format!("@{} = {}", var, in_val);
+            let test_out = format!("@{} = {}", var, sub_val);
+            println!("in: {}", test_in);
+            let out = g_env.define_var(&test_in).unwrap();
+            assert_eq!(out, test_out, "Expected \"{}\", but got \"{}\"", test_out, out);
+            let check = g_env.evaluate(&format!(".{}", var)).unwrap();
+            let expect: Value = serde_json::from_str(sub_val).unwrap();
+            assert_eq!(check, expect, "Expected {:?}, got {:?}", expect, check);
+        }
+        fn verify_non_sub(var: &str, val: &str, g_env: &mut GlobalEnv) {
+            let test_in = format!("@{} = {}", var, val);
+            println!("in: {}", test_in);
+            let out = g_env.define_var(&test_in).unwrap();
+            assert_eq!(out, test_in, "Expected \"{}\", but got \"{}\"", test_in, out);
+            let check = g_env.evaluate(&format!(".{}", var)).unwrap();
+            let expect: Value = serde_json::from_str(val).unwrap();
+            assert_eq!(check, expect, "Expected {:?}, got {:?}", expect, check);
+        }
+
+        {
+            verify_non_sub("baseUrl", "\"https://10.0.0.20:5443/api/v1\"", &mut g_env);
+        }
+        {
+            verify_non_sub("urls", "[\"https://10.0.0.20:5443/api/v1\", \"https://reqbin.com\"]", &mut g_env);
+            verify_non_sub("obj", "{\"a\": \"test\", \"b\": \"hello\"}", &mut g_env);
+            verify_non_sub("int1", "50", &mut g_env);
+        }
+        {
+            fn check_env_file() -> Result<(), RestClientError> {
+                let file_str = fs::read_to_string(ENV_FILE)?;
+                assert!(file_str.contains("baseUrl"), "File should contain baseUrl");
+                assert!(!file_str.contains("fail"), "File should not contain fail");
+                Ok(())
+            }
+            if let Err(e) = check_env_file() {
+                panic!("Got error: {}", e.to_string());
+            }
+        }
+        {
+            let fail_err = g_env.define_var(&String::from("@fail = some invalid json"));
+            match fail_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "expected value at line 1 column 1",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            let fail_err = g_env.define_var(&String::from("@fail \"line invalid\""));
+            match fail_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "cannot parse line: @fail \"line invalid\"",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            verify_sub("testUrl", "\"{{.baseUrl}}/test\"", "\"https://10.0.0.20:5443/api/v1/test\"", &mut g_env);
+            verify_sub("url1", "\"{{.urls[0]}}\"", "\"https://10.0.0.20:5443/api/v1\"", &mut g_env);
+            verify_sub("objA", "\"{{.obj.a}}\"", "\"test\"", &mut g_env);
+            verify_sub("objB", "\"{{.baseUrl}}/{{.obj.b}}\"", "\"https://10.0.0.20:5443/api/v1/hello\"", &mut g_env);
+        }
+        {
+            let test_fail_sub = r#"@fail = "{{.dne}}""#;
+            let fail_err = g_env.define_var(&String::from(test_fail_sub));
+            match fail_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "failed to get resource at .dne",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        {
+            let out = g_env.define_var(&String::from("@unset baseUrl")).unwrap();
+            assert_eq!(out, "@baseUrl (deleted)", "Expected \"@baseUrl (deleted)\", but got \"{}\"", out);
+            assert!(g_env.env.get("baseUrl").is_none(), "baseUrl should be removed from env");
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(!file_str.contains("baseUrl"), "File should not contain baseUrl");
+            let dne = g_env.evaluate(&String::from(".baseUrl"));
+            match dne {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "failed to get resource at .baseUrl",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+            // an empty value doesn't delete the variable
+            let fail_err = g_env.define_var(&String::from("@int1 ="));
+            assert!(fail_err.is_err(), "An empty value should fail");
+            assert!(g_env.env.get("int1").is_some(), "int1 should still be set");
+            let out = g_env.define_var(&String::from("@unset int1")).unwrap();
+            assert_eq!(out, "@int1 (deleted)", "Expected \"@int1 (deleted)\", but got \"{}\"", out);
+            assert!(g_env.env.get("int1").is_none(), "int1 should be removed from env");
+        }
+        {
+            let fail_err = g_env.define_var(&String::from("@unset baseUrl"));
+            match fail_err {
+                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "variable baseUrl is not set",
+                    "Got an incorrect error: \"{}\"",
+                    e.to_string()
+                ),
+            };
+        }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_append_var() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"id": 3, "name": "test"});
+        {
+            assert_eq!(g_env.define_var(&String::from("@ids += 1")).unwrap(), "@ids += 1");
+            assert_eq!(g_env.define_var(&String::from("@ids += 2")).unwrap(), "@ids += 2");
+            assert_eq!(g_env.define_var(&String::from("@ids+={{.id}}")).unwrap(), "@ids += 3");
+            assert_eq!(g_env.env["ids"], json!([1, 2, 3]));
+            g_env.define_var(&String::from(r#"@ids += {"name": "{{.name}}"}"#)).unwrap();
+            assert_eq!(g_env.env["ids"], json!([1, 2, 3, {"name": "test"}]));
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(file_str.contains("ids"), "File should contain ids");
+        }
+        {
+            g_env.define_var(&String::from(r#"@tmp headers = {"Accept": "*/*"}"#)).unwrap();
+            g_env.define_var(&String::from(r#"@headers += {"Accept": "application/json", "X-Id": 3}"#)).unwrap();
+            assert_eq!(g_env.env["headers"], json!({"Accept": "application/json", "X-Id": 3}));
+            assert!(g_env.tmp_vars.contains("headers"));
+        }
+        {
+            let err = g_env.define_var(&String::from("@name += 1")).unwrap_err();
+            assert_eq!(err.to_string(), "name is not an array or object");
+            let err = g_env.define_var(&String::from("@headers += [1]")).unwrap_err();
+            assert_eq!(err.to_string(), "only an object can be merged into headers");
+            assert!(g_env.define_var(&String::from("@ids += not json")).is_err());
+            assert_eq!(g_env.env["ids"], json!([1, 2, 3, {"name": "test"}]));
+        }
+        {
+            // names can have dashes, but not end with +
+            assert_eq!(g_env.define_var(&String::from("@my-list+= 1")).unwrap(), "@my-list += 1");
+            assert_eq!(g_env.env["my-list"], json!([1]));
+            assert!(g_env.env.get("my-list+").is_none());
+            assert!(g_env.define_var(&String::from("@my-list+ = 1")).is_err());
+        }
+        {
+            let err = g_env.define_var(&String::from("@tmp ids += 4")).unwrap_err();
+            assert_eq!(err.to_string(), "@tmp can't be used with +=: @tmp ids += 4");
+            let err = g_env.define_var(&String::from("@computed ids += 4")).unwrap_err();
+            assert_eq!(err.to_string(), "@computed can't be used with +=: @computed ids += 4");
+            g_env.define_var(&String::from("@computed next = {{.id + 1}}")).unwrap();
+            let err = g_env.define_var(&String::from("@next += 1")).unwrap_err();
+            assert_eq!(err.to_string(), "can't append to the computed variable next");
+            assert_eq!(g_env.env["ids"], json!([1, 2, 3, {"name": "test"}]));
+        }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_find_env_file() {
+        let root = env::temp_dir().join(format!("vim-rest-client-{}", std::process::id()));
+        let child = root.join("project").join("src").join("nested");
+        fs::create_dir_all(&child).unwrap();
+        {
+            assert_eq!(find_env_file(&child, ENV_FILE), None);
+        }
+        {
+            fs::write(root.join(ENV_FILE), "{}").unwrap();
+            assert_eq!(find_env_file(&child, ENV_FILE), Some(root.join(ENV_FILE)));
+        }
+        {
+            let project = root.join("project");
+            fs::write(project.join(ENV_FILE), "{}").unwrap();
+            assert_eq!(find_env_file(&child, ENV_FILE), Some(project.join(ENV_FILE)));
+            assert_eq!(find_env_file(&root, ENV_FILE), Some(root.join(ENV_FILE)));
+        }
+        {
+            let absolute = root.join(ENV_FILE).to_string_lossy().to_string();
+            assert_eq!(find_env_file(&child, &absolute), Some(root.join(ENV_FILE)));
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_last_request_ms() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.parse_input(&mut "###{\n@a = 1\n###}".as_bytes(), false);
+            assert_eq!(g_env.env.get(LAST_REQUEST_MS), None);
+        }
+        {
+            g_env.call_curl(&vec![String::from("--version")], RESPONSE_BODY_MAX_BYTES, false).unwrap();
+            let ms = g_env.env.get(LAST_REQUEST_MS);
+            assert!(ms.is_some_and(|ms| ms.is_u64()), "Expected last_request_ms, got {:?}", ms);
+            let env_file = fs::read_to_string(ENV_FILE).unwrap();
+            g_env.define_var(&String::from("@b = 2")).unwrap();
+            let env_file_after = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(!env_file.contains(LAST_REQUEST_MS) && !env_file_after.contains(LAST_REQUEST_MS));
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.ret = String::from("GET http://127.0.0.1:1\n");
+            fold_env.start_marker = String::from("###{");
+            fold_env.request_started = true;
+            fold_env.url = String::from("http://127.0.0.1:1");
+            fold_env.make_request(&mut g_env);
+            let result = fold_env.compile_return(&g_env).unwrap();
+            let executed_re = Regex::new(r"^###\{ executed \(ERROR, \d+ms\)\n").unwrap();
+            assert!(executed_re.is_match(&result.render()), "Got:\n{}", result.render());
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_validate_syntax() {
+        let g_env = GlobalEnv::new(None);
+        let validate = |input: &str| g_env.validate_syntax(&mut input.as_bytes());
+        {
+            let input = r#"text before
+###{ valid
+@a = 1
+@b = "{{.a}}"
+@c = [{{.a}}, {{.obj.{{.key}}}}]
+@unset d
+# @name resp
+GET {{.baseUrl}}/items
+Content-Type: application/json
+
+{"a": {{.a}}}
+###{ nested
+POST https://reqbin.com/echo/post/json
+###}
+########## valid RESULT
+old output, which isn't checked
+###}
+###{ while {{.i < 3}}
+@i = {{.i + 1}}
+###} endwhile
+###{ if {{.a}}
+###{ else
+###} endif"#;
+            let errors = validate(input);
+            assert!(errors.is_empty(), "Expected no errors, got {:?}", errors);
+        }
+        {
+            let errors = validate("###{\n@a = 1\n###{\n@b = 2\n###}");
+            assert_eq!(errors, vec!["line 1: fold is not closed"]);
+        }
+        {
+            let errors = validate("###{\n@a = 1\n###}\n###}");
+            assert_eq!(errors, vec!["line 4: ###} closes nothing"]);
+        }
+        {
+            let errors = validate("###{\n@a = not json\n@b = {{.a}} + 1\n###}");
+            assert_eq!(errors, vec![
+                "line 2: invalid value for @a: expected ident at line 1 column 2",
+                "line 3: invalid value for @b: trailing characters at line 1 column 3",
+            ]);
+        }
+        {
+            let errors = validate("###{\n@my-list+= 1\n@tmp ids += 1\n@computed ids += 1\n###}");
+            assert_eq!(errors, vec![
+                "line 3: @tmp can't be used with +=",
+                "line 4: @computed can't be used with +=",
+            ]);
+        }
+        {
+            let errors = validate("###{ while {{.i < 3}}\n@i = {{.i + 1}}\n###}");
+            assert_eq!(errors, vec!["line 3: while started on line 1 is closed by ###}"]);
+        }
+        {
+            let errors = validate("###{ while {{.i < 3}}\n@i = {{.i + 1}}");
+            assert_eq!(errors, vec!["line 1: while is not closed"]);
+        }
+        {
+            let errors = validate("###{\nGET\n###}\n###{\nGET reqbin.com/a b\n###}\n###{\n###{ else\n###}");
+            assert_eq!(errors, vec![
+                "line 2: expected a method and URL: GET",
+                "line 5: expected a method and URL: GET reqbin.com/a b",
+                "line 8: else is not in an if block",
+            ]);
+        }
+        {
+            // folds with titles starting like blocks, and URLs without a scheme
+            let input = r#"###{ iffy
+GET localhost:8080/x
+###}
+###{ format check
+GET reqbin.com/echo
+###}
+###{ for each user
+POST :8080/users
+###}"#;
+            let errors = validate(input);
+            assert!(errors.is_empty(), "Expected no errors, got {:?}", errors);
+        }
+    }
+
+    #[test]
+    fn test_export_env() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "name": "test",
+            "port": 8080,
+            "ids": [1, 2],
+            "server": {"host": "example.com", "tls": true},
+            "__internal": 1
+        });
+        {
+            let res = g_env.export_env(EnvExportFormat::Dotenv).unwrap();
+            let expected = "ids=[1,2]\nname=test\nport=8080\nserver={\"host\":\"example.com\",\"tls\":true}\n";
+            assert_eq!(res, expected, "Expected:\n{}\nGot:\n{}", expected, res);
+        }
+        {
+            let res = g_env.export_env(EnvExportFormat::Toml).unwrap();
+            let expected = "ids = [1, 2]\nname = \"test\"\nport = 8080\n\n[server]\nhost = \"example.com\"\ntls = true\n";
+            assert_eq!(res, expected, "Expected:\n{}\nGot:\n{}", expected, res);
+        }
+        {
+            let res = g_env.export_env(EnvExportFormat::Yaml).unwrap();
+            let expected = "ids:\n- 1\n- 2\nname: test\nport: 8080\nserver:\n  host: example.com\n  tls: true\n";
+            assert_eq!(res, expected, "Expected:\n{}\nGot:\n{}", expected, res);
+        }
+        {
+            let res = g_env.export_env(EnvExportFormat::Json).unwrap();
+            let val = serde_json::from_str::<Value>(&res).unwrap();
+            assert_eq!(val, json!({
+                "name": "test",
+                "port": 8080,
+                "ids": [1, 2],
+                "server": {"host": "example.com", "tls": true}
+            }));
+        }
+        {
+            // TOML has no null
+            g_env.env = json!({"missing": null});
+            assert!(matches!(g_env.export_env(EnvExportFormat::Toml), Err(RestClientError::ParseError(_))));
+        }
+        assert!(EnvExportFormat::get_match("YAML").is_some());
+        assert!(EnvExportFormat::get_match("xml").is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "baseUrl": "https://example.com",
+            "port": 8080,
+            "verbose": true,
+            "ids": [1, 2],
+            "__internal": 1
+        });
+        {
+            let schema = json!({
+                "baseUrl": {"type": "string", "required": true},
+                "port": "number",
+                "verbose": "boolean",
+                "ids": "array",
+                "token": "string"
+            });
+            let errors = g_env.validate_schema(&schema);
+            assert!(errors.is_empty(), "Expected no errors, got {:?}", errors);
+        }
+        {
+            let schema = json!({
+                "baseUrl": "number",
+                "token": {"type": "string", "required": true},
+                "port": "integer",
+                "ids": 5
+            });
+            let errors = g_env.validate_schema(&schema);
+            assert_eq!(errors, vec![
+                "baseUrl should be number but is string",
+                "invalid schema for ids",
+                "unknown type \"integer\" for port",
+                "missing required variable token",
+            ]);
+        }
+        {
+            let schema = json!({
+                "strict": true,
+                "baseUrl": "string",
+                "port": "number"
+            });
+            let errors = g_env.validate_schema(&schema);
+            assert_eq!(errors, vec!["unexpected variable ids", "unexpected variable verbose"]);
+        }
+        {
+            let errors = g_env.validate_schema(&json!(["not", "an", "object"]));
+            assert_eq!(errors, vec!["schema must be an object"]);
+        }
+        {
+            fs::write(ENV_SCHEMA_FILE, r#"{"baseUrl": {"type": "string", "required": true}, "port": "string"}"#).unwrap();
+            let errors = g_env.validate();
+            fs::remove_file(ENV_SCHEMA_FILE).unwrap();
+            assert_eq!(errors, vec!["port should be string but is number"]);
+            let errors = g_env.validate();
+            assert!(errors[0].starts_with("could not read schema"), "Got {:?}", errors);
+        }
+    }
+
+    #[test]
+    fn test_parse_dotenv() {
+        let contents = "# comment\n\nNAME=jane\nexport PORT=8080\nQUOTED=\"a b\"\nSINGLE='c'\nEMPTY=\n";
+        assert_eq!(parse_dotenv(contents), json!({
+            "NAME": "jane",
+            "PORT": 8080,
+            "QUOTED": "a b",
+            "SINGLE": "c",
+            "EMPTY": ""
+        }));
+    }
+
+    #[test]
+    fn test_import_dotenv() {
+        let dotenv_file = ".env.import";
+        let mut g_env = GlobalEnv::new(None);
+        g_env.define_var(&String::from(r#"@shared = "base""#)).unwrap();
+        let env_before = fs::read_to_string(ENV_FILE).unwrap();
+        env::set_var("VIM_REST_CLIENT_TEST_HOME", "/home/jane");
+        env::remove_var("VIM_REST_CLIENT_TEST_UNSET");
+        {
+            fs::write(dotenv_file, r#"# comment
+
+shared=personal
+PORT=8080
+QUOTED="a b"
+SINGLE='$VIM_REST_CLIENT_TEST_HOME'
+DIR=$VIM_REST_CLIENT_TEST_HOME/projects
+BRACED="${VIM_REST_CLIENT_TEST_HOME}_x"
+UNSET=$VIM_REST_CLIENT_TEST_UNSET
+EQUALS=a=b
+"#).unwrap();
+            g_env.import_dotenv(Path::new(dotenv_file)).unwrap();
+            assert_eq!(g_env.env["shared"], json!("personal"));
+            assert_eq!(g_env.env["PORT"], json!("8080"));
+            assert_eq!(g_env.env["QUOTED"], json!("a b"));
+            assert_eq!(g_env.env["SINGLE"], json!("$VIM_REST_CLIENT_TEST_HOME"));
+            assert_eq!(g_env.env["DIR"], json!("/home/jane/projects"));
+            assert_eq!(g_env.env["BRACED"], json!("/home/jane_x"));
+            assert_eq!(g_env.env["UNSET"], json!(""));
+            assert_eq!(g_env.env["EQUALS"], json!("a=b"));
+            assert_eq!(fs::read_to_string(ENV_FILE).unwrap(), env_before);
+        }
+        {
+            g_env.env = json!({});
+            let results = g_env.parse_input(&mut "###{\n# @import-dotenv .env.import\n@port = {{.PORT}}\n###}".as_bytes(), false);
+            let res = strip_durations(&render_results(&results));
+            assert!(res.contains("imported .env.import\n@port = 8080"), "Got:\n{}", res);
+        }
+        {
+            fs::remove_file(dotenv_file).unwrap();
+            assert!(g_env.import_dotenv(Path::new(dotenv_file)).is_err());
+        }
+        env::remove_var("VIM_REST_CLIENT_TEST_HOME");
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_render_color() {
+        let mut g_env = GlobalEnv::new(None);
+        let results = g_env.parse_input(&mut "###{ ok\n@ok = 1\n###}\n###{ bad\n@bad = {{.dne}}\n###}\n###{ skipped\n# @skip\n###}".as_bytes(), false);
+        {
+            let res = strip_durations(&g_env.render(&results));
+            assert!(!res.contains('\x1b'), "Expected no color codes, got:\n{}", res);
+            assert!(res.contains("###{ bad executed (ERROR)"), "Got:\n{}", res);
+        }
+        {
+            g_env.color = true;
+            let res = strip_durations(&g_env.render(&results));
+            assert!(res.contains("###{ ok executed (\x1b[32mSUCCESS\x1b[0m)"), "Got:\n{}", res);
+            assert!(res.contains("###{ bad executed (\x1b[31mERROR\x1b[0m)"), "Got:\n{}", res);
+            assert!(res.contains("###{ skipped executed (\x1b[33mSKIPPED\x1b[0m)"), "Got:\n{}", res);
+        }
+        assert_eq!(
+            color_statuses("###{ executed (SUCCESS, 12ms)"),
+            "###{ executed (\x1b[32mSUCCESS\x1b[0m, 12ms)"
+        );
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_output_format() {
+        let mut g_env = GlobalEnv::new(None);
+        let input = "text before\n###{ ok\n@ok = 1\n###}\n###{ bad\n@bad = {{.dne}}\n###}";
+        let results = g_env.parse_input(&mut input.as_bytes(), false);
+        {
+            g_env.output_format = OutputFormat::VimFold;
+            assert_eq!(g_env.render(&results), render_results(&results));
+        }
+        {
+            g_env.output_format = OutputFormat::Markdown;
+            g_env.color = true;
+            let expected = r#"text before
+
+## ok (SUCCESS)
+
+```http
+@ok = 1
+```
+
+```json
+@ok = 1
+```
+
+## bad (ERROR)
+
+```http
+@bad = {{.dne}}
+```
+
+```json
+@bad: line 1: failed to get resource at .dne
+```"#;
+            let res = g_env.render(&results);
+            assert_eq!(res, expected, "Expected:\n{}\nGot:\n{}", expected, res);
+            g_env.color = false;
+        }
+        {
+            g_env.output_format = OutputFormat::Json;
+            let res: Value = serde_json::from_str(&g_env.render(&results)).unwrap();
+            let folds = res.as_array().unwrap();
+            assert_eq!(folds.len(), 2);
+            assert_eq!(folds[0]["title"], json!("ok"));
+            assert_eq!(folds[0]["input"], json!("@ok = 1\n"));
+            assert_eq!(folds[0]["output"], json!("@ok = 1\n"));
+            assert_eq!(folds[0]["error"], json!(false));
+            assert!(folds[0]["duration_ms"].is_u64());
+            assert_eq!(folds[1]["title"], json!("bad"));
+            assert_eq!(folds[1]["error"], json!(true));
+        }
+        assert!(OutputFormat::get_match("Markdown") == Some(OutputFormat::Markdown));
+        assert!(OutputFormat::get_match("html").is_none());
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_diff_env() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"same": 1, "changed": "old", "__internal": 1});
+        g_env.initial_env = g_env.env.clone();
+        assert!(g_env.diff_env().is_empty());
+        g_env.define_var(&String::from(r#"@changed = "new""#)).unwrap();
+        g_env.define_var(&String::from("@added = [1, 2]")).unwrap();
+        g_env.define_var(&String::from("@same = 1")).unwrap();
+        g_env.define_var(&String::from("@__internal = 2")).unwrap();
+        g_env.define_var(&String::from("@tmp scratch = 3")).unwrap();
+        g_env.set_var(&String::from(LAST_REQUEST_MS), &json!(12), false).unwrap();
+        g_env.set_var(&String::from(RATE_LIMIT_REMAINING), &json!(5), false).unwrap();
+        let diff = g_env.diff_env();
+        assert_eq!(diff.len(), 2, "Got {:?}", diff);
+        assert_eq!(diff["changed"], (Some(json!("old")), json!("new")));
+        assert_eq!(diff["added"], (None, json!([1, 2])));
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_reset_session_vars() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        for line in [r#"@resp_login = {"ok": true}"#, r#"@resp_token = "abc""#, r#"@respond = 1"#, r#"@baseUrl = "https://reqbin.com""#] {
+            g_env.define_var(&String::from(line)).unwrap();
+        }
+        {
+            g_env.reset_session_vars(&[]).unwrap();
+            assert_eq!(g_env.list_vars().len(), 4);
+        }
+        {
+            g_env.reset_session_vars(&["resp_", "unused_"]).unwrap();
+            let expected = json!({"respond": 1, "baseUrl": "https://reqbin.com"});
+            assert_eq!(g_env.env, expected);
+            let env_file: Value = serde_json::from_str(&fs::read_to_string(ENV_FILE).unwrap()).unwrap();
+            assert_eq!(env_file, expected);
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_merge_env() {
+        let overlay_file = ".env.overlay.json";
+        let mut g_env = GlobalEnv::new(None);
+        g_env.define_var(&String::from(r#"@shared = "base""#)).unwrap();
+        let env_before = fs::read_to_string(ENV_FILE).unwrap();
+        {
+            fs::write(overlay_file, r#"{"shared": "personal", "token": "abc"}"#).unwrap();
+            g_env.merge_env(Path::new(overlay_file)).unwrap();
+            assert_eq!(g_env.evaluate(&String::from(".shared")).unwrap(), json!("personal"));
+            assert_eq!(g_env.evaluate(&String::from(".token")).unwrap(), json!("abc"));
+            assert_eq!(fs::read_to_string(ENV_FILE).unwrap(), env_before);
+        }
+        {
+            g_env.define_var(&String::from("@other = 1")).unwrap();
+            let env_file = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(!env_file.contains("token"), "Overlay was written to env file: {}", env_file);
+            assert!(env_file.contains(r#""shared": "base""#), "Env file was changed: {}", env_file);
+        }
+        {
+            fs::write(overlay_file, r#"["not", "an", "object"]"#).unwrap();
+            assert!(g_env.merge_env(Path::new(overlay_file)).is_err());
+        }
+        {
+            fs::remove_file(overlay_file).unwrap();
+            assert!(g_env.merge_env(Path::new(overlay_file)).is_err());
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_list_vars() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        g_env.define_var(&String::from("@zeta = 1")).unwrap();
+        g_env.define_var(&String::from(r#"@alpha = {"a": [1, 2]}"#)).unwrap();
+        g_env.define_var(&String::from(r#"@tmp mid = "m""#)).unwrap();
+        g_env.env["__internal"] = json!(true);
+        assert_eq!(g_env.list_vars(), vec![
+            (String::from("alpha"), json!({"a": [1, 2]})),
+            (String::from("mid"), json!("m")),
+            (String::from("zeta"), json!(1)),
+        ]);
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_define_tmp_var() {
+        clear_env_file();
+        let mut g_env = GlobalEnv::new(None);
+        {
+            let out = g_env.define_var(&String::from(r#"@tmp token = "abc""#)).unwrap();
+            let expected = r#"@token = "abc" (tmp)"#;
+            assert_eq!(out, expected, "Expected \"{}\", but got \"{}\"", expected, out);
+            assert!(fs::metadata(ENV_FILE).is_err(), "{} should not be written", ENV_FILE);
+            let check = g_env.evaluate(&String::from(".token")).unwrap();
+            assert_eq!(check, json!("abc"), "Expected \"abc\", got {:?}", check);
+        }
+        {
+            let out = g_env.define_var(&String::from(r#"@auth = "Bearer {{.token}}""#)).unwrap();
+            let expected = r#"@auth = "Bearer abc""#;
+            assert_eq!(out, expected, "Expected \"{}\", but got \"{}\"", expected, out);
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(file_str.contains("auth"), "File should contain auth");
+            assert!(!file_str.contains("token"), "File should not contain token");
+        }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_computed_var() {
+        clear_env_file();
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        g_env.define_var(&String::from(r#"@baseUrl = "https://a.com""#)).unwrap();
+        g_env.define_var(&String::from(r#"@path = "users""#)).unwrap();
+        {
+            let out = g_env.define_var(&String::from(r#"@computed fullUrl = "{{.baseUrl}}/{{.path}}""#)).unwrap();
+            let expected = r#"@fullUrl = "{{.baseUrl}}/{{.path}}" (computed)"#;
+            assert_eq!(out, expected, "Expected \"{}\", but got \"{}\"", expected, out);
+            let res = g_env.parse_selectors(&String::from("{{.fullUrl}}")).unwrap();
+            assert_eq!(res, "https://a.com/users");
+        }
+        {
+            g_env.define_var(&String::from(r#"@baseUrl = "https://b.com""#)).unwrap();
+            let res = g_env.parse_selectors(&String::from("{{.fullUrl}}")).unwrap();
+            assert_eq!(res, "https://b.com/users");
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(file_str.contains("__computed__fullUrl"), "File should contain the expression: {}", file_str);
+        }
+        {
+            // a computed variable can't use itself
+            g_env.define_var(&String::from(r#"@computed loop = "{{.loop}}""#)).unwrap();
+            assert!(g_env.parse_selectors(&String::from("{{.loop}}")).is_err());
+        }
+        {
+            g_env.define_var(&String::from(r#"@fullUrl = "static""#)).unwrap();
+            let res = g_env.parse_selectors(&String::from("{{.fullUrl}}")).unwrap();
+            assert_eq!(res, "static");
+            assert!(g_env.env.get("__computed__fullUrl").is_none());
+            g_env.define_var(&String::from("@unset loop")).unwrap();
+            assert!(g_env.env.get("__computed__loop").is_none());
+        }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_define_secret_var() {
+        clear_env_file();
+        env::set_var("VRC_TEST_SECRET", "s3cr3t");
+        env::set_var("VRC_TEST_PLAIN", "visible");
+        let mut g_env = GlobalEnv::new(None);
+        {
+            let out = g_env.define_var(&String::from(r#"@token = "{{$$VRC_TEST_SECRET}}""#)).unwrap();
+            let expected = "@token = [SECRET]";
+            assert_eq!(out, expected, "Expected \"{}\", but got \"{}\"", expected, out);
+            let check = g_env.evaluate(&String::from(".token")).unwrap();
+            assert_eq!(check, json!("s3cr3t"), "Expected \"s3cr3t\", got {:?}", check);
+        }
+        {
+            let out = g_env.define_var(&String::from(r#"@auth = "Bearer {{.token}}""#)).unwrap();
+            assert_eq!(out, "@auth = [SECRET]");
+            g_env.define_var(&String::from(r#"@user = "{{$VRC_TEST_PLAIN}}""#)).unwrap();
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(!file_str.contains("s3cr3t"), "File should not contain the secret: {}", file_str);
+            assert!(file_str.contains("visible"), "File should contain user: {}", file_str);
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.output = String::from("-H 'Authorization: Bearer s3cr3t'");
+            fold_env.redact(&g_env);
+            let expected = "-H 'Authorization: Bearer [SECRET]'";
+            assert_eq!(fold_env.output, expected);
+        }
+        {
+            // a short secret doesn't make unrelated values secret
+            env::set_var("VRC_TEST_SHORT", "1");
+            let out = g_env.define_var(&String::from(r#"@tok = "{{$$VRC_TEST_SHORT}}""#)).unwrap();
+            assert_eq!(out, "@tok = [SECRET]");
+            let out = g_env.define_var(&String::from("@count = 10")).unwrap();
+            assert_eq!(out, "@count = 10");
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(file_str.contains("\"count\": 10"), "File should contain count: {}", file_str);
+            let mut fold_env = FoldEnv::new();
+            fold_env.output = String::from("Failed to connect to 127.0.0.1 port 10");
+            fold_env.redact(&g_env);
+            assert_eq!(fold_env.output, "Failed to connect to 127.0.0.1 port 10");
+            env::remove_var("VRC_TEST_SHORT");
+        }
+        env::remove_var("VRC_TEST_SECRET");
+        env::remove_var("VRC_TEST_PLAIN");
+        clear_env_file();
+    }
+
+//    #[test]
+//    fn test_make_request() {
+//        let mut g_env = GlobalEnv::new();
+//        g_env.env = json!({
+//            "baseUrl": "https://reqbin.com",
+//            "getXml": "echo/get/xml",
+//            "ct": "Content-Type",
+//            "json": "application/json"
+//        });
+//        {
+//            let req = Request {
+//                method: Method::Get,
+//                url: String::from("https://reqbin.com/echo/get/xml"),
+//                headers: vec![],
+//                multipart_forms: vec![],
+//                data: None,
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap().get_return();
+//            let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
+//            let resp = resp.lines().last().unwrap();
+//            assert_eq!(resp, expected, "Expected {}, got {}", expected, resp);
+//            assert!(val.is_string(), "Response is XML so value should be string, got {:?}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Get,
+//                url: String::from("{{.baseUrl}}/{{.getXml}}"),
+//                headers: vec![],
+//                multipart_forms: vec![],
+//                data: None,
+//            };
+//            let (resp, _) = req.make_request(&mut g_env, false, false).unwrap().get_return();
+//            let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
+//            let resp = resp.lines().last().unwrap();
+//            assert_eq!(resp, expected, "Expected {}, got {}", expected, resp);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Post,
+//                url: String::from("https://reqbin.com/echo/post/json"),
+//                headers: vec![String::from("{{.ct}}: {{.json}}")],
+//                multipart_forms: vec![],
+//                data: Some(String::from("{\"test\": \"value\"}")),
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap().get_return();
+//            let expected = r#"{
+//  "success": "true"
+//}"#;
+//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
+//            assert_eq!(val["success"], json!("true"), "Got incorrect value: {:?}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Post,
+//                url: String::from("https://reqbin.com/echo/post/json"),
+//                headers: vec![String::from("{{.dne}}: application/json")],
+//                multipart_forms: vec![],
+//                data: Some(String::from("{\"test\": \"value\"}")),
+//            };
+//            let resp = req.make_request(&mut g_env, false, false);
+//            match resp {
+//                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+//                Err(e) => assert_eq!(
+//                    e.to_string(),
+//                    "failed to get resource at .dne",
+//                    "Got an incorrect error: \"{}\"",
+//                    e.to_string()
+//                ),
+//            };
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Get,
+//                url: String::from("http://aunchoeu"),
+//                headers: vec![],
+//                multipart_forms: vec![],
+//                data: None,
+//            };
+//            let resp = req.make_request(&mut g_env, false, false);
+//            match resp {
+//                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
+//                Err(e) => assert_eq!(
+//                    e.to_string(),
+//                    "curl: (6) Couldn't resolve host 'aunchoeu'\n",
+//                    "Got an incorrect error: \"{}\"",
+//                    e.to_string()
+//                ),
+//            };
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Post,
+//                url: String::from("https://reqbin.com/echo/post/json"),
+//                headers: vec![String::from("{{.ct}}: {{.json}}")],
+//                multipart_forms: vec![],
+//                data: Some(String::from("{\"test\": \"value\"}")),
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, true, false).unwrap().get_return();
+//            let expected = "curl -k --include https://reqbin.com/echo/post/json -X POST -H Content-Type: application/json -d {\"test\": \"value\"}";
+//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
+//            assert!(val.as_str().unwrap().is_empty(), "Expected val to be empty, got {}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Post,
+//                url: String::from("https://reqbin.com/echo/post/json"),
+//                headers: vec![String::from("{{.ct}}: {{.json}}")],
+//                multipart_forms: vec![],
+//                data: Some(String::from("{\"test\": \"value\"}")),
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, true, true).unwrap().get_return();
+//            let expected = "curl -k -v https://reqbin.com/echo/post/json -X POST -H Content-Type: application/json -d {\"test\": \"value\"}";
+//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
+//            assert!(val.as_str().unwrap().is_empty(), "Expected val to be empty, got {}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Post,
+//                url: String::from("https://reqbin.com/echo/post/json"),
+//                headers: vec![String::from("{{.ct}}: {{.json}}")],
+//                multipart_forms: vec![],
+//                data: Some(String::from("{\"test\": \"value\"}")),
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, false, true).unwrap().get_return();
+//            let expected1 = "> POST /echo/post/json";
+//            let expected2 = "< Content-Type: application/json";
+//            let expected3 = Regex::new(r"(?m)^<.* 200 OK$").unwrap();
+//            assert!(resp.contains(expected1), "Expected {} in response, but response is {}", expected1, resp);
+//            assert!(resp.contains(expected2), "Expected {} in response, but response is {}", expected2, resp);
+//            assert!(expected3.is_match(&resp), "Expected {} in response, but response is {}", "< HTTP/_ 200 OK", resp);
+//            assert_eq!(val["success"], json!("true"), "Got incorrect value: {:?}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Get,
+//                url: String::from("https://reqbin.com/echo/get/xml"),
+//                headers: vec![],
+//                multipart_forms: vec![],
+//                data: None,
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap().get_return();
+//            let expected = "<Response>\n  <ResponseCode>0</ResponseCode>\n  <ResponseMessage>Success</ResponseMessage>\n</Response>";
+//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
+//            assert!(val.is_string(), "Response is XML so value should be string, got {:?}", val);
+//        }
+//        {
+//            let req = Request {
+//                method: Method::Head,
+//                url: String::from("https://reqbin.com/echo/get/json"),
+//                headers: vec![],
+//                multipart_forms: vec![],
+//                data: None,
+//            };
+//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap().get_return();
+//            let expected = Regex::new(r"(?m)^HTTP/\S+ 200").unwrap();
+//            assert!(expected.is_match(&resp), "Expected 200 status in response, but response is {}", resp);
+//            assert_eq!(val, json!(""), "Expected no body for HEAD, got {:?}", val);
+//        }
+//
+//        clear_env_file();
+//    }
+
+    #[test]
+    fn test_response() {
+        {
+            let resp = Response::new(String::from("HTTP/1.1 100 Continue\n\nHTTP/1.1 200 OK\nContent-Type: application/json; charset=utf-8\n\n{\"test\": \"val\"}"), String::new(), false);
+            match resp {
+                Response::Json(h, v) => {
+                    println!("SUCCESS!\n\nHeaders:\n{h}\n\nValue:\n{:?}", v);
+                    assert!(true);
+                },
+                Response::NonJson(h, v) => {
+                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{v}");
+                    assert!(false, "Response was NonJson");
+                },
+                Response::NoSplit(v) => {
+                    println!("FAILED\n\nValue:\n{v}");
+                    assert!(false, "Response was NoSplit");
+                },
+                Response::Saved(h, p) => {
+                    println!("FAILED\n\nHeaders:\n{h}\n\nPath:\n{p}");
+                    assert!(false, "Response was Saved");
+                },
+            }
+        }
+        {
+            let resp = Response::new(String::from("Just some response w/ no headers"), String::new(), false);
+            match resp {
+                Response::Json(h, v) => {
+                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{:?}", v);
+                    assert!(false, "Response was Json");
+                },
+                Response::NonJson(h, v) => {
+                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{v}");
+                    assert!(false, "Response was NonJson");
+                },
+                Response::NoSplit(v) => {
+                    println!("SUCCESS!\n\nValue:\n{v}");
+                    assert!(true);
+                },
+                Response::Saved(h, p) => {
+                    println!("FAILED\n\nHeaders:\n{h}\n\nPath:\n{p}");
+                    assert!(false, "Response was Saved");
+                },
             }
-            let selector_val = selector_val.unwrap();
-            selector_val.as_str()
-                .map_or_else(
-                    || selector_val.to_string(),
-                    |s| String::from(s)
-                )
-        });
-        if let Some(err) = replace_err {
-            return Err(io_error(&err))?;
         }
-        let subbed = value.to_string();
-        if re.is_match(&subbed) {
-            return self.parse_selectors(&subbed);
+        {
+            let (ret, val) = Response::body_only(String::from("{\"test\": \"val\"}")).get_return();
+            assert_eq!(ret, "{\n  \"test\": \"val\"\n}");
+            assert_eq!(val, json!({"test": "val"}));
+            let (ret, val) = Response::body_only(String::from("plain text")).get_return();
+            assert_eq!(ret, "plain text");
+            assert_eq!(val, json!("plain text"));
+        }
+        {
+            let resp = Response::Saved(String::from("HTTP/1.1 200 OK"), String::from("out.bin"));
+            let (ret, val) = resp.get_return();
+            assert_eq!(ret, "HTTP/1.1 200 OK\n\nResponse saved to out.bin");
+            assert_eq!(val, json!("out.bin"));
         }
-        Ok(subbed)
     }
 
-    /// Given a particular string representing a variable or jq selection, evaluate
-    /// the value in the environment json. If there's an error, return the error
-    /// with the error cause. Due to jq returning null for out-of-bounds or no key,
-    /// this function will have a generic null error message.
-    /// If the selector string represents an environment variable (like $VAR) then
-    /// retrieve the value from the appropriate environment and return a json string.
-    fn evaluate(&mut self, selector: &String) -> Result<Value, Box<dyn Error>> {
-        if let Some(val) = self.get_env_var(selector)? {
-            return Ok(val);
+    #[test]
+    fn test_split_raw_response() {
+        let body: &[u8] = b"\x89PNG\r\n\x1a\n\xff\xfe\r\n\r\nend";
+        {
+            let raw = [&b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\r\n"[..], body].concat();
+            let (headers, rest) = split_raw_response(&raw);
+            assert_eq!(headers, "HTTP/1.1 200 OK\nContent-Type: image/png");
+            assert_eq!(rest, body);
+        }
+        {
+            let raw = [
+                &b"HTTP/1.1 302 Found\r\nLocation: /img\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"[..],
+                body,
+            ].concat();
+            let (headers, rest) = split_raw_response(&raw);
+            assert_eq!(headers, "HTTP/1.1 302 Found\nLocation: /img\n\nHTTP/1.1 200 OK");
+            assert_eq!(rest, body);
         }
-        let res_str = jq_rs::run(&selector, &self.env.to_string())?;
-        let res_val = serde_json::from_str(&res_str)?;
-        match res_val {
-            Value::Null => Err(io_error(&format!("failed to get resource at {}", selector)))?,
-            _ => Ok(res_val)
+        {
+            let (headers, rest) = split_raw_response(body);
+            assert_eq!(headers, "");
+            assert_eq!(rest, body);
         }
     }
 
-    /// Given a selector, checks if it has the pattern for an environment variable,
-    /// like $VAR. If not, return None, otherwise return the value of the env var if
-    /// it exists, or an empty string. If sshTo is defined, then retrieve the
-    /// environment variable on the desired machine.
-    fn get_env_var
-    (
-        &mut self,
-        selector: &String,
-    ) -> Result<Option<Value>, Box<dyn Error>> {
-        let env_var_re = Regex::new(r"^\$(.*)$").unwrap();
-        if let Some(caps) = env_var_re.captures(selector) {
-            if selector.contains('(') {
-                return self.command_substitution(selector);
-            }
-            let var = caps.get(1).unwrap().as_str();
-            if let Some(_) = self.env.get(SSH_TO) {
-                let rt = Runtime::new()?;
-                let val = rt.block_on(self.ssh_get_env_var(&String::from(selector)))?;
-                return Ok(Some(val));
-            }
-            Ok(env::var(var)
-                .map_or_else(|_| Some(json!("")), |val| Some(json!(val))))
-        } else {
-            Ok(None)
+    #[test]
+    fn test_truncate_response() {
+        {
+            let ret = String::from("HTTP/1.1 200 OK\n\nsmall");
+            let res = truncate_response(ret.clone(), RESPONSE_BODY_MAX_BYTES);
+            assert_eq!(res, ret, "Expected {}, but got {}", ret, res);
+        }
+        {
+            let ret = format!("HTTP/1.1 200 OK\n\n{}", "a".repeat(RESPONSE_BODY_MAX_BYTES));
+            let res = truncate_response(ret, RESPONSE_BODY_MAX_BYTES);
+            let sentinel = format!("\n[response truncated at {} bytes]", RESPONSE_BODY_MAX_BYTES);
+            assert!(res.ends_with(&sentinel), "Expected response to end with {}", sentinel);
+            assert_eq!(res.len(), RESPONSE_BODY_MAX_BYTES + sentinel.len());
+        }
+        {
+            // never splits a multi-byte char
+            let res = truncate_response(String::from("aé"), 2);
+            assert_eq!(res, "a\n[response truncated at 2 bytes]", "Got {}", res);
         }
     }
 
-    /// Substitutes with the output of a command. Allows for executing things to
-    /// get the string, like $(lsb_release -a).
-    fn command_substitution
-    (
-        &mut self,
-        selector: &String,
-    ) -> Result<Option<Value>, Box<dyn Error>> {
-        if let Some(_) = self.env.get(SSH_TO) {
-            let rt = Runtime::new()?;
-            return rt.block_on(self.ssh_command_substitution(selector));
+    #[test]
+    fn test_pretty_xml() {
+        {
+            let xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
+            let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<Response>\n  <ResponseCode>0</ResponseCode>\n  <ResponseMessage>Success</ResponseMessage>\n</Response>";
+            let res = pretty_xml(xml).unwrap();
+            assert_eq!(res, expected, "Expected:\n{}\nGot:\n{}", expected, res);
         }
-        let echo = Command::new("bash")
-            .arg("-c")
-            .arg(format!("echo \"{}\"", selector))
-            .output()?;
-        let e = String::from_utf8_lossy(&echo.stderr).to_string();
-        if !echo.status.success() {
-            return Err(io_error(&e))?;
+        {
+            let res = pretty_xml("<Response><Unclosed></Response>");
+            assert!(res.is_none(), "Expected None for invalid XML, got {:?}", res);
+        }
+        {
+            let resp = Response::new(String::from("HTTP/1.1 200 OK\nContent-Type: application/xml\n\n<a><b>1</b></a>"), String::new(), false);
+            let (display, val) = resp.get_return();
+            let expected = "HTTP/1.1 200 OK\nContent-Type: application/xml\n\n<a>\n  <b>1</b>\n</a>";
+            assert_eq!(display, expected, "Expected:\n{}\nGot:\n{}", expected, display);
+            assert_eq!(val, json!("<a><b>1</b></a>"), "Expected raw body, got {:?}", val);
+        }
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        {
+            let (display, val) = parse_yaml("items: [1, 2]\nname:   test").unwrap();
+            let expected = "items:\n- 1\n- 2\nname: test";
+            assert_eq!(display, expected, "Expected:\n{}\nGot:\n{}", expected, display);
+            assert_eq!(val, json!({"name": "test", "items": [1, 2]}), "Got {:?}", val);
+        }
+        {
+            let res = parse_yaml("key: [unclosed");
+            assert!(res.is_none(), "Expected None for invalid YAML, got {:?}", res);
+        }
+        {
+            let resp = Response::new(String::from("HTTP/1.1 200 OK\nContent-Type: application/yaml\n\nkind:   Pod\nmetadata: {name: web}"), String::new(), false);
+            let (display, val) = resp.get_return();
+            let expected = "HTTP/1.1 200 OK\nContent-Type: application/yaml\n\nkind: Pod\nmetadata:\n  name: web";
+            assert_eq!(display, expected, "Expected:\n{}\nGot:\n{}", expected, display);
+            assert_eq!(val, json!({"kind": "Pod", "metadata": {"name": "web"}}), "Got {:?}", val);
+        }
+        {
+            // without a YAML content type the body is kept as is
+            let resp = Response::new(String::from("HTTP/1.1 200 OK\nContent-Type: text/plain\n\nkind:   Pod"), String::new(), false);
+            let (display, val) = resp.get_return();
+            let expected = "HTTP/1.1 200 OK\nContent-Type: text/plain\n\nkind:   Pod";
+            assert_eq!(display, expected, "Expected:\n{}\nGot:\n{}", expected, display);
+            assert_eq!(val, json!("kind:   Pod"), "Got {:?}", val);
+        }
+    }
+
+    #[test]
+    fn test_method() {
+        let cases = [
+            ("get", "GET"),
+            ("post", "POST"),
+            ("delete", "DELETE"),
+            ("put", "PUT"),
+            ("patch", "PATCH"),
+            ("PATCH", "PATCH"),
+            ("head", "HEAD"),
+            ("Options", "OPTIONS"),
+            ("purge", "PURGE"),
+        ];
+        for (input, expected) in cases {
+            let method = Method::get_match(input).to_string();
+            assert_eq!(method, expected, "Expected {}, but got {}", expected, method);
+        }
+        assert!(matches!(Method::get_match("patch"), Method::Patch));
+        assert!(matches!(Method::get_match("head"), Method::Head));
+        assert!(matches!(Method::get_match("options"), Method::Options));
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut g_env = GlobalEnv::new(None);
+        fn failing_fold(retries: u64) -> FoldEnv {
+            let mut fold_env = FoldEnv::new();
+            fold_env.request_started = true;
+            fold_env.url = String::from("http://127.0.0.1:1");
+            fold_env.retries = retries;
+            fold_env
+        }
+        {
+            let mut fold_env = failing_fold(0);
+            fold_env.make_request(&mut g_env);
+            assert!(fold_env.error, "Expected error, output was {}", fold_env.output);
+            assert!(!fold_env.output.contains("attempt"), "Expected no retries, output was {}", fold_env.output);
+        }
+        {
+            let mut fold_env = failing_fold(2);
+            fold_env.make_request(&mut g_env);
+            assert!(fold_env.error, "Expected error, output was {}", fold_env.output);
+            assert!(fold_env.output.contains("attempt 1 failed: "), "Output was {}", fold_env.output);
+            assert!(fold_env.output.contains("attempt 2 failed: "), "Output was {}", fold_env.output);
+            assert!(!fold_env.output.contains("attempt 3 failed: "), "Output was {}", fold_env.output);
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let flags = Flags::new();
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @rate-limit 2"), &flags);
+            assert_eq!(fold_env.rate_limit, Some(2.0));
+            fold_env.parse_flags(&String::from("# @rate-limit 0"), &flags);
+            assert_eq!(fold_env.rate_limit, None);
+            fold_env.parse_flags(&String::from("# @rate-limit 0.5"), &flags);
+            assert_eq!(fold_env.rate_limit, Some(0.5));
+        }
+        {
+            assert_eq!(rate_limit_sleep(2.0, Duration::from_millis(100)), Duration::from_millis(400));
+            assert_eq!(rate_limit_sleep(0.5, Duration::from_millis(500)), Duration::from_millis(1500));
+            assert_eq!(rate_limit_sleep(10.0, Duration::from_millis(100)), Duration::ZERO);
+            assert_eq!(rate_limit_sleep(10.0, Duration::from_secs(5)), Duration::ZERO);
+        }
+        {
+            // debug folds don't send anything, so they don't wait
+            let mut g_env = GlobalEnv::new(None);
+            g_env.last_request_at = Some(Instant::now());
+            let input = "###{\n# @debug\n# @rate-limit 1\nGET https://reqbin.com/echo/get/json\n###}\n".repeat(2);
+            let start = Instant::now();
+            g_env.parse_input(&mut input.as_bytes(), false);
+            let elapsed = start.elapsed().as_millis();
+            assert!(elapsed < 1000, "Debug folds should not wait, took {}ms", elapsed);
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut g_env = GlobalEnv::new(None);
+        let flags = Flags::new();
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @delay 500"), &flags);
+            assert_eq!(fold_env.delay_ms, Some(500));
+            fold_env.parse_flags(&String::from("# @delay soon"), &flags);
+            assert_eq!(fold_env.delay_ms, Some(500));
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @debug"), &flags);
+            fold_env.parse_flags(&String::from("# @delay 60000"), &flags);
+            fold_env.request_started = true;
+            fold_env.url = String::from("https://reqbin.com/echo/get/json");
+            let start = std::time::Instant::now();
+            fold_env.make_request(&mut g_env);
+            assert!(start.elapsed() < Duration::from_millis(60000));
+            assert!(!fold_env.error, "Expected no error, output was {}", fold_env.output);
+            assert!(fold_env.output.starts_with("# sleep 60000ms\ncurl "), "Output was {}", fold_env.output);
+        }
+    }
+
+    #[test]
+    fn test_check_redirect_status() {
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.check_redirect_status("HTTP/1.1 301 Moved Permanently\n\nHTTP/1.1 404 Not Found");
+            assert!(!fold_env.error, "Expected no error without @follow");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.follow = true;
+            fold_env.check_redirect_status("HTTP/1.1 301 Moved Permanently\n\nHTTP/1.1 200 OK");
+            assert!(!fold_env.error, "Expected no error, output was {}", fold_env.output);
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.max_redirects = Some(3);
+            fold_env.check_redirect_status("HTTP/1.1 301 Moved Permanently\n\nHTTP/1.1 404 Not Found");
+            assert!(fold_env.error);
+            assert_eq!(fold_env.output, "redirect destination returned status 404\n");
+        }
+    }
+
+    #[test]
+    fn test_filter_response() {
+        let flags = Flags::new();
+        let val = json!({"items": [1, 2, 3]});
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @jq .items[0]"), &flags);
+            let (output, val) = fold_env.filter_response("HTTP/1.1 200 OK", &val).unwrap();
+            assert_eq!(output, "HTTP/1.1 200 OK\n\n1");
+            assert_eq!(val, json!(1));
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @jq .items"), &flags);
+            fold_env.parse_flags(&String::from("# @jq map(. * 2)"), &flags);
+            let (output, val) = fold_env.filter_response("", &val).unwrap();
+            assert_eq!(output, "[\n  2,\n  4,\n  6\n]");
+            assert_eq!(val, json!([2, 4, 6]));
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @jq .items +"), &flags);
+            assert!(fold_env.filter_response("", &val).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_fixture() {
+        let flags = Flags::new();
+        let path = env::temp_dir().join("vim-rest-client-fixture.json");
+        let _ = fs::remove_file(&path);
+        let mut g_env = GlobalEnv::new(None);
+        let val = json!({"id": 5, "name": "test"});
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @fixture {}", path.display()), &flags);
+            fold_env.check_fixture(&mut g_env, &val).unwrap();
+            assert!(fold_env.fixture_created);
+            assert!(fold_env.status().starts_with("FIXTURE CREATED, "), "Got {}", fold_env.status());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "{\n  \"id\": 5,\n  \"name\": \"test\"\n}\n");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @fixture {}", path.display()), &flags);
+            fold_env.check_fixture(&mut g_env, &val).unwrap();
+            assert!(!fold_env.error, "Expected match, got:\n{}", fold_env.output);
+            assert!(fold_env.status().starts_with("SUCCESS, "), "Got {}", fold_env.status());
+        }
+        {
+            fs::write(&path, "{\n  \"id\": 5,\n  \"name\": \"old\"\n}\n").unwrap();
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @fixture {}", path.display()), &flags);
+            fold_env.check_fixture(&mut g_env, &val).unwrap();
+            assert!(fold_env.error);
+            let expected = format!("response does not match fixture {}\n-  \"name\": \"old\"\n+  \"name\": \"test\"\n", path.display());
+            assert_eq!(fold_env.output, expected, "Expected:\n{}\nGot:\n{}", expected, fold_env.output);
+        }
+        {
+            g_env.update_fixtures = true;
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @fixture {}", path.display()), &flags);
+            fold_env.check_fixture(&mut g_env, &val).unwrap();
+            assert!(!fold_env.error && !fold_env.fixture_created);
+            assert!(fs::read_to_string(&path).unwrap().contains("\"test\""));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_iso_timestamp() {
+        assert_eq!(iso_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso_timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+        assert_eq!(iso_timestamp(UNIX_EPOCH + Duration::from_secs(1_714_566_645)), "2024-05-01T12:30:45.000Z");
+    }
+
+    #[test]
+    fn test_log_request() {
+        let flags = Flags::new();
+        let path = env::temp_dir().join("vim-rest-client-log.ndjson");
+        let _ = fs::remove_file(&path);
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"dir": env::temp_dir().display().to_string(), "token": "abc123"});
+        g_env.last_request_ms = Some(42);
+        let req = Request::new(Method::Get, "https://reqbin.com/echo?token=abc123", Vec::new(), None, false);
+        let headers = "HTTP/1.1 200 OK\nContent-Type: application/json";
+        {
+            let fold_env = FoldEnv::new();
+            fold_env.log_request(&mut g_env, &req, headers, "{}").unwrap();
+            assert!(fs::metadata(&path).is_err());
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @log {{.dir}}/vim-rest-client-log.ndjson"), &flags);
+            fold_env.parse_flags(&String::from("# @sensitive token"), &flags);
+            fold_env.log_request(&mut g_env, &req, headers, r#"{"token":"abc123"}"#).unwrap();
+            fold_env.log_request(&mut g_env, &req, headers, &"é".repeat(LOG_BODY_MAX_BYTES)).unwrap();
+            let req = Request::new(Method::Get, "https://reqbin.com/echo/{{.id}}?token={{.token}}", Vec::new(), None, false);
+            g_env.env["id"] = json!(7);
+            fold_env.log_request(&mut g_env, &req, headers, "{}").unwrap();
+            let body = format!("{} abc123", "x".repeat(LOG_BODY_MAX_BYTES - 4));
+            fold_env.log_request(&mut g_env, &req, headers, &body).unwrap();
+        }
+        let log = fs::read_to_string(&path).unwrap();
+        let entries = log.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<Value>>();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0]["method"], json!("GET"));
+        assert_eq!(entries[0]["url"], json!("https://reqbin.com/echo?token=[REDACTED]"));
+        assert_eq!(entries[0]["status"], json!(200));
+        assert_eq!(entries[0]["duration_ms"], json!(42));
+        assert_eq!(entries[0]["body"], json!(r#"{"token":"[REDACTED]"}"#));
+        assert!(Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z$").unwrap()
+            .is_match(entries[0]["timestamp"].as_str().unwrap()));
+        assert_eq!(entries[1]["body"].as_str().unwrap().len(), LOG_BODY_MAX_BYTES);
+        assert_eq!(entries[2]["url"], json!("https://reqbin.com/echo/7?token=[REDACTED]"));
+        let body = entries[3]["body"].as_str().unwrap();
+        assert_eq!(body, format!("{} [RE", "x".repeat(LOG_BODY_MAX_BYTES - 4)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_capture_rate_limit() {
+        let flags = Flags::new();
+        let mut g_env = GlobalEnv::new(None);
+        let headers = "HTTP/1.1 200 OK\nContent-Type: application/json\nx-ratelimit-remaining: 42\nX-RateLimit-Reset: 1700000000";
+        {
+            g_env.env = json!({});
+            let mut fold_env = FoldEnv::new();
+            fold_env.capture_rate_limit(&mut g_env, headers).unwrap();
+            assert_eq!(g_env.env, json!({}));
+            assert_eq!(fold_env.output, "");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @show-rate-limit"), &flags);
+            fold_env.capture_rate_limit(&mut g_env, headers).unwrap();
+            assert_eq!(g_env.env[RATE_LIMIT_REMAINING], json!(42));
+            assert_eq!(g_env.env[RATE_LIMIT_RESET], json!(1700000000));
+            assert_eq!(fold_env.output, "Rate limit: 42 remaining, resets at 1700000000\n");
+        }
+        {
+            g_env.env = json!({});
+            g_env.rate_limit_headers = true;
+            let verbose = "< HTTP/1.1 429 Too Many Requests\n< X-RateLimit-Remaining: 0\n<\n";
+            let mut fold_env = FoldEnv::new();
+            fold_env.capture_rate_limit(&mut g_env, verbose).unwrap();
+            assert_eq!(g_env.env, json!({RATE_LIMIT_REMAINING: 0}));
+            assert_eq!(fold_env.output, "Rate limit: 0 remaining\n");
+            g_env.rate_limit_headers = false;
+        }
+    }
+
+    #[test]
+    fn test_add_stats() {
+        let flags = Flags::new();
+        let headers = "HTTP/1.1 200 OK\nContent-Type: application/json\nContent-Length: 2458";
+        let body = format!("{{\"a\": 1, \"b\": \"{}\"}}", "x".repeat(2440));
+        let resp = Response::new(format!("{}\n\n{}", headers, body), String::new(), false);
+        let (_, val) = resp.get_return();
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.add_stats(headers, &body, &val);
+            assert_eq!(fold_env.output, "");
+        }
+        {
+            // the body is one line, even though it is shown pretty-printed
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @stats"), &flags);
+            fold_env.add_stats(headers, &body, &val);
+            assert_eq!(fold_env.output, "--- Stats: 2.4 KB, 1 line, 2 JSON keys, 2 headers ---\n");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @stats"), &flags);
+            fold_env.add_stats("", "ok\nfine\n", &json!("ok\nfine\n"));
+            assert_eq!(fold_env.output, "--- Stats: 8 B, 2 lines, 0 headers ---\n");
+        }
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MB");
+    }
+
+    #[test]
+    fn test_check_not_modified() {
+        let flags = Flags::new();
+        let not_modified = "HTTP/1.1 304 Not Modified\nETag: \"abc\"";
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.check_not_modified(not_modified);
+            assert_eq!(fold_env.output, "");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @if-none-match etag"), &flags);
+            fold_env.check_not_modified("HTTP/1.1 200 OK\nETag: \"def\"");
+            assert_eq!(fold_env.output, "");
+            fold_env.check_not_modified(not_modified);
+            assert_eq!(fold_env.output, "(304 Not Modified — cached)\n");
+            assert!(!fold_env.error);
+        }
+    }
+
+    #[test]
+    fn test_with_transient_retries() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            let mut codes = vec![6, 7].into_iter();
+            let res = g_env.with_transient_retries(&[0, 0, 0], |_| match codes.next() {
+                Some(code) => Err(RestClientError::Curl(String::from("failed"), code)),
+                None => Ok((b"ok".to_vec(), String::new())),
+            });
+            assert_eq!(res.unwrap().0, b"ok");
+            assert_eq!(mem::take(&mut g_env.transient_retries), vec![
+                "curl failed with exit code 6, retry 1 of 3 in 0ms",
+                "curl failed with exit code 7, retry 2 of 3 in 0ms",
+            ]);
+        }
+        {
+            let mut attempts = 0;
+            let res = g_env.with_transient_retries(&[0, 0, 0], |_| {
+                attempts += 1;
+                Err(RestClientError::Curl(String::from("failed"), 6))
+            });
+            assert!(res.is_err());
+            assert_eq!(attempts, 4);
+            assert_eq!(mem::take(&mut g_env.transient_retries).len(), 3);
+        }
+        {
+            let mut attempts = 0;
+            let res = g_env.with_transient_retries(&[0, 0, 0], |_| {
+                attempts += 1;
+                Err(RestClientError::Curl(String::from("failed"), 22))
+            });
+            assert!(res.is_err());
+            assert_eq!(attempts, 1);
+            assert!(g_env.transient_retries.is_empty());
+        }
+        {
+            let mut attempts = 0;
+            let res = g_env.with_transient_retries(&[], |_| {
+                attempts += 1;
+                Err(RestClientError::Curl(String::from("failed"), 6))
+            });
+            assert!(res.is_err());
+            assert_eq!(attempts, 1);
+        }
+    }
+
+    #[test]
+    fn test_replace_colon_params() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"userId": 42, "slug": "hello-world", "postId": null});
+        assert_eq!(
+            replace_colon_params(&mut g_env, "https://reqbin.com:8443/users/:userId/posts/:slug?a=:userId").unwrap(),
+            "https://reqbin.com:8443/users/42/posts/hello-world?a=:userId"
+        );
+        assert_eq!(
+            replace_colon_params(&mut g_env, "https://reqbin.com/users/:missing").unwrap_err().to_string().trim_end(),
+            "path parameter :missing is not set"
+        );
+        assert!(replace_colon_params(&mut g_env, "https://reqbin.com/posts/:postId").is_err());
+    }
+
+    #[test]
+    fn test_save_headers() {
+        let flags = Flags::new();
+        let path = env::temp_dir().join("vim-rest-client-headers.txt");
+        let _ = fs::remove_file(&path);
+        let mut g_env = GlobalEnv::new(None);
+        let headers = "HTTP/1.1 200 OK\nContent-Type: application/json\nContent-Length: 19";
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.save_headers(&mut g_env, headers).unwrap();
+            assert!(fs::metadata(&path).is_err());
+            assert_eq!(fold_env.output, "");
+        }
+        {
+            g_env.env = json!({"dir": env::temp_dir().display().to_string()});
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @save-headers {{.dir}}/vim-rest-client-headers.txt"), &flags);
+            fold_env.save_headers(&mut g_env, headers).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", headers));
+            assert_eq!(fold_env.output, format!("Headers saved to {}\n", path.display()));
+        }
+        {
+            let verbose = "* Connected to reqbin.com\n> GET / HTTP/1.1\n> Host: reqbin.com\n>\n< HTTP/1.1 200 OK\n< Content-Type: application/json\n< Content-Length: 19\n<\n";
+            let mut fold_env = FoldEnv::new();
+            fold_env.is_verbose = true;
+            fold_env.parse_flags(&format!("# @save-headers {}", path.display()), &flags);
+            fold_env.save_headers(&mut g_env, verbose).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", headers));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let flags = Flags::new();
+        let path = env::temp_dir().join("vim-rest-client-schema.json");
+        fs::write(&path, r#"{"type": "object", "properties": {"success": {"type": "string"}}}"#).unwrap();
+        let mut g_env = GlobalEnv::new(None);
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @schema {}", path.display()), &flags);
+            fold_env.check_schema(&mut g_env, &json!({"success": "true"})).unwrap();
+            assert!(!fold_env.error, "Expected match, got:\n{}", fold_env.output);
+            assert_eq!(fold_env.output, "");
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @schema {}", path.display()), &flags);
+            fold_env.check_schema(&mut g_env, &json!({"success": true})).unwrap();
+            assert!(fold_env.error);
+            let expected = format!("response does not match schema {}\n/success: true is not of type \"string\"\n", path.display());
+            assert_eq!(fold_env.output, expected, "Expected:\n{}\nGot:\n{}", expected, fold_env.output);
         }
-        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
-        let ret = ret.replace('\n', "");
-        Ok(Some(json!(ret)))
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&format!("# @schema {}", path.display()), &flags);
+            fold_env.check_schema(&mut g_env, &json!("<html></html>")).unwrap();
+            assert!(!fold_env.error);
+            let expected = format!("response is not JSON, skipped schema {}\n", path.display());
+            assert_eq!(fold_env.output, expected);
+        }
+        {
+            g_env.env = json!({"schemaPath": path.display().to_string()});
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @schema {{.schemaPath}}"), &flags);
+            fold_env.check_schema(&mut g_env, &json!([])).unwrap();
+            assert!(fold_env.error);
+        }
+        let _ = fs::remove_file(&path);
     }
 
-    fn call_curl(&mut self, args: &Vec<String>) -> Result<(String, String), Box<dyn Error>> {
-        if let Some(_) = self.env.get(SSH_TO) {
-            let rt = Runtime::new()?;
-            return rt.block_on(self.ssh_curl(args));
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(diff_lines("a\nb\n", "a\nb"), "");
+        assert_eq!(diff_lines("a\nb", "a\nc"), "-b\n+c\n");
+        assert_eq!(diff_lines("a", "a\nb"), "+b\n");
+        assert_eq!(diff_lines("a\nb", "a"), "-b\n");
+    }
+
+    #[test]
+    fn test_check_assertions() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"created": {"id": 5}});
+        let val = json!({"status": "ok", "id": 5, "items": [1, 2]});
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.assertions = vec![
+                String::from(r#".status == "ok""#),
+                String::from(".items | length > 1"),
+            ];
+            fold_env.check_assertions(&mut g_env, &val);
+            assert!(!fold_env.error, "Expected no error, output was {}", fold_env.output);
+            assert!(fold_env.output.is_empty(), "Expected no output, got {}", fold_env.output);
         }
-        let curl = Command::new("curl")
-            .args(args)
-            .output()?;
-        let e = String::from_utf8_lossy(&curl.stderr).to_string();
-        if !curl.status.success() {
-            return Err(io_error(&e))?;
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.assertions = vec![
+                String::from(r#".status == "error""#),
+                String::from(".missing"),
+                String::from(".id == 5"),
+            ];
+            fold_env.check_assertions(&mut g_env, &val);
+            assert!(fold_env.error);
+            let expected = "assertion failed: .status == \"error\"\nassertion failed: .missing\n";
+            assert_eq!(fold_env.output, expected, "Expected {}, but got {}", expected, fold_env.output);
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.assertions = vec![String::from(".id == {{.created.id}}")];
+            fold_env.check_assertions(&mut g_env, &val);
+            assert!(!fold_env.error, "Expected no error, output was {}", fold_env.output);
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.assertions = vec![String::from(".id == {{.dne}}")];
+            fold_env.check_assertions(&mut g_env, &val);
+            assert!(fold_env.error);
+            let expected = "assertion failed: .id == {{.dne}}: failed to get resource at .dne\n";
+            assert_eq!(fold_env.output, expected, "Expected {}, but got {}", expected, fold_env.output);
         }
-        let ret = String::from_utf8_lossy(&curl.stdout).to_string();
-        let ret = ret.replace('\r', "");
-        let e = e.replace('\r', "");
-        Ok((ret, e))
     }
 
-    async fn ssh_curl(&mut self, args: &Vec<String>) -> Result<(String, String), Box<dyn Error>> {
-        let dest = self.env.get(SSH_TO)
-            .unwrap()
-            .as_str()
-            .ok_or_else(|| io_error(&format!("{} was not a string", SSH_TO)))?;
-        let session = if let Some(sess_ref) = self.sessions.remove(dest) {
-            sess_ref
-        } else {
-            let mut session_builder = SessionBuilder::default();
-            if let Some(config) = self.env.get(SSH_CONFIG) {
-                let config = config.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_CONFIG)))?;
-                session_builder.config_file(config);
-            }
-            if let Some(key) = self.env.get(SSH_KEY) {
-                let key = key.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_KEY)))?;
-                session_builder.keyfile(key);
-            }
-            if let Some(port) = self.env.get(SSH_PORT) {
-                let port = port.as_u64().ok_or_else(|| io_error(&format!("{} was not a number", SSH_PORT)))? as u16;
-                session_builder.port(port);
-            }
-            session_builder.connect_mux(dest).await?
-        };
-        let curl = session.command("curl")
-            .args(args)
-            .output()
-            .await?;
-        let e = String::from_utf8_lossy(&curl.stderr).to_string();
-        if !curl.status.success() {
-            return Err(io_error(&e))?;
+    #[test]
+    fn test_execute_fold() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({
+            "baseUrl": "https://reqbin.com",
+            "ct": "application/json",
+            "name": "test"
+        });
+        {
+            let curl = g_env.execute_fold_debug(
+                Method::Post,
+                "{{.baseUrl}}/echo/post/json",
+                vec![String::from("Content-Type: {{.ct}}")],
+                Some(String::from(r#"{"name": "{{.name}}"}"#)),
+            ).unwrap();
+            assert!(curl.starts_with("curl "), "Got {}", curl);
+            assert!(curl.contains("-X POST"), "Got {}", curl);
+            assert!(curl.contains("https://reqbin.com/echo/post/json"), "Got {}", curl);
+            assert!(curl.contains("Content-Type: application/json"), "Got {}", curl);
+            assert!(curl.contains(r#"{"name": "test"}"#), "Got {}", curl);
+        }
+        {
+            let path = env::temp_dir().join("vim-rest-client-execute.json");
+            fs::write(&path, "{\"a\": 1}\n").unwrap();
+            g_env.env = json!({"path": path.display().to_string()});
+            let (output, _) = g_env.execute_fold(Method::Get, "file://{{.path}}", vec![], None).unwrap();
+            assert!(output.contains("{\"a\": 1}"), "Got {}", output);
+            let _ = fs::remove_file(&path);
+        }
+        {
+            assert!(g_env.execute_fold(Method::Get, "{{.dne}}", vec![], None).is_err());
         }
-        let ret = String::from_utf8_lossy(&curl.stdout).to_string();
-        let ret = ret.replace('\r', "");
-        let e = e.replace('\r', "");
-        self.sessions.insert(String::from(dest), session);
-        Ok((ret, e))
     }
 
-    async fn ssh_get_env_var(&mut self, var: &String) -> Result<Value, Box<dyn Error>> {
-        let dest = self.env.get(SSH_TO)
-            .unwrap()
-            .as_str()
-            .ok_or_else(|| io_error(&format!("{} was not a string", SSH_TO)))?;
-        let session = if let Some(sess_ref) = self.sessions.remove(dest) {
-            sess_ref
-        } else {
-            let mut session_builder = SessionBuilder::default();
-            if let Some(config) = self.env.get(SSH_CONFIG) {
-                let config = config.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_CONFIG)))?;
-                session_builder.config_file(config);
-            }
-            if let Some(key) = self.env.get(SSH_KEY) {
-                let key = key.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_KEY)))?;
-                session_builder.keyfile(key);
-            }
-            if let Some(port) = self.env.get(SSH_PORT) {
-                let port = port.as_u64().ok_or_else(|| io_error(&format!("{} was not a number", SSH_PORT)))? as u16;
-                session_builder.port(port);
-            }
-            session_builder.connect_mux(dest).await?
-        };
-        let echo = session.command("echo")
-            .raw_arg(var)
-            .output()
-            .await?;
-        let e = String::from_utf8_lossy(&echo.stderr).to_string();
-        if !echo.status.success() {
-            return Err(io_error(&e))?;
+    #[test]
+    fn test_catch_jq_panic() {
+        assert_eq!(catch_jq_panic(|| 1).unwrap(), 1);
+        match catch_jq_panic(|| -> i32 { panic!("bad filter") }) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => assert_eq!(e.to_string(), "jq panicked: bad filter"),
         }
-        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
-        let ret = ret.replace('\r', "");
-        let ret = ret.replace('\n', "");
-        self.sessions.insert(String::from(dest), session);
-        Ok(json!(ret))
+        match catch_jq_panic(|| -> i32 { panic!("bad filter {}", 2) }) {
+            Ok(_) => panic!("Expected an error"),
+            Err(e) => assert_eq!(e.to_string(), "jq panicked: bad filter 2"),
+        }
+        assert!(run_jq(".[", &json!({})).is_err());
     }
 
-    async fn ssh_command_substitution(&mut self, selector: &str) -> Result<Option<Value>, Box<dyn Error>> {
-        let dest = self.env.get(SSH_TO)
-            .unwrap()
-            .as_str()
-            .ok_or_else(|| io_error(&format!("{} was not a string", SSH_TO)))?;
-        let session = if let Some(sess_ref) = self.sessions.remove(dest) {
-            sess_ref
-        } else {
-            let mut session_builder = SessionBuilder::default();
-            if let Some(config) = self.env.get(SSH_CONFIG) {
-                let config = config.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_CONFIG)))?;
-                session_builder.config_file(config);
-            }
-            if let Some(key) = self.env.get(SSH_KEY) {
-                let key = key.as_str().ok_or_else(|| io_error(&format!("{} was not a string", SSH_KEY)))?;
-                session_builder.keyfile(key);
-            }
-            if let Some(port) = self.env.get(SSH_PORT) {
-                let port = port.as_u64().ok_or_else(|| io_error(&format!("{} was not a number", SSH_PORT)))? as u16;
-                session_builder.port(port);
+    #[test]
+    fn test_fetch_pages() {
+        let flags = Flags::new();
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        let ok = String::from("HTTP/1.1 200 OK");
+        {
+            // pages 1 to 3, each pointing to the next, until the last
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @paginate .next_page_url"), &flags);
+            let mut requested = Vec::new();
+            let first = json!({"page": 1, "next_page_url": "/p/2"});
+            let pages = fold_env.fetch_pages(&mut g_env, "https://api.example.com/items", &ok, first, |_, url| {
+                requested.push(String::from(url));
+                let page = requested.len() + 1;
+                if page < 3 {
+                    Ok((ok.clone(), json!({"page": page, "next_page_url": format!("/p/{}", page + 1)})))
+                } else {
+                    Ok((ok.clone(), json!({"page": page, "next_page_url": null})))
+                }
+            }).unwrap();
+            assert_eq!(requested, vec!["https://api.example.com/p/2", "https://api.example.com/p/3"]);
+            assert_eq!(run_jq("[.[].page]", &pages).unwrap(), json!([1, 2, 3]));
+            assert_eq!(fold_env.output, "fetched 3 page(s)\n");
+        }
+        {
+            // a page that always points to another page stops at MAX_PAGES
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @paginate .next"), &flags);
+            let pages = fold_env.fetch_pages(&mut g_env, "https://api.example.com", &ok, json!({"next": "/again"}), |_, _| {
+                Ok((ok.clone(), json!({"next": "/again"})))
+            }).unwrap();
+            assert_eq!(pages.as_array().unwrap().len(), MAX_PAGES);
+            assert!(fold_env.output.starts_with("warning: stopped paginating after 100 pages\n"), "Got {}", fold_env.output);
+        }
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @paginate .next"), &flags);
+            let pages = fold_env.fetch_pages(&mut g_env, "https://api.example.com", &ok, json!({"next": ""}), |_, _| {
+                panic!("no page should be requested");
+            }).unwrap();
+            assert_eq!(pages, json!([{"next": ""}]));
+        }
+        {
+            // the Link header of each page points to the next
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @paginate link"), &flags);
+            let link = |page: u32| format!(
+                "HTTP/1.1 200 OK\nLink: <https://api.example.com/items?page=1>; rel=\"first\", <?page={}>; rel=\"next\"",
+                page
+            );
+            let mut requested = Vec::new();
+            let pages = fold_env.fetch_pages(&mut g_env, "https://api.example.com/items", &link(2), json!([1]), |_, url| {
+                requested.push(String::from(url));
+                match requested.len() {
+                    1 => Ok((link(3), json!([2]))),
+                    _ => Ok((ok.clone(), json!([3]))),
+                }
+            }).unwrap();
+            assert_eq!(requested, vec!["https://api.example.com/items?page=2", "https://api.example.com/items?page=3"]);
+            assert_eq!(pages, json!([[1], [2], [3]]));
+        }
+        {
+            // a page that fails isn't added to the pages
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @paginate .next"), &flags);
+            let res = fold_env.fetch_pages(&mut g_env, "https://api.example.com", &ok, json!({"next": "/p/2"}), |_, _| {
+                Ok((String::from("HTTP/1.1 500 Internal Server Error"), json!({"error": "oops"})))
+            });
+            match res {
+                Ok(pages) => panic!("Expected an error, got {}", pages),
+                Err(err) => assert_eq!(err.to_string(), "page 2 (https://api.example.com/p/2) returned status 500"),
             }
-            session_builder.connect_mux(dest).await?
-        };
-        let echo = session.command("echo")
-            .raw_arg(selector)
-            .output()
-            .await?;
-        let e = String::from_utf8_lossy(&echo.stderr).to_string();
-        if !echo.status.success() {
-            return Err(io_error(&e))?;
         }
-        let ret = String::from_utf8_lossy(&echo.stdout).to_string();
-        let ret = ret.replace('\r', "");
-        let ret = ret.replace('\n', "");
-        self.sessions.insert(String::from(dest), session);
-        Ok(Some(json!(ret)))
+        {
+            // debug mode only shows the first curl command
+            let input = "###{\n# @debug\n# @paginate .next\nGET https://reqbin.com/echo/get/json\n###}";
+            let results = g_env.parse_input(&mut input.as_bytes(), false);
+            assert!(!results[0].error, "Got {}", results[0].output);
+            assert!(!results[0].output.contains("page(s)"), "Got {}", results[0].output);
+        }
     }
-}
-
-
-/// Returns an error
-pub fn io_error(err: &str) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
-}
 
-/// Adds a newline to the string if the last char is not a newline
-fn insert_newline(s: &mut String) {
-    if !s.is_empty() && s.chars().last().unwrap() != '\n' {
-        s.push('\n');
+    #[test]
+    fn test_resolve_url() {
+        let base = "https://api.example.com/v1/items?page=1";
+        assert_eq!(resolve_url(base, "https://other.example.com/x"), "https://other.example.com/x");
+        assert_eq!(resolve_url(base, "//cdn.example.com/x"), "https://cdn.example.com/x");
+        assert_eq!(resolve_url(base, "/v1/items?page=2"), "https://api.example.com/v1/items?page=2");
+        assert_eq!(resolve_url(base, "?page=2"), "https://api.example.com/v1/items?page=2");
+        assert_eq!(resolve_url(base, "others?page=2"), "https://api.example.com/v1/others?page=2");
+        assert_eq!(resolve_url("localhost:8080/items", "/items?page=2"), "localhost:8080/items?page=2");
+        assert_eq!(resolve_url("https://api.example.com", "items"), "https://api.example.com/items");
     }
-}
-
-
-///////////////////////////////////////////////
-/// Unit tests
-///////////////////////////////////////////////
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    fn clear_env_file() {
-        if let Err(_) = fs::remove_file(ENV_FILE) {
-            println!("file doesn't exist")
-        } else {
-            println!("file deleted")
-        }
+    #[test]
+    fn test_link_next() {
+        assert_eq!(
+            link_next("HTTP/1.1 200 OK\nlink: <https://a.example.com/?page=3>; rel=\"prev next\""),
+            Some(String::from("https://a.example.com/?page=3"))
+        );
+        assert_eq!(link_next("HTTP/1.1 200 OK\nLink: </?page=1>; rel=\"prev\""), None);
+        // only the headers of the last response after a redirect count
+        assert_eq!(link_next("HTTP/1.1 301 Moved\nLink: </old>; rel=next\n\nHTTP/1.1 200 OK"), None);
     }
 
     #[test]
-    fn test_parse_selectors() {
-        // create dummy env (json) and call evaluate to see if it returns the
-        // right values
+    fn test_capture_all_vars() {
+        let flags = Flags::new();
         let mut g_env = GlobalEnv::new(None);
-        g_env.env = json!({
-            "arr": ["a", "b", "c"],
-            "str": "value",
-            "num": 1,
-            "bool": true,
-            "obj": {"a": 1, "b": 2},
-            "a": "test",
-            "a1": "success"
-        });
-
-        {
-            let s = String::from("\"Some String\"");
-            let res = g_env.parse_selectors(&s).unwrap();
-            assert_eq!(res, s, "Expected {}, but got {}", s, res);
-        }
+        g_env.env = json!({});
+        let val = json!({"data": {"token": "abc", "expires_in": 3600}});
         {
-            let s = String::from("\"Some {{.str}}\"");
-            let res = g_env.parse_selectors(&s).unwrap();
-            let expect = String::from("\"Some value\"");
-            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @capture-all {token: .data.token, expiry: .data.expires_in}"), &flags);
+            fold_env.capture_all_vars(&mut g_env, &val).unwrap();
+            assert_eq!(g_env.evaluate(&String::from(".token")).unwrap(), json!("abc"));
+            assert_eq!(g_env.evaluate(&String::from(".expiry")).unwrap(), json!(3600));
+            let res = g_env.parse_selectors(&String::from("Bearer {{.token}}")).unwrap();
+            assert_eq!(res, "Bearer abc");
         }
         {
-            let s = String::from("\"{{.obj.{{.arr[0]}}}}\"");
-            let res = g_env.parse_selectors(&s).unwrap();
-            let expect = String::from("\"1\"");
-            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @capture-all .data.token"), &flags);
+            match fold_env.capture_all_vars(&mut g_env, &val) {
+                Ok(_) => panic!("Expected error for a non-object result"),
+                Err(e) => assert_eq!(e.to_string(), "capture-all .data.token did not return an object"),
+            }
         }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_capture_var_mappings() {
+        let flags = Flags::new();
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"prefix": "api"});
+        let val = json!({"data": {"access_token": "abc", "user": {"id": 7}}});
         {
-            let s = String::from("\"{{.{{.arr[0]}}}}\"");
-            let res = g_env.parse_selectors(&s).unwrap();
-            let expect = String::from("\"test\"");
-            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from(r#"# @vars-from-response {"token": ".data.access_token", "{{.prefix}}UserId": ".data.user.id", "bad": ".data |"}"#), &flags);
+            assert!(!fold_env.error, "Got:\n{}", fold_env.output);
+            fold_env.capture_var_mappings(&mut g_env, &val).unwrap();
+            assert_eq!(g_env.evaluate(&String::from(".token")).unwrap(), json!("abc"));
+            assert_eq!(g_env.evaluate(&String::from(".apiUserId")).unwrap(), json!(7));
+            assert!(g_env.env.get("bad").is_none());
+            assert!(fold_env.output.starts_with("warning: could not set bad"), "Got:\n{}", fold_env.output);
         }
         {
-            let s = String::from("\"{{.{{.arr[0]}}{{.num}}}}\"");
-            let res = g_env.parse_selectors(&s).unwrap();
-            let expect = String::from("\"success\"");
-            assert_eq!(res, expect, "Expected {}, but got {}", expect, res);
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from(r#"# @vars-from-response {"token": 1}"#), &flags);
+            assert!(fold_env.error);
+            assert_eq!(fold_env.output, "Could not parse vars-from-response: the selector of token is not a string\n");
         }
+        clear_env_file();
     }
 
     #[test]
-    fn test_evaluate() {
-        // create dummy env (json) and call evaluate to see if it returns the
-        // right values
+    fn test_capture_body() {
         let mut g_env = GlobalEnv::new(None);
-        g_env.env = json!({
-            "arr": ["a", "b", "c"],
-            "str": "value",
-            "num": 1,
-            "bool": true,
-            "obj": {"a": 1, "b": 2}
-        });
+        g_env.env = json!({});
+        let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
         {
-            let arr = g_env.evaluate(&String::from(".arr")).unwrap();
-            assert_eq!(arr, json!(["a", "b", "c"]), "Expected [\"a\", \"b\", \"c\"], but got {:?}", arr);
-            let arr0 = g_env.evaluate(&String::from(".arr[0]")).unwrap();
-            assert_eq!(arr0, json!("a"), "Expected \"a\", but got {:?}", arr0);
-            let arr_err = g_env.evaluate(&String::from(".arr[3]"));
-            match arr_err {
-                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-                Err(e) => assert_eq!(
-                    e.to_string(),
-                    "failed to get resource at .arr[3]",
-                    "Got an incorrect error: \"{}\"",
-                    e.to_string()
-                ),
-            };
+            let mut fold_env = FoldEnv::new();
+            fold_env.captures = vec![
+                (String::from(r"<ResponseCode>(\d+)</ResponseCode>"), String::from("code")),
+                (String::from(r"<ResponseMessage>.*</ResponseMessage>"), String::from("message")),
+            ];
+            fold_env.capture_body(&mut g_env, body).unwrap();
+            assert!(fold_env.output.is_empty(), "Expected no output, got {}", fold_env.output);
+            let code = g_env.evaluate(&String::from(".code")).unwrap();
+            assert_eq!(code, json!("0"), "Expected \"0\", but got {:?}", code);
+            let message = g_env.evaluate(&String::from(".message")).unwrap();
+            let expected = json!("<ResponseMessage>Success</ResponseMessage>");
+            assert_eq!(message, expected, "Expected {:?}, but got {:?}", expected, message);
         }
         {
-            let strng = g_env.evaluate(&String::from(".str")).unwrap();
-            assert_eq!(strng, json!("value"), "Expected \"value\", but got {:?}", strng);
-            let num = g_env.evaluate(&String::from(".num")).unwrap();
-            assert_eq!(num, json!(1), "Expected 1, but got {:?}", num);
-            let boolean = g_env.evaluate(&String::from(".bool")).unwrap();
-            assert_eq!(boolean, json!(true), "Expected true, but got {:?}", boolean);
+            let mut fold_env = FoldEnv::new();
+            fold_env.captures = vec![(String::from(r"<Missing>(.*)</Missing>"), String::from("missing"))];
+            fold_env.capture_body(&mut g_env, body).unwrap();
+            let expected = "warning: capture <Missing>(.*)</Missing> did not match the response\n";
+            assert_eq!(fold_env.output, expected, "Expected {}, but got {}", expected, fold_env.output);
+            assert!(g_env.env.get("missing").is_none(), "missing should not be set");
         }
         {
-            let obj = g_env.evaluate(&String::from(".obj")).unwrap();
-            assert_eq!(obj, json!({"a": 1, "b": 2}), "Expected {{\"a\": 1, \"b\", 2}}, but got {:?}", obj);
-            let obj_a = g_env.evaluate(&String::from(".obj.a")).unwrap();
-            assert_eq!(obj_a, json!(1), "Expected 1, but got {:?}", obj_a);
-            let obj_err = g_env.evaluate(&String::from(".obj.c"));
-            match obj_err {
+            let mut fold_env = FoldEnv::new();
+            fold_env.strict = true;
+            fold_env.captures = vec![(String::from(r"<Missing>(.*)</Missing>"), String::from("missing"))];
+            match fold_env.capture_body(&mut g_env, body) {
                 Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
                 Err(e) => assert_eq!(
                     e.to_string(),
-                    "failed to get resource at .obj.c",
+                    "capture <Missing>(.*)</Missing> did not match the response",
                     "Got an incorrect error: \"{}\"",
                     e.to_string()
                 ),
             };
         }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_capture_headers() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        let headers = "HTTP/1.1 100 Continue\n\nHTTP/1.1 200 OK\ncontent-type: application/json; charset=utf-8\nX-RateLimit-Remaining: 42";
+        let mut fold_env = FoldEnv::new();
+        fold_env.header_captures = vec![
+            (String::from("Content-Type"), String::from("ct")),
+            (String::from("X-RateLimit-Remaining"), String::from("remaining")),
+            (String::from("X-Missing"), String::from("missing")),
+        ];
+        fold_env.capture_headers(&mut g_env, headers).unwrap();
+        let expected = json!({
+            "ct": "application/json; charset=utf-8",
+            "remaining": "42",
+            "missing": ""
+        });
+        assert_eq!(g_env.env, expected, "Expected {:?}, but got {:?}", expected, g_env.env);
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_extract_cookies() {
+        let flags = Flags::new();
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        let headers = "HTTP/1.1 200 OK\nSet-Cookie: session=abc123; Path=/; HttpOnly\nset-cookie: theme=dark\nSet-Cookie: session=def456; Path=/";
+        let mut fold_env = FoldEnv::new();
+        fold_env.parse_flags(&String::from("# @extract-cookie session sessionId"), &flags);
+        fold_env.parse_flags(&String::from("# @extract-cookie theme theme"), &flags);
+        fold_env.parse_flags(&String::from("# @extract-cookie csrf csrfToken"), &flags);
+        fold_env.extract_cookies(&mut g_env, headers).unwrap();
+        let expected = json!({
+            "sessionId": "def456",
+            "theme": "dark",
+            "csrfToken": ""
+        });
+        assert_eq!(g_env.env, expected, "Expected {:?}, but got {:?}", expected, g_env.env);
+        assert_eq!(fold_env.output, "warning: cookie csrf is not set by the response\n");
+        {
+            let mut fold_env = FoldEnv::new();
+            fold_env.parse_flags(&String::from("# @extract-cookie session sessionId"), &flags);
+            fold_env.extract_cookies(&mut g_env, "HTTP/1.1 200 OK\nSet-Cookie: session=abc123; Path=/; HttpOnly").unwrap();
+            assert_eq!(g_env.env["sessionId"], json!("abc123"));
+            assert_eq!(fold_env.output, "");
+        }
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_ssh_jump_host() {
+        let mut g_env = GlobalEnv::new(None);
         {
-            let dne = g_env.evaluate(&String::from(".DNE_KEY"));
-            match dne {
-                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-                Err(e) => assert_eq!(
-                    e.to_string(),
-                    "failed to get resource at .DNE_KEY",
-                    "Got an incorrect error: \"{}\"",
-                    e.to_string()
-                ),
-            };
+            g_env.env = json!({});
+            assert_eq!(g_env.ssh_jump_host().unwrap(), None);
         }
         {
-            let env_var = g_env.evaluate(&String::from("$SHELL")).unwrap();
-            assert_eq!(env_var, json!("/bin/bash"), "Expected \"/bin/bash\", but got {:?}", env_var);
-            let dne_env_var = g_env.evaluate(&String::from("$DNE_VAR")).unwrap();
-            assert_eq!(dne_env_var, json!(""), "Expected \"\", but got {:?}", dne_env_var);
+            g_env.env = json!({"sshJumpHost": "admin@bastion"});
+            assert_eq!(g_env.ssh_jump_host().unwrap(), Some(String::from("admin@bastion")));
+        }
+        {
+            g_env.env = json!({"user": "admin", "sshJumpHost": "{{.user}}@bastion"});
+            assert_eq!(g_env.ssh_jump_host().unwrap(), Some(String::from("admin@bastion")));
         }
         {
-            let env_var = g_env.evaluate(&String::from("$(lsb_release -r | sed 's/^.*\\s\\+//')")).unwrap();
-            assert_eq!(env_var, json!("22.04"), "Expected \"22.04\", but got {:?}", env_var);
+            g_env.env = json!({"sshJumpHost": 22});
+            assert!(g_env.ssh_jump_host().is_err());
         }
     }
 
     #[test]
-    fn test_define_var() {
+    fn test_ssh_health_check_timeout() {
         let mut g_env = GlobalEnv::new(None);
-        g_env.env = json!({"init": "test"});
-        fn verify_sub(var: &str, in_val: &str, sub_val: &str, g_env: &mut GlobalEnv) {
-            let test_in = format!("@{} = {}", var, in_val);
-            let test_out = format!("@{} = {}", var, sub_val);
-            println!("in: {}", test_in);
-            let out = g_env.define_var(&test_in).unwrap();
-            assert_eq!(out, test_out, "Expected \"{}\", but got \"{}\"", test_out, out);
-            let check = g_env.evaluate(&format!(".{}", var)).unwrap();
-            let expect: Value = serde_json::from_str(sub_val).unwrap();
-            assert_eq!(check, expect, "Expected {:?}, got {:?}", expect, check);
+        {
+            g_env.env = json!({});
+            assert_eq!(g_env.ssh_health_check_timeout(), Duration::from_millis(DEFAULT_SSH_HEALTH_CHECK_TIMEOUT_MS));
         }
-        fn verify_non_sub(var: &str, val: &str, g_env: &mut GlobalEnv) {
-            let test_in = format!("@{} = {}", var, val);
-            println!("in: {}", test_in);
-            let out = g_env.define_var(&test_in).unwrap();
-            assert_eq!(out, test_in, "Expected \"{}\", but got \"{}\"", test_in, out);
-            let check = g_env.evaluate(&format!(".{}", var)).unwrap();
-            let expect: Value = serde_json::from_str(val).unwrap();
-            assert_eq!(check, expect, "Expected {:?}, got {:?}", expect, check);
+        {
+            g_env.session_health_check_timeout_ms = 100;
+            assert_eq!(g_env.ssh_health_check_timeout(), Duration::from_millis(100));
         }
-
         {
-            verify_non_sub("baseUrl", "\"https://10.0.0.20:5443/api/v1\"", &mut g_env);
+            g_env.env = json!({"sshHealthCheckTimeout": 2000});
+            assert_eq!(g_env.ssh_health_check_timeout(), Duration::from_millis(2000));
         }
         {
-            verify_non_sub("urls", "[\"https://10.0.0.20:5443/api/v1\", \"https://reqbin.com\"]", &mut g_env);
-            verify_non_sub("obj", "{\"a\": \"test\", \"b\": \"hello\"}", &mut g_env);
-            verify_non_sub("int1", "50", &mut g_env);
+            g_env.env = json!({"sshHealthCheckTimeout": "300"});
+            assert_eq!(g_env.ssh_health_check_timeout(), Duration::from_millis(300));
         }
+    }
+
+    #[test]
+    fn test_ssh_session_guard() {
+        let mut sessions = HashMap::new();
         {
-            fn check_env_file() -> Result<(), Box<dyn Error>> {
-                let file_str = fs::read_to_string(ENV_FILE)?;
-                assert!(file_str.contains("baseUrl"), "File should contain baseUrl");
-                assert!(!file_str.contains("fail"), "File should not contain fail");
-                Ok(())
-            }
-            if let Err(e) = check_env_file() {
-                panic!("Got error: {}", e.to_string());
-            }
+            let session = SshSessionGuard::new(&mut sessions, "host", String::from("session"));
+            assert_eq!(session.as_str(), "session");
         }
+        assert_eq!(sessions.get("host"), Some(&String::from("session")));
         {
-            let fail_err = g_env.define_var(&String::from("@fail = some invalid json"));
-            match fail_err {
-                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-                Err(e) => assert_eq!(
-                    e.to_string(),
-                    "expected value at line 1 column 1",
-                    "Got an incorrect error: \"{}\"",
-                    e.to_string()
-                ),
-            };
+            // a command panicking while the session is taken out
+            let session = sessions.remove("host").unwrap();
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _session = SshSessionGuard::new(&mut sessions, "host", session);
+                panic!("command failed");
+            }));
+            assert!(res.is_err());
+        }
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions.get("host"), Some(&String::from("session")));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct FakeSession {
+        id: u32,
+        alive: bool,
+    }
+
+    impl HealthCheck for FakeSession {
+        async fn is_alive(&self, _timeout: Duration) -> bool {
+            self.alive
         }
+    }
+
+    #[test]
+    fn test_take_or_connect() {
+        let rt = Runtime::new().unwrap();
+        let timeout = Duration::from_millis(100);
+        let mut sessions = HashMap::new();
         {
-            let fail_err = g_env.define_var(&String::from("@fail \"line invalid\""));
-            match fail_err {
-                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-                Err(e) => assert_eq!(
-                    e.to_string(),
-                    "cannot parse line: @fail \"line invalid\"",
-                    "Got an incorrect error: \"{}\"",
-                    e.to_string()
-                ),
-            };
+            let session = rt.block_on(take_or_connect(
+                &mut sessions, "host", timeout, async { Ok(FakeSession {id: 1, alive: true}) }
+            )).unwrap();
+            assert_eq!(session.id, 1);
         }
+        assert_eq!(sessions.get("host"), Some(&FakeSession {id: 1, alive: true}));
         {
-            verify_sub("testUrl", "\"{{.baseUrl}}/test\"", "\"https://10.0.0.20:5443/api/v1/test\"", &mut g_env);
-            verify_sub("url1", "\"{{.urls[0]}}\"", "\"https://10.0.0.20:5443/api/v1\"", &mut g_env);
-            verify_sub("objA", "\"{{.obj.a}}\"", "\"test\"", &mut g_env);
-            verify_sub("objB", "\"{{.baseUrl}}/{{.obj.b}}\"", "\"https://10.0.0.20:5443/api/v1/hello\"", &mut g_env);
+            // a live session is reused without connecting
+            let session = rt.block_on(take_or_connect(
+                &mut sessions, "host", timeout, async { Err(io_error("should not connect")) }
+            )).unwrap();
+            assert_eq!(session.id, 1);
         }
         {
-            let test_fail_sub = r#"@fail = "{{.dne}}""#;
-            let fail_err = g_env.define_var(&String::from(test_fail_sub));
-            match fail_err {
-                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-                Err(e) => assert_eq!(
-                    e.to_string(),
-                    "failed to get resource at .dne",
-                    "Got an incorrect error: \"{}\"",
-                    e.to_string()
-                ),
-            };
+            // a dead session is replaced in the cache
+            sessions.get_mut("host").unwrap().alive = false;
+            let session = rt.block_on(take_or_connect(
+                &mut sessions, "host", timeout, async { Ok(FakeSession {id: 2, alive: true}) }
+            )).unwrap();
+            assert_eq!(session.id, 2);
         }
-        clear_env_file();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions.get("host"), Some(&FakeSession {id: 2, alive: true}));
+        {
+            // if reconnecting fails, the dead session isn't kept
+            sessions.get_mut("host").unwrap().alive = false;
+            let res = rt.block_on(take_or_connect(
+                &mut sessions, "host", timeout, async { Err(io_error("could not connect")) }
+            ));
+            assert!(res.is_err());
+        }
+        assert!(sessions.is_empty());
     }
 
-//    #[test]
-//    fn test_make_request() {
-//        let mut g_env = GlobalEnv::new();
-//        g_env.env = json!({
-//            "baseUrl": "https://reqbin.com",
-//            "getXml": "echo/get/xml",
-//            "ct": "Content-Type",
-//            "json": "application/json"
-//        });
-//        {
-//            let req = Request {
-//                method: Method::Get,
-//                url: String::from("https://reqbin.com/echo/get/xml"),
-//                headers: vec![],
-//                multipart_forms: vec![],
-//                data: None,
-//            };
-//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap();
-//            let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
-//            let resp = resp.lines().last().unwrap();
-//            assert_eq!(resp, expected, "Expected {}, got {}", expected, resp);
-//            assert!(val.is_string(), "Response is XML so value should be string, got {:?}", val);
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Get,
-//                url: String::from("{{.baseUrl}}/{{.getXml}}"),
-//                headers: vec![],
-//                multipart_forms: vec![],
-//                data: None,
-//            };
-//            let (resp, _) = req.make_request(&mut g_env, false, false).unwrap();
-//            let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Response>  <ResponseCode>0</ResponseCode>  <ResponseMessage>Success</ResponseMessage></Response>";
-//            let resp = resp.lines().last().unwrap();
-//            assert_eq!(resp, expected, "Expected {}, got {}", expected, resp);
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Post,
-//                url: String::from("https://reqbin.com/echo/post/json"),
-//                headers: vec![String::from("{{.ct}}: {{.json}}")],
-//                multipart_forms: vec![],
-//                data: Some(String::from("{\"test\": \"value\"}")),
-//            };
-//            let (resp, val) = req.make_request(&mut g_env, false, false).unwrap();
-//            let expected = r#"{
-//  "success": "true"
-//}"#;
-//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
-//            assert_eq!(val["success"], json!("true"), "Got incorrect value: {:?}", val);
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Post,
-//                url: String::from("https://reqbin.com/echo/post/json"),
-//                headers: vec![String::from("{{.dne}}: application/json")],
-//                multipart_forms: vec![],
-//                data: Some(String::from("{\"test\": \"value\"}")),
-//            };
-//            let resp = req.make_request(&mut g_env, false, false);
-//            match resp {
-//                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-//                Err(e) => assert_eq!(
-//                    e.to_string(),
-//                    "failed to get resource at .dne",
-//                    "Got an incorrect error: \"{}\"",
-//                    e.to_string()
-//                ),
-//            };
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Get,
-//                url: String::from("http://aunchoeu"),
-//                headers: vec![],
-//                multipart_forms: vec![],
-//                data: None,
-//            };
-//            let resp = req.make_request(&mut g_env, false, false);
-//            match resp {
-//                Ok(ret) => panic!("Expected error, but got Ok with value {:?}", ret),
-//                Err(e) => assert_eq!(
-//                    e.to_string(),
-//                    "curl: (6) Couldn't resolve host 'aunchoeu'\n",
-//                    "Got an incorrect error: \"{}\"",
-//                    e.to_string()
-//                ),
-//            };
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Post,
-//                url: String::from("https://reqbin.com/echo/post/json"),
-//                headers: vec![String::from("{{.ct}}: {{.json}}")],
-//                multipart_forms: vec![],
-//                data: Some(String::from("{\"test\": \"value\"}")),
-//            };
-//            let (resp, val) = req.make_request(&mut g_env, true, false).unwrap();
-//            let expected = "curl -k --include https://reqbin.com/echo/post/json -X POST -H Content-Type: application/json -d {\"test\": \"value\"}";
-//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
-//            assert!(val.as_str().unwrap().is_empty(), "Expected val to be empty, got {}", val);
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Post,
-//                url: String::from("https://reqbin.com/echo/post/json"),
-//                headers: vec![String::from("{{.ct}}: {{.json}}")],
-//                multipart_forms: vec![],
-//                data: Some(String::from("{\"test\": \"value\"}")),
-//            };
-//            let (resp, val) = req.make_request(&mut g_env, true, true).unwrap();
-//            let expected = "curl -k -v https://reqbin.com/echo/post/json -X POST -H Content-Type: application/json -d {\"test\": \"value\"}";
-//            assert!(resp.contains(expected), "Expected {} in response, but response is {}", expected, resp);
-//            assert!(val.as_str().unwrap().is_empty(), "Expected val to be empty, got {}", val);
-//        }
-//        {
-//            let req = Request {
-//                method: Method::Post,
-//                url: String::from("https://reqbin.com/echo/post/json"),
-//                headers: vec![String::from("{{.ct}}: {{.json}}")],
-//                multipart_forms: vec![],
-//                data: Some(String::from("{\"test\": \"value\"}")),
-//            };
-//            let (resp, val) = req.make_request(&mut g_env, false, true).unwrap();
-//            let expected1 = "> POST /echo/post/json";
-//            let expected2 = "< Content-Type: application/json";
-//            let expected3 = Regex::new(r"(?m)^<.* 200 OK$").unwrap();
-//            assert!(resp.contains(expected1), "Expected {} in response, but response is {}", expected1, resp);
-//            assert!(resp.contains(expected2), "Expected {} in response, but response is {}", expected2, resp);
-//            assert!(expected3.is_match(&resp), "Expected {} in response, but response is {}", "< HTTP/_ 200 OK", resp);
-//            assert_eq!(val["success"], json!("true"), "Got incorrect value: {:?}", val);
-//        }
-//
-//        clear_env_file();
-//    }
+    #[test]
+    fn test_parse_ssh_port() {
+        assert_eq!(parse_ssh_port(&json!(22)).unwrap(), 22);
+        assert_eq!(parse_ssh_port(&json!(2222)).unwrap(), 2222);
+        assert_eq!(parse_ssh_port(&json!("2222")).unwrap(), 2222);
+        assert_eq!(parse_ssh_port(&json!(65535)).unwrap(), 65535);
+        assert!(parse_ssh_port(&json!(0)).is_err());
+        assert!(parse_ssh_port(&json!(65536)).is_err());
+        assert!(parse_ssh_port(&json!(-22)).is_err());
+        assert!(parse_ssh_port(&json!("70000")).is_err());
+        assert!(parse_ssh_port(&json!("ssh")).is_err());
+        assert!(parse_ssh_port(&json!("22a")).is_err());
+        assert!(parse_ssh_port(&json!(22.5)).is_err());
+        assert!(parse_ssh_port(&json!(null)).is_err());
+    }
 
     #[test]
-    fn test_response() {
-        {
-            let resp = Response::new(String::from("HTTP/1.1 100 Continue\n\nHTTP/1.1 200 OK\nContent-Type: application/json; charset=utf-8\n\n{\"test\": \"val\"}"), String::new(), false);
-            match resp {
-                Response::Json(h, v) => {
-                    println!("SUCCESS!\n\nHeaders:\n{h}\n\nValue:\n{:?}", v);
-                    assert!(true);
-                },
-                Response::NonJson(h, v) => {
-                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{v}");
-                    assert!(false, "Response was NonJson");
-                },
-                Response::NoSplit(v) => {
-                    println!("FAILED\n\nValue:\n{v}");
-                    assert!(false, "Response was NoSplit");
-                },
-            }
-        }
-        {
-            let resp = Response::new(String::from("Just some response w/ no headers"), String::new(), false);
-            match resp {
-                Response::Json(h, v) => {
-                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{:?}", v);
-                    assert!(false, "Response was Json");
-                },
-                Response::NonJson(h, v) => {
-                    println!("FAILED\n\nHeaders:\n{h}\n\nValue:\n{v}");
-                    assert!(false, "Response was NonJson");
-                },
-                Response::NoSplit(v) => {
-                    println!("SUCCESS!\n\nValue:\n{v}");
-                    assert!(true);
-                },
-            }
+    fn test_parse_status() {
+        let cases = [
+            ("HTTP/1.1 200 OK\nContent-Type: application/json", Some(200)),
+            ("HTTP/1.1 100 Continue\n\nHTTP/1.1 201 Created", Some(201)),
+            ("HTTP/2 404\ncontent-type: text/html", Some(404)),
+            ("* Connected to reqbin.com\n> GET / HTTP/1.1\n< HTTP/1.1 500 Internal Server Error", Some(500)),
+            ("", None),
+        ];
+        for (headers, expected) in cases {
+            let status = parse_status(headers);
+            assert_eq!(status, expected, "Expected {:?}, but got {:?}", expected, status);
         }
+
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({});
+        let mut fold_env = FoldEnv::new();
+        fold_env.status_variable = String::from("myStatus");
+        fold_env.capture_status(&mut g_env, Some(200)).unwrap();
+        let res = g_env.parse_selectors(&String::from("{{.myStatus}}")).unwrap();
+        assert_eq!(res, "200", "Expected 200, but got {}", res);
+        fold_env.capture_status(&mut g_env, None).unwrap();
+        assert_eq!(g_env.env["myStatus"], Value::Null, "Expected null, but got {:?}", g_env.env["myStatus"]);
+        clear_env_file();
     }
 }
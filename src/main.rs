@@ -57,34 +57,427 @@
 /// }
 /// ###}
 use std::env;
-use std::io;
+use std::fs;
+use std::io::{self, BufReader, IsTerminal};
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
 
 fn main() {
     if let Some(_) = env::args().find(|arg| &arg == &"-h" || &arg == &"--help") {
         usage();
         return;
     }
-    // get filename from args (returns option)
-    let mut args = env::args();
-    let _binname = args.next();
-    let filename = args.next();
+    // get filename from args, either directly or from the environment name
+    let mut filename = None;
+    let mut list_vars = false;
+    let mut update_fixtures = false;
+    let mut validate = false;
+    let mut validate_syntax = false;
+    let mut diff = false;
+    let mut no_color = false;
+    let mut dry_run = false;
+    let mut rate_limit_headers = false;
+    let mut retry_transient = false;
+    let mut colon_params = false;
+    let mut separator = None;
+    let mut batch = None;
+    let mut shared_env = false;
+    let mut batch_summary = false;
+    let mut reset_prefixes = Vec::new();
+    let mut output_file = None;
+    let mut secure = false;
+    let mut scope = vim_rest_client::EnvScope::Inherited;
+    let mut export_format = None;
+    let mut output_format = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--list-vars" {
+            list_vars = true;
+        } else if arg == "--export-env" {
+            export_format = args.next();
+            if export_format.is_none() {
+                usage();
+                process::exit(1);
+            }
+        } else if arg == "--format" {
+            output_format = args.next();
+            if output_format.is_none() {
+                usage();
+                process::exit(1);
+            }
+        } else if arg == "--isolate" {
+            scope = vim_rest_client::EnvScope::Isolated;
+        } else if arg == "--secure" {
+            secure = true;
+        } else if arg == "--output" {
+            output_file = args.next();
+            if output_file.is_none() {
+                usage();
+                process::exit(1);
+            }
+        } else if arg == "--dry-run" {
+            dry_run = true;
+        } else if arg == "--reset-prefix" {
+            match args.next() {
+                Some(prefix) => reset_prefixes.push(prefix),
+                None => {
+                    usage();
+                    process::exit(1);
+                },
+            }
+        } else if arg == "--batch" {
+            batch = args.next();
+            if batch.is_none() {
+                usage();
+                process::exit(1);
+            }
+        } else if arg == "--shared-env" {
+            shared_env = true;
+        } else if arg == "--batch-summary" {
+            batch_summary = true;
+        } else if arg == "--separator" {
+            separator = args.next();
+            match &separator {
+                Some(separator) if !vim_rest_client::is_valid_separator(separator) => {
+                    println!("invalid separator {}: it can't start with ###", separator);
+                    process::exit(1);
+                },
+                Some(_) => (),
+                None => {
+                    usage();
+                    process::exit(1);
+                },
+            }
+        } else if arg == "--colon-params" {
+            colon_params = true;
+        } else if arg == "--retry-transient" {
+            retry_transient = true;
+        } else if arg == "--rate-limit-headers" {
+            rate_limit_headers = true;
+        } else if arg == "--no-color" {
+            no_color = true;
+        } else if arg == "--diff" {
+            diff = true;
+        } else if arg == "--validate" {
+            validate = true;
+        } else if arg == "--validate-syntax" {
+            validate_syntax = true;
+        } else if arg == "--update-fixtures" {
+            update_fixtures = true;
+        } else if arg == "--env" {
+            match args.next() {
+                Some(name) => filename = Some(vim_rest_client::named_env_file(&name)),
+                None => {
+                    usage();
+                    process::exit(1);
+                },
+            }
+        } else {
+            filename = Some(arg);
+        }
+    }
+    let output_format = output_format.map(|format| {
+        vim_rest_client::OutputFormat::get_match(&format).unwrap_or_else(|| {
+            println!("unknown output format {}", format);
+            process::exit(1);
+        })
+    });
+    // --batch without --shared-env sets up a new env the same way for each file
+    let new_env = || {
+        let mut g_env = vim_rest_client::GlobalEnv::new(filename.as_deref());
+        // Vim pipes the output, so it is only colored in a terminal
+        g_env.color = io::stdout().is_terminal() && !no_color;
+        g_env.dry_run = dry_run;
+        g_env.rate_limit_headers = rate_limit_headers;
+        g_env.retry_transient = retry_transient;
+        g_env.use_colon_params = colon_params;
+        if let Some(separator) = &separator {
+            g_env.separator = separator.clone();
+        }
+        if let Some(format) = output_format {
+            g_env.output_format = format;
+        }
+        if secure {
+            g_env.insecure = false;
+        }
+        g_env.update_fixtures = update_fixtures;
+        g_env.scope = scope;
+        g_env
+    };
+    let mut g_env = new_env();
+    if list_vars {
+        for (var, val) in g_env.list_vars() {
+            println!("@{} = {}", var, val);
+        }
+        return;
+    }
+    if !reset_prefixes.is_empty() {
+        let prefixes = reset_prefixes.iter().map(String::as_str).collect::<Vec<&str>>();
+        if let Err(err) = g_env.reset_session_vars(&prefixes) {
+            println!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(format) = export_format {
+        let res = vim_rest_client::EnvExportFormat::get_match(&format)
+            .ok_or(format!("unknown export format {}", format))
+            .and_then(|format| g_env.export_env(format).map_err(|e| e.to_string()));
+        match res {
+            Ok(exported) => print!("{}", exported),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+    if validate {
+        let errors = g_env.validate();
+        for error in &errors {
+            println!("{}", error);
+        }
+        if !errors.is_empty() {
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(pattern) = batch {
+        // these only apply to a single run from STDIN
+        if output_file.is_some() || diff || validate_syntax {
+            usage();
+            process::exit(1);
+        }
+        let paths = match glob::glob(&pattern) {
+            Ok(paths) => paths.filter_map(Result::ok).collect::<Vec<PathBuf>>(),
+            Err(err) => {
+                println!("invalid batch pattern {}: {}", pattern, err);
+                process::exit(1);
+            },
+        };
+        if paths.is_empty() {
+            println!("no files matched {}", pattern);
+            process::exit(1);
+        }
+        let shared = if shared_env { Some(g_env) } else { None };
+        if !run_batch(&paths, new_env, shared, batch_summary) {
+            process::exit(1);
+        }
+        return;
+    }
     let stdin = io::stdin();
     let mut handle = stdin.lock();
-    let mut g_env = vim_rest_client::GlobalEnv::new(filename);
-    println!("{}", g_env.parse_input(&mut handle, false));
+    if validate_syntax {
+        let errors = g_env.validate_syntax(&mut handle);
+        for error in &errors {
+            println!("{}", error);
+        }
+        if !errors.is_empty() {
+            process::exit(1);
+        }
+        return;
+    }
+    let results = g_env.parse_input(&mut handle, false);
+    println!("{}", g_env.render(&results));
+    // the file is always written without colors
+    if let Some(path) = output_file.filter(|path| path != "-") {
+        let rendered = g_env.render_uncolored(&results);
+        if let Err(err) = fs::write(&path, format!("{}\n", rendered)) {
+            eprintln!("could not write output to {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+    if diff {
+        let mut changes = g_env.diff_env().into_iter().collect::<Vec<_>>();
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        println!();
+        println!("{} variable(s) changed", changes.len());
+        for (var, (old, new)) in changes {
+            match old {
+                Some(old) => println!("@{} = {} (was {})", var, new, old),
+                None => println!("@{} = {} (new)", var, new),
+            }
+        }
+    }
+}
+
+/// Runs each file in turn, with a new env for each one unless a shared env is
+/// given. A new env starts from the env as it was before the first file, so
+/// the variables a file saves to the env file aren't seen by the next.
+/// Prints the output of each file (unless only the summary is wanted)
+/// followed by a summary of the folds that succeeded and failed in each. A
+/// file that fails doesn't stop the others. Returns false if any fold failed
+/// or any file couldn't be read.
+fn run_batch(
+    paths: &[PathBuf],
+    new_env: impl Fn() -> vim_rest_client::GlobalEnv,
+    mut shared: Option<vim_rest_client::GlobalEnv>,
+    summary_only: bool,
+) -> bool {
+    // file, successful folds, failed folds, duration in milliseconds
+    let mut rows: Vec<(String, usize, usize, u128)> = Vec::new();
+    let mut ok = true;
+    let initial = shared.is_none().then(|| new_env().snapshot());
+    for path in paths {
+        let name = path.display().to_string();
+        let started = Instant::now();
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                println!("could not read {}: {}", name, err);
+                ok = false;
+                rows.push((name, 0, 0, 0));
+                continue;
+            },
+        };
+        let mut fresh;
+        let g_env = match shared.as_mut() {
+            Some(g_env) => g_env,
+            None => {
+                fresh = new_env();
+                if let Some(initial) = initial.as_ref().filter(|initial| **initial != fresh.snapshot()) {
+                    if let Err(err) = fresh.restore(initial.clone()) {
+                        println!("could not reset the env for {}: {}", name, err);
+                        ok = false;
+                        rows.push((name, 0, 0, 0));
+                        continue;
+                    }
+                }
+                &mut fresh
+            },
+        };
+        let results = g_env.parse_input(&mut BufReader::new(file), false);
+        let folds = results.iter().filter(|result| !result.is_text).count();
+        let errors = results.iter().filter(|result| !result.is_text && result.error).count();
+        ok = ok && errors == 0;
+        if !summary_only {
+            println!("{}", name);
+            println!("{}", g_env.render(&results));
+            println!();
+        }
+        rows.push((name, folds - errors, errors, started.elapsed().as_millis()));
+    }
+    let width = rows.iter()
+        .map(|(name, _, _, _)| name.len())
+        .chain(["TOTAL".len()])
+        .max()
+        .unwrap_or(0);
+    println!("{:<width$}  {:>7}  {:>5}  {:>8}", "FILE", "SUCCESS", "ERROR", "DURATION", width = width);
+    for (name, successes, errors, ms) in &rows {
+        println!("{:<width$}  {:>7}  {:>5}  {:>6}ms", name, successes, errors, ms, width = width);
+    }
+    println!(
+        "{:<width$}  {:>7}  {:>5}  {:>6}ms",
+        "TOTAL",
+        rows.iter().map(|row| row.1).sum::<usize>(),
+        rows.iter().map(|row| row.2).sum::<usize>(),
+        rows.iter().map(|row| row.3).sum::<u128>(),
+        width = width,
+    );
+    ok
 }
 
 fn usage() {
     println!("Usage of vim-rest-client:");
-    println!("STDIN | vim-rest-client [-h/--help] [file]");
+    println!("STDIN | vim-rest-client [-h/--help] [--list-vars] [--reset-prefix <prefix>] [--export-env <format>] [--validate] [--validate-syntax] [--format <format>] [--dry-run] [--rate-limit-headers] [--retry-transient] [--colon-params] [--separator <str>] [--batch <pattern>] [--shared-env] [--batch-summary] [--isolate] [--secure] [--output <file>] [--diff] [--no-color] [--update-fixtures] [--env <name>] [file]");
     println!();
     println!("\t--help/-h\t\tShow this usage message");
-    println!("\tfile\t\tThe name to use as the env file (default .env.json)");
+    println!("\t--list-vars\t\tPrint all set variables and exit");
+    println!("\t--reset-prefix <prefix>\tRemove the variables starting with the prefix from the env file and exit. Can be repeated");
+    println!("\t--export-env <format>\tPrint all set variables as toml, yaml, dotenv or json and exit");
+    println!("\t--validate\t\tCheck the variables against the schema in .env.schema.json and exit");
+    println!("\t--validate-syntax\tCheck the syntax of the input without running it, print the problems and exit");
+    println!("\t--format <format>\tRender the results as vim folds (default), markdown or json");
+    println!("\t--dry-run\t\tShow the curl command of every request instead of running it, as if they all had @debug");
+    println!("\t--rate-limit-headers\tShow the rate limit headers of every response, as if they all had @show-rate-limit");
+    println!("\t--retry-transient\tRetry curl when it can't resolve the host or connect, as if all requests had @retry-transient");
+    println!("\t--colon-params\t\tReplace :name path parameters in every URL with the variables, as if all requests had @colon-params");
+    println!("\t--batch <pattern>\tRun the files matching the glob pattern instead of STDIN, each with the env as it was before the first, and print a summary of their folds. Can't be used with --output, --diff or --validate-syntax");
+    println!("\t--shared-env\t\tWith --batch, run all the files with the same env, so the variables set by one are seen by the next");
+    println!("\t--batch-summary\t\tWith --batch, only print the summary, without the output of the files");
+    println!("\t--separator <str>\tUse the string as the line between the input and output of folds (default ##########), which can't start with ###");
+    println!("\t--isolate\t\tDiscard the variables set in nested folds when they end, except those with @export");
+    println!("\t--secure\t\tVerify certificates, instead of passing -k to curl, except for folds with @no-verify");
+    println!("\t--output <file>\t\tAlso write the output to the file, overwriting it. With - only stdout is used");
+    println!("\t--diff\t\t\tAfter running, print the variables that were added or changed, leaving out temporary ones");
+    println!("\t--no-color\t\tDon't color the fold statuses, even when printing to a terminal");
+    println!("\t--update-fixtures\tOverwrite the fixture files of @fixture with the current responses");
+    println!("\t--env <name>\t\tUse the named environment, with .env.<name>.json as the env file");
+    println!("\tfile\t\tThe name to use as the env file (default .env.json), looked for in the current directory and then its parents");
+    println!();
+    println!("Variables from ~/.rest-client/env.json are loaded first and overridden by the env file.");
+    println!("Requests are made over SSH if sshTo is set, using sshConfig, sshKey, sshPort and sshJumpHost if they are set.");
+    println!("Cached SSH sessions are reconnected if they don't answer within sshHealthCheckTimeout milliseconds (default 5000).");
+    println!("Requests without @proxy or @socks5 go through the SOCKS5 proxy in socks5Proxy if it is set.");
+    println!("The time taken by the last request, in milliseconds, is in the variable last_request_ms.");
+    println!("The condition of an if block or while loop is false if it is false, null (like a variable that isn't set), 0, \"\" or \"false\". Unlike in jq, 0 and \"\" count as false.");
     println!();
     println!("Flags:");
     println!("# @name <name>\t\t\tSaves output from the fold result into the environment under the given name.");
+    println!("# @accumulate <name>\t\tAppends the response to the array under the given name, which is emptied when a while loop starts.");
     println!("# @form <name>=<val>\t\tAdds multi-form data to the request. Equivalent to -F for curl.");
-    println!("# @debug\t\t\tDoes not execute fold but prints the curl command that would have executed.");
+    println!("# @form-type <name>=<type>\tSets the content type of the multi-form field, like -F '<name>=<val>;type=<type>' for curl.");
+    println!("# @form-encoded\t\tSends the body as application/x-www-form-urlencoded. Equivalent to --data-urlencode for curl.");
+    println!("# @graphql\t\t\tSends the body as a GraphQL query, wrapped in a JSON body with the query variables.");
+    println!("# @graphql-variables <json>\tSets the variables of the GraphQL query to the JSON object.");
+    println!("# @body-file <path>\t\tSends the contents of the file as the body. Over SSH, the file must be on the remote host.");
+    println!("# @body-template <path>\tUses the file as the request body, substituting any {{}} selectors in it.");
+    println!("# @debug\t\t\tDoes not execute fold but prints the curl command that would have executed, and a JSON body formatted below it.");
     println!("# @verbose\t\t\tEnables verbose logs.");
-    println!("# @options <flags>\t\tAdds arguments to the argument list for curl.");
+    println!("# @no-include\t\t\tGets only the response body, without the headers. Overrides @verbose.");
+    println!("# @options <flags>\t\tAdds arguments to the argument list for curl, which can be quoted like in a shell.");
+    println!("# @timeout <seconds>\t\tSets the maximum time allowed for the request. Equivalent to --max-time for curl.");
+    println!("# @connect-timeout <seconds>\tSets the maximum time allowed for connecting to the host. Equivalent to --connect-timeout for curl.");
+    println!("# @retry <count>\t\tRetries a failed request up to count times, waiting longer after each attempt.");
+    println!("# @colon-params\t\tReplaces the :name path parameters of the URL with the values of the variables, like {{.name}}.");
+    println!("# @separator <str>\t\tUses the string as the line between the input and output of the fold, instead of --separator.");
+    println!("# @retry-transient\t\tRetries curl up to 3 times, after 1s, 2s and 4s, if it can't resolve the host or connect.");
+    println!("# @delay <milliseconds>\t\tWaits before making the request, and before each retry.");
+    println!("# @rate-limit <rps>\t\tWaits between requests to make at most rps requests per second, counting the requests of earlier folds.");
+    println!("# @max-response-size <bytes>\tKeeps at most this many bytes of the response (default 1MB).");
+    println!("# @bearer <token>\t\tSends the token in an Authorization: Bearer header, unless there is an Authorization header already.");
+    println!("# @if-none-match <name>\tSends the ETag in the variable in an If-None-Match header, if it is set. A 304 response is noted as cached.");
+    println!("# @if-modified-since <name>\tSends the date in the variable in an If-Modified-Since header, if it is set. A 304 response is noted as cached.");
+    println!("# @bearer-refresh <url> <name>\tOn a 401, POSTs the env to the URL, saves the token it returns under the name and retries once.");
+    println!("# @bearer-refresh-body <jq>\tUses the jq expression on the env as the body of the @bearer-refresh request.");
+    println!("# @proxy <url>\t\t\tMakes the request through the proxy. Equivalent to -x for curl.");
+    println!("# @socks5 <host:port>\t\tMakes the request through the SOCKS5 proxy, which can't be used with @proxy. Equivalent to --socks5 for curl.");
+    println!("# @interface <name>\t\tMakes the request from the network interface. Equivalent to --interface for curl.");
+    println!("# @dns-server <address>\tResolves the host with the DNS server. Can be repeated. Equivalent to --dns-servers for curl.");
+    println!("# @cert <path>\t\t\tUses the client certificate for TLS. Equivalent to --cert for curl.");
+    println!("# @key <path>\t\t\tUses the private key for TLS. Equivalent to --key for curl.");
+    println!("# @cookie-jar <path>\t\tReads cookies from and saves cookies to the file. Equivalent to -b and -c for curl.");
+    println!("# @output <path>\t\tSaves the response body to the file instead of showing it.");
+    println!("# @save-headers <path>\t\tSaves the response headers (status line and header lines) to the file.");
+    println!("# @log <path>\t\t\tAppends the request and response (truncated to 1KB) to the file, as a line of JSON.");
+    println!("# @follow\t\t\tFollows redirects. Equivalent to -L for curl.");
+    println!("# @max-redirects <n>\t\tFollows at most n redirects. Equivalent to -L --max-redirs for curl.");
+    println!("# @no-verify\t\t\tSkips certificate verification even with --secure. Equivalent to -k for curl.");
+    println!("# @verify\t\t\tVerifies certificates, which is otherwise skipped without --secure.");
+    println!("# @http2\t\t\tUses HTTP/2. Equivalent to --http2 for curl.");
+    println!("# @http3\t\t\tUses HTTP/3. Equivalent to --http3 for curl. If @http2 is also set, HTTP/2 is used.");
+    println!("# @http-version <ver>\t\tUses HTTP 1.0, 1.1, 2 or 3, with the curl flag for it. Overrides @http2 and @http3.");
+    println!("# @jq <filter>\t\t\tFilters the response through jq before it is shown and saved. Filters are applied in order.");
+    println!("# @assert <expr>\t\tMarks the fold as an error if the jq expression is not true for the response.");
+    println!("# @capture <regex> <name>\tSaves the first group matched by the regex in the response body under the given name.");
+    println!("# @capture-all <jq>\t\tSaves each key of the object returned by the jq expression on the response as a variable.");
+    println!("# @vars-from-response <json>\tSaves the result of each jq selector in the JSON object on the response under its key, which can use {{}}.");
+    println!("# @paginate <jq>\t\tRequests the next page at the URL the jq expression returns on each page, up to 100 pages. @name gets the array of pages. With link, follows the rel=\"next\" URL of the Link header.");
+    println!("# @header <header> <name>\tSaves the value of the response header under the given name.");
+    println!("# @extract-cookie <cookie> <name>\tSaves the value of the cookie set by the response under the given name.");
+    println!("# @status <name>\t\tSaves the response status code under the given name.");
+    println!("# @show-rate-limit\t\tShows X-RateLimit-Remaining and X-RateLimit-Reset, saved under __rate_limit_remaining and __rate_limit_reset.");
+    println!("# @stats\t\t\tShows the size of the response body, its number of lines and JSON keys, and the number of headers.");
+    println!("# @max-iter <n>\t\t\tIn a while loop, stops the loop with an error after n iterations.");
+    println!("# @break [label]\t\tIn a while loop, ends the innermost loop, or the one whose endwhile has the label, after the fold.");
+    println!("# @fixture <path>\t\tCompares the response body to the file, showing differing lines. Creates the file if it doesn't exist.");
+    println!("# @schema <path>\t\tValidates the response body against the JSON Schema file, listing the errors.");
+    println!("# @export <name>\t\tKeeps the variable after the nested fold ends, when running with --isolate.");
+    println!("# @sensitive <name>\t\tHides the value of the variable in the output. The name can be a glob like *_token. Values under 4 characters are only hidden where they are set.");
+    println!("# @strict\t\t\tTreats captures that don't match the response as errors.");
+    println!("# @skip\t\t\t\tSkips the rest of the fold.");
+    println!("# @skip-if <expr>\t\tSkips the rest of the fold if the jq expression is true for the env.");
+    println!("# @import <path>\t\tLoads the variables in the JSON or dotenv file for the rest of the run, without saving them to the env file.");
+    println!("# @import-dotenv <path>\tLike @import for a dotenv file, with all values as strings and $VAR expanded from the OS environment.");
 }
@@ -0,0 +1,370 @@
+/// process_for module
+/// Handles for loop for vim-rest-client. A for block is defined thusly:
+///
+/// ###{ for item in {{.items}}
+/// <requests, variable assignments, folds using {{.item}}>
+/// ###} endfor
+///
+/// The array selector is evaluated once, before the first loop. For each
+/// element the loop variable is set (as a temporary variable, so it is not
+/// written to the env file) and the block is run. After the loop, a variable
+/// with the same name is put back as it was.
+///
+/// The output shown for a for loop should be the result of the final loop.
+/// Like in a while loop, {{.loop.index}} and {{.loop.count}} are set in the
+/// block.
+///
+/// Supports nested for loops.
+
+use std::io::BufRead;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{DEFAULT_SEPARATOR, FOLD_END, FOLD_START, GlobalEnv, RestClientError, io_error, marker_regex, render_results};
+use crate::process_while::{LOOP_VAR, compile_loop_return, gen_loop_output, restore_var, save_var, set_loop_vars};
+
+// patterns following FOLD_START or FOLD_END, see marker_regex; the array
+// expression is a single {{}} selector, with nothing after it
+pub const FOR_START: &str = r"\s*for\s+(\w+)\s+in\s+(\{\{[^{}]+\}\})\s*$";
+pub(crate) const FOR_END: &str = r"\s*endfor";
+const ERROR: &str = r"\(ERROR(, \d+ms)?\)$";
+
+pub struct For {
+    item_var: String,       // loop variable set to each element
+    array_expr: String,     // array selector, should be valid jq selector in {{}}
+    block: String,          // the entire for block saved to allow looping
+    separator: String,      // separator between the input and output of the folds
+    pub output: String,     // the output of the last run loop, which is returned
+    pub error: bool,        // error state of the for loop
+}
+
+impl For {
+    fn new() -> For {
+        For {
+            item_var: String::new(),
+            array_expr: String::new(),
+            block: String::new(),
+            separator: String::from(DEFAULT_SEPARATOR),
+            output: String::new(),
+            error: false,
+        }
+    }
+
+    /// Builds the for loop from the input reader, along with the first line
+    /// which was already read from the reader by parse_input.
+    /// After building the for loop, executes it and returns the struct to
+    /// allow the caller to get the error state and output.
+    pub fn parse_for(
+        first_line: &String,
+        input: &mut impl BufRead,
+        g_env: &mut GlobalEnv,
+    ) -> For {
+        let mut f = For::new();
+        f.separator = g_env.separator.clone();
+        let mut num_loops = 1;
+        let start_re = marker_regex(FOLD_START, FOR_START);
+        let end_re = marker_regex(FOLD_END, FOR_END);
+        if let Some(caps) = start_re.captures(first_line) {
+            f.item_var = String::from(&caps[1]);
+            f.array_expr = String::from(&caps[2]);
+        }
+        f.block.push_str(first_line);
+        f.block.push('\n');
+        if f.array_expr.is_empty() {
+            f.error = true;
+            f.gen_default_output(String::from("Could not get for selector"));
+            return f;
+        }
+        loop {
+            let mut line = String::new();
+            let res = input.read_line(&mut line);
+            line = String::from((&line).trim_end());
+            match res {
+                Ok(0) => {
+                    break;
+                },
+                Ok(_) => (),
+                Err(e) => {
+                    f.error = true;
+                    f.gen_default_output(e.to_string());
+                    return f;
+                },
+            };
+            f.block.push_str(&line);
+            f.block.push('\n');
+            if start_re.is_match(&line) {
+                num_loops += 1;
+            }
+            if end_re.is_match(&line) {
+                num_loops -= 1;
+            }
+            if num_loops == 0 {
+                break;
+            }
+        }
+        f.block = String::from(f.block.trim_end());
+        f.run(g_env);
+        f
+    }
+
+    /// Run for loop: call parse_input on block for each element of the array
+    fn run(&mut self, g_env: &mut GlobalEnv) {
+        let error_re = Regex::new(ERROR).unwrap();
+        let items = match self.get_items(g_env) {
+            Ok(items) => items,
+            Err(err) => {
+                self.error = true;
+                self.gen_default_output(err.to_string());
+                return;
+            },
+        };
+        let saved_loop_vars = save_var(g_env, LOOP_VAR);
+        let saved_var = save_var(g_env, &self.item_var);
+        for (index, item) in items.iter().enumerate() {
+            set_loop_vars(g_env, index);
+            if let Err(err) = g_env.set_var(&self.item_var, item, false) {
+                self.error = true;
+                self.gen_default_output(err.to_string());
+                break;
+            }
+            // call parse_input with ignore_first_loop true to avoid infinite loop
+            self.output = render_results(&g_env.parse_input(&mut self.block.clone().as_bytes(), true));
+            let first_line = self.output.lines().next().unwrap_or("");
+            self.error = error_re.is_match(first_line);
+            // a @break in the loop is for the while loop around it
+            if self.error || g_env.break_label.is_some() {
+                break;
+            }
+        }
+        restore_var(g_env, saved_var);
+        restore_var(g_env, saved_loop_vars);
+        if self.output.is_empty() {
+            self.gen_default_output(String::new());
+        }
+    }
+
+    /// Return the block (input) and output of last loop, with proper formatting.
+    pub fn compile_return(&mut self) -> (String, String) {
+        compile_loop_return(&self.output, &self.separator, self.error)
+    }
+
+    /// Evaluates the selector for the for loop, which must be an array.
+    fn get_items(&self, g_env: &mut GlobalEnv) -> Result<Vec<Value>, RestClientError> {
+        let selector = self.array_expr
+            .trim_start_matches("{{")
+            .trim_end_matches("}}");
+        let selector = g_env.parse_selectors(&String::from(selector))?;
+        match g_env.evaluate(&selector)? {
+            Value::Array(items) => Ok(items),
+            _ => Err(io_error(&format!("{} is not an array", selector)))?,
+        }
+    }
+
+    /// Creates an output like parse_input, in the case where parse_input wasn't
+    /// able to run and it has to be simulated.
+    fn gen_default_output(&mut self, output: String) {
+        self.output = gen_loop_output(&self.block, &self.separator, self.error, output);
+    }
+}
+
+
+///////////////////////////////////////////////
+/// Unit tests
+///////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use serde_json::json;
+    use crate::ENV_FILE;
+    use crate::tests::strip_durations;
+
+    fn clear_env_file() {
+        if let Err(_) = fs::remove_file(ENV_FILE) {
+            println!("file doesn't exist")
+        } else {
+            println!("file deleted")
+        }
+    }
+
+    #[test]
+    fn test_parse_for() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({
+                "items": [],
+                "sum": 0
+            });
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@sum = {{.sum + .item}}
+###} endfor"#);
+            let f = For::parse_for(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ for item in {{.items}} executed (SUCCESS)
+@sum = {{.sum + .item}}
+########## for item in {{.items}} RESULT
+###} endfor"#);
+            assert_eq!(
+                strip_durations(&f.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                f.output
+            );
+            assert!(!f.error);
+        }
+        {
+            g_env.env = json!({
+                "items": [3],
+                "sum": 0
+            });
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@sum = {{.sum + .item}}
+###} endfor"#);
+            let f = For::parse_for(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ for item in {{.items}} executed (SUCCESS)
+@sum = {{.sum + .item}}
+########## for item in {{.items}} RESULT
+@sum = 3
+###} endfor"#);
+            assert_eq!(
+                strip_durations(&f.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                f.output
+            );
+            assert!(!f.error);
+        }
+        {
+            g_env.env = json!({
+                "items": [1, 2, 3],
+                "sum": 0
+            });
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@sum = {{.sum + .item}}
+###} endfor"#);
+            let f = For::parse_for(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ for item in {{.items}} executed (SUCCESS)
+@sum = {{.sum + .item}}
+########## for item in {{.items}} RESULT
+@sum = 6
+###} endfor"#);
+            assert_eq!(
+                strip_durations(&f.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                f.output
+            );
+            assert!(!f.error);
+        }
+        {
+            g_env.env = json!({
+                "items": [1, 2, 3]
+            });
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@sum = {.item}
+###} endfor"#);
+            let f = For::parse_for(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ for item in {{.items}} executed (ERROR)
+@sum = {.item}
+########## for item in {{.items}} ERROR
+@sum: line 1: key must be a string at line 1 column 2
+###} endfor"#);
+            assert_eq!(
+                strip_durations(&f.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                f.output
+            );
+            assert!(f.error);
+        }
+        {
+            g_env.env = json!({
+                "items": "not an array"
+            });
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@sum = {{.item}}
+###} endfor"#);
+            let f = For::parse_for(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ for item in {{.items}} executed (ERROR)
+@sum = {{.item}}
+########## for item in {{.items}} ERROR
+.items is not an array
+###} endfor"#);
+            assert_eq!(
+                strip_durations(&f.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                f.output
+            );
+            assert!(f.error);
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_for_start() {
+        let start_re = marker_regex(FOLD_START, FOR_START);
+        let caps = start_re.captures("###{ for user in {{.users | map(.id)}}  ").unwrap();
+        assert_eq!(&caps[1], "user");
+        assert_eq!(&caps[2], "{{.users | map(.id)}}");
+        assert!(!start_re.is_match("###{ for user in {{.users}} and more"));
+        assert!(!start_re.is_match("###{ for user in {{.users}} {{.more}}"));
+        assert!(!start_re.is_match("###{ for user in {{{.users}}}"));
+        assert!(!start_re.is_match("###{ for each user"));
+    }
+
+    #[test]
+    fn test_loop_var_restored() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"items": [1, 2]});
+        {
+            g_env.set_var(&String::from("item"), &json!("kept"), true).unwrap();
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@last = {{.item}}
+###} endfor"#);
+            let f = For::parse_for(&first_line, &mut input.as_bytes(), &mut g_env);
+            assert!(!f.error, "Got:\n{}", f.output);
+            assert_eq!(g_env.env["last"], json!(2));
+            assert_eq!(g_env.env["item"], json!("kept"));
+            assert!(!g_env.tmp_vars.contains("item"), "item should still be persisted");
+            let file_str = fs::read_to_string(ENV_FILE).unwrap();
+            assert!(file_str.contains("\"kept\""), "Got:\n{}", file_str);
+        }
+        {
+            g_env.define_var(&String::from("@unset item")).unwrap();
+            let first_line = String::from("###{ for item in {{.items}}");
+            let input = String::from(r#"@last = {{.item}}
+###} endfor"#);
+            let f = For::parse_for(&first_line, &mut input.as_bytes(), &mut g_env);
+            assert!(!f.error, "Got:\n{}", f.output);
+            assert!(g_env.env.get("item").is_none(), "item should be removed");
+        }
+
+        clear_env_file();
+    }
+}
@@ -0,0 +1,406 @@
+/// process_if module
+/// Handles conditional blocks for vim-rest-client. An if block is defined
+/// thusly:
+///
+/// ###{ if {{.status == 200}}
+/// <requests, variable assignments, folds>
+/// ###{ else
+/// <requests, variable assignments, folds>
+/// ###} endif
+///
+/// The else branch is optional. The condition is evaluated once; if it is true
+/// the if branch is run, otherwise the else branch is run, if there is one.
+/// As in a while loop, false, null, 0, "" and "false" are false.
+/// The output shows which branch was taken, followed by the output of that
+/// branch.
+///
+/// Supports nested if blocks.
+
+use std::io::BufRead;
+use regex::Regex;
+
+use crate::{DEFAULT_SEPARATOR, FOLD_END, FOLD_START, GlobalEnv, RestClientError, marker_regex, render_results};
+use crate::process_while::{compile_loop_return, eval_condition, gen_loop_output};
+
+// patterns following FOLD_START or FOLD_END, see marker_regex
+pub const IF_START: &str = r"\s*if\s*(\{\{.*\}\})";
+pub(crate) const ELSE: &str = r"\s*else\s*$";
+pub(crate) const IF_END: &str = r"\s*endif";
+const ERROR: &str = r"\(ERROR(, \d+ms)?\)$";
+
+pub struct IfBlock {
+    condition: String,              // if condition, should be valid jq selector
+    block: String,                  // the entire if block, shown as the input
+    first_line: String,             // the if line
+    if_branch: String,              // lines run if the condition is true
+    else_line: String,              // the else line, if there is an else branch
+    else_branch: Option<String>,    // lines run if the condition is false
+    last_line: String,              // the endif line
+    separator: String,              // separator between the input and output of the folds
+    pub output: String,             // the output of the branch taken, which is returned
+    pub error: bool,                // error state of the if block
+}
+
+impl IfBlock {
+    fn new() -> IfBlock {
+        IfBlock {
+            condition: String::new(),
+            block: String::new(),
+            first_line: String::new(),
+            if_branch: String::new(),
+            else_line: String::new(),
+            else_branch: None,
+            last_line: String::new(),
+            separator: String::from(DEFAULT_SEPARATOR),
+            output: String::new(),
+            error: false,
+        }
+    }
+
+    /// Builds the if block from the input reader, along with the first line
+    /// which was already read from the reader by parse_input. The output of a
+    /// previous run is dropped.
+    /// After building the if block, executes it and returns the struct to allow
+    /// the caller to get the error state and output.
+    pub fn parse_if(
+        first_line: &String,
+        input: &mut impl BufRead,
+        g_env: &mut GlobalEnv,
+    ) -> IfBlock {
+        let mut b = IfBlock::new();
+        b.separator = g_env.separator.clone();
+        let mut depth = 1;
+        let mut old_output_started = false;
+        let start_re = marker_regex(FOLD_START, IF_START);
+        let else_re = marker_regex(FOLD_START, ELSE);
+        let end_re = marker_regex(FOLD_END, IF_END);
+        start_re.captures(first_line)
+            .and_then(|caps| caps.get(1))
+            .and_then(|condition| {
+                b.condition = String::from(condition.as_str());
+                Some(())
+            });
+        b.first_line = first_line.clone();
+        b.build_block();
+        if b.condition.is_empty() {
+            b.error = true;
+            b.gen_default_output(String::from("Could not get if condition"));
+            return b;
+        }
+        loop {
+            let mut line = String::new();
+            let res = input.read_line(&mut line);
+            line = String::from((&line).trim_end());
+            match res {
+                Ok(0) => {
+                    break;
+                },
+                Ok(_) => (),
+                Err(e) => {
+                    b.error = true;
+                    b.gen_default_output(e.to_string());
+                    return b;
+                },
+            };
+            if start_re.is_match(&line) {
+                depth += 1;
+            }
+            if end_re.is_match(&line) {
+                depth -= 1;
+            }
+            if depth == 0 {
+                b.last_line = line;
+                break;
+            }
+            if old_output_started {
+                continue;
+            }
+            if depth == 1 && line.starts_with(&b.separator) {
+                old_output_started = true;
+                continue;
+            }
+            if depth == 1 && else_re.is_match(&line) {
+                b.else_line = line;
+                b.else_branch = Some(String::new());
+                continue;
+            }
+            let branch = b.else_branch.as_mut().unwrap_or(&mut b.if_branch);
+            branch.push_str(&line);
+            branch.push('\n');
+        }
+        b.build_block();
+        b.run(g_env);
+        b
+    }
+
+    /// Puts the if block back together from its lines and branches
+    fn build_block(&mut self) {
+        self.block = format!("{}\n{}", self.first_line, self.if_branch);
+        if let Some(else_branch) = &self.else_branch {
+            self.block.push_str(&format!("{}\n{}", self.else_line, else_branch));
+        }
+        self.block.push_str(&self.last_line);
+        self.block = String::from(self.block.trim_end());
+    }
+
+    /// Run the if block: evaluate the condition and call parse_input on the
+    /// branch taken
+    fn run(&mut self, g_env: &mut GlobalEnv) {
+        let branch = match self.check_condition(g_env) {
+            Ok(true) => Some(("if", self.if_branch.clone())),
+            Ok(false) => self.else_branch.clone().map(|branch| ("else", branch)),
+            Err(err) => {
+                self.error = true;
+                self.gen_default_output(err.to_string());
+                return;
+            },
+        };
+        let (name, branch) = match branch {
+            Some(branch) => branch,
+            None => {
+                self.gen_default_output(String::from("no branch taken"));
+                return;
+            },
+        };
+        // run the branch as a plain fold, and keep only its output
+        let res = render_results(&g_env.parse_input(&mut format!("{}\n{}{}", FOLD_START, branch, FOLD_END).as_bytes(), false));
+        let error_re = Regex::new(ERROR).unwrap();
+        self.error = error_re.is_match(res.lines().next().unwrap_or(""));
+        let lines = res.lines().collect::<Vec<&str>>();
+        let mut output = format!("{} branch taken", name);
+        if let Some(divider) = lines.iter().position(|line| line.starts_with(&self.separator)) {
+            // the input of the branch now has the executed markers of its folds
+            let mut input = String::new();
+            for line in &lines[1..divider] {
+                input.push_str(&format!("{}\n", line));
+            }
+            if name == "if" {
+                self.if_branch = input;
+            } else {
+                self.else_branch = Some(input);
+            }
+            self.build_block();
+            for line in &lines[divider + 1..lines.len() - 1] {
+                output.push('\n');
+                output.push_str(line);
+            }
+        }
+        self.gen_default_output(output);
+    }
+
+    /// Return the block (input) and output of the branch taken, with proper
+    /// formatting.
+    pub fn compile_return(&mut self) -> (String, String) {
+        compile_loop_return(&self.output, &self.separator, self.error)
+    }
+
+    /// Evaluates the condition for the if block, which is true unless the jq
+    /// syntax returns false, null, 0, "" or "false", like a while condition.
+    fn check_condition(&self, g_env: &mut GlobalEnv) -> Result<bool, RestClientError> {
+        eval_condition(g_env, &self.condition)
+    }
+
+    /// Creates an output like parse_input, with the output given.
+    fn gen_default_output(&mut self, output: String) {
+        self.output = gen_loop_output(&self.block, &self.separator, self.error, output);
+    }
+}
+
+
+///////////////////////////////////////////////
+/// Unit tests
+///////////////////////////////////////////////
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use serde_json::json;
+    use crate::ENV_FILE;
+    use crate::tests::strip_durations;
+
+    fn clear_env_file() {
+        if let Err(_) = fs::remove_file(ENV_FILE) {
+            println!("file doesn't exist")
+        } else {
+            println!("file deleted")
+        }
+    }
+
+    #[test]
+    fn test_parse_if() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({
+                "i": 1
+            });
+            let first_line = String::from("###{ if {{.i > 0}}");
+            let input = String::from(r#"@res = "positive"
+###{ else
+@res = "not positive"
+###} endif"#);
+            let b = IfBlock::parse_if(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ if {{.i > 0}} executed (SUCCESS)
+@res = "positive"
+###{ else
+@res = "not positive"
+########## if {{.i > 0}} RESULT
+if branch taken
+@res = "positive"
+###} endif"#);
+            assert_eq!(
+                strip_durations(&b.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                b.output
+            );
+            assert!(!b.error);
+        }
+        {
+            g_env.env = json!({
+                "i": 0
+            });
+            let first_line = String::from("###{ if {{.i > 0}}");
+            let input = String::from(r#"@res = "positive"
+###{ else
+@res = "not positive"
+###} endif"#);
+            let b = IfBlock::parse_if(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ if {{.i > 0}} executed (SUCCESS)
+@res = "positive"
+###{ else
+@res = "not positive"
+########## if {{.i > 0}} RESULT
+else branch taken
+@res = "not positive"
+###} endif"#);
+            assert_eq!(
+                strip_durations(&b.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                b.output
+            );
+            assert!(!b.error);
+        }
+        {
+            g_env.env = json!({
+                "i": 0
+            });
+            let first_line = String::from("###{ if {{.i > 0}}");
+            let input = String::from(r#"@res = "positive"
+###} endif"#);
+            let b = IfBlock::parse_if(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ if {{.i > 0}} executed (SUCCESS)
+@res = "positive"
+########## if {{.i > 0}} RESULT
+no branch taken
+###} endif"#);
+            assert_eq!(
+                strip_durations(&b.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                b.output
+            );
+            assert!(!b.error);
+        }
+        {
+            g_env.env = json!({});
+            let first_line = String::from("###{ if {{.items[{{.dne}}]}}");
+            let input = String::from(r#"@res = "positive"
+###} endif"#);
+            let b = IfBlock::parse_if(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ if {{.items[{{.dne}}]}} executed (ERROR)
+@res = "positive"
+########## if {{.items[{{.dne}}]}} ERROR
+failed to get resource at .dne
+###} endif"#);
+            assert_eq!(
+                strip_durations(&b.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                b.output
+            );
+            assert!(b.error);
+        }
+        {
+            // like in a while loop, a variable that isn't set and 0 are false
+            for condition in ["{{.dne}}", "{{0}}"] {
+                g_env.env = json!({});
+                let first_line = format!("###{{ if {}", condition);
+                let input = String::from(r#"@res = "positive"
+###{ else
+@res = "negative"
+###} endif"#);
+                let b = IfBlock::parse_if(&first_line, &mut input.as_bytes(), &mut g_env);
+                assert!(!b.error, "Got:\n{}", b.output);
+                assert_eq!(g_env.env["res"], json!("negative"), "Condition {}", condition);
+            }
+        }
+        {
+            g_env.env = json!({
+                "i": 1,
+                "j": 0
+            });
+            let first_line = String::from("###{ if {{.i > 0}} executed (SUCCESS)");
+            let input = String::from(r#"###{ if {{.j > 0}}
+@res = "both"
+###{ else
+@res = "only i"
+###} endif
+###{ else
+@res = "neither"
+########## if {{.i > 0}} RESULT
+old output
+###} endif"#);
+            let b = IfBlock::parse_if(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ if {{.i > 0}} executed (SUCCESS)
+###{ if {{.j > 0}} executed (SUCCESS)
+@res = "both"
+###{ else
+@res = "only i"
+###} endif
+###{ else
+@res = "neither"
+########## if {{.i > 0}} RESULT
+if branch taken
+### if {{.j > 0}} RESULT
+else branch taken
+@res = "only i"
+### endif
+###} endif"#);
+            assert_eq!(
+                strip_durations(&b.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                b.output
+            );
+            assert!(!b.error);
+        }
+
+        clear_env_file();
+    }
+}
@@ -6,24 +6,47 @@
 /// ###} endwhile
 ///
 /// The output shown for a while loop should be the result of the final loop.
+/// A # @max-iter <n> line in the block limits the loop to n iterations, after
+/// which the loop stops with an error.
+/// Folds in the loop can use # @accumulate <name> to collect the response of
+/// every iteration in an array, which is emptied when the loop starts, unless
+/// a loop around it is already collecting into it.
+/// Inside the loop (and in its condition) {{.loop.index}} is the 0-based
+/// iteration and {{.loop.count}} the 1-based one. After the loop, a variable
+/// named loop is put back as it was, or removed.
+/// A fold with # @break ends the innermost loop once the fold is done, skipping
+/// the folds after it. The endwhile line can be given a label, as in
+/// ###} endwhile outer, for # @break outer to end that loop from a nested one.
 /// vim-rest-client creates a single SSH session for all connections to the same
 /// destination, so if a while loop makes SSH requests, it will reuse that
 /// session for all loops.
 ///
-/// Supports nested while loops.
+/// Supports nested while loops, up to WHILE_MAX_DEPTH levels, counting the
+/// loops the block is nested in.
 
 use std::io::BufRead;
 use regex::Regex;
+use serde_json::{Value, json};
 
-use crate::{GlobalEnv};
+use crate::{DEFAULT_SEPARATOR, FOLD_END, FOLD_START, GlobalEnv, RestClientError, STATUSES, marker_regex, render_results};
 
-pub const WHILE_START: &str = r"^###\{\s*while\s*(\{\{.*\}\})";
-const WHILE_END: &str = r"^###\}\s*endwhile";
-const ERROR: &str = r"\(ERROR\)$";
+// patterns following FOLD_START or FOLD_END, see marker_regex
+pub const WHILE_START: &str = r"\s*while\s*(\{\{.*\}\})";
+pub(crate) const WHILE_END: &str = r"\s*endwhile(?:\s+(\w+))?";
+const MAX_ITER: &str = r"^#\s*@max-iter\s*(\d+)";
+pub const ACCUMULATE: &str = r"^#\s*@accumulate\s*([^ ]+)";
+pub const BREAK: &str = r"^#\s*@break(?:\s+(\w+))?\s*$";
+pub(crate) const LOOP_VAR: &str = "loop";
+pub const WHILE_MAX_DEPTH: usize = 16;
+const ERROR: &str = r"\(ERROR(, \d+ms)?\)$";
 
 pub struct While {
     condition: String,      // while loop condition, should be valid jq selector
     block: String,          // the entire while block saved to allow looping
+    max_iter: Option<usize>, // max number of iterations, unbounded if not set
+    accumulate_vars: Vec<String>, // array variables the loop's folds append to
+    label: Option<String>,  // label after endwhile, which @break can name
+    separator: String,      // separator between the input and output of the folds
     pub output: String,     // the output of the last run loop, which is returned
     pub error: bool,        // error state of the while loop
 }
@@ -33,6 +56,10 @@ impl While {
         While {
             condition: String::new(),
             block: String::new(),
+            max_iter: None,
+            accumulate_vars: Vec::new(),
+            label: None,
+            separator: String::from(DEFAULT_SEPARATOR),
             output: String::new(),
             error: false,
         }
@@ -48,9 +75,18 @@ impl While {
         g_env: &mut GlobalEnv,
     ) -> While {
         let mut w = While::new();
+        w.separator = g_env.separator.clone();
         let mut num_loops = 1;
-        let start_re = Regex::new(WHILE_START).unwrap();
-        let end_re = Regex::new(WHILE_END).unwrap();
+        // deepest nesting in the block, counting this loop
+        let mut max_depth = num_loops;
+        let start_re = marker_regex(FOLD_START, WHILE_START);
+        let end_re = marker_regex(FOLD_END, WHILE_END);
+        let max_iter_re = Regex::new(MAX_ITER).unwrap();
+        let accumulate_re = Regex::new(ACCUMULATE).unwrap();
+        let break_re = Regex::new(BREAK).unwrap();
+        // labels named by @break in each loop being read, which are dropped
+        // when the loop with that label ends
+        let mut break_labels: Vec<Vec<String>> = vec![Vec::new()];
         start_re.captures(first_line)
             .and_then(|caps| caps.get(1))
             .and_then(|condition| {
@@ -83,15 +119,53 @@ impl While {
             w.block.push('\n');
             if start_re.is_match(&line) {
                 num_loops += 1;
+                max_depth = max_depth.max(num_loops);
+                break_labels.push(Vec::new());
             }
-            if end_re.is_match(&line) {
+            if let Some(caps) = end_re.captures(&line) {
                 num_loops -= 1;
+                let label = caps.get(1).map(|label| String::from(label.as_str()));
+                let mut labels = break_labels.pop().unwrap_or_default();
+                labels.retain(|l| Some(l) != label.as_ref());
+                if num_loops == 0 {
+                    w.label = label;
+                    break_labels.push(labels);
+                    break;
+                }
+                if let Some(outer) = break_labels.last_mut() {
+                    outer.append(&mut labels);
+                }
             }
-            if num_loops == 0 {
-                break;
+            if let Some(label) = break_re.captures(&line).and_then(|caps| caps.get(1)) {
+                if let Some(labels) = break_labels.last_mut() {
+                    labels.push(String::from(label.as_str()));
+                }
+            }
+            // only the flags for this loop, not any nested loops
+            if num_loops == 1 {
+                if let Some(caps) = accumulate_re.captures(&line) {
+                    w.accumulate_vars.push(String::from(&caps[1]));
+                }
+                if let Some(caps) = max_iter_re.captures(&line) {
+                    w.max_iter = caps[1].parse::<usize>().ok();
+                }
             }
         }
         w.block = String::from(w.block.trim_end());
+        // the whole block is read anyway, so its lines aren't taken for folds
+        if g_env.while_depth + max_depth > WHILE_MAX_DEPTH {
+            w.error = true;
+            w.gen_default_output(String::from("max nesting depth exceeded"));
+            return w;
+        }
+        // a nested loop can break out of the loops around it, so the labels
+        // are only known to be missing in the outermost loop
+        let missing = break_labels.concat();
+        if g_env.while_depth == 0 && !missing.is_empty() {
+            w.error = true;
+            w.gen_default_output(format!("no while loop labeled {}", missing.join(", ")));
+            return w;
+        }
         w.run(g_env);
         w
     }
@@ -99,103 +173,231 @@ impl While {
     /// Run while loop: call parse_input on block while the condition is true
     fn run(&mut self, g_env: &mut GlobalEnv) {
         let error_re = Regex::new(ERROR).unwrap();
-        while self.check_condition(g_env) && !self.error {
-            // call parse_input with ignore_first_while true to avoid infinite loop
-            self.output = g_env.parse_input(&mut self.block.clone().as_bytes(), true);
+        let saved_loop_vars = save_var(g_env, LOOP_VAR);
+        // arrays a loop around this one collects into keep their values
+        let own_accumulate_vars = self.accumulate_vars.iter()
+            .filter(|var| !g_env.accumulating.contains(*var))
+            .cloned()
+            .collect::<Vec<String>>();
+        for var in &own_accumulate_vars {
+            if let Err(err) = g_env.set_var(var, &json!([]), true) {
+                self.error = true;
+                self.gen_default_output(err.to_string());
+                return;
+            }
+        }
+        g_env.accumulating.extend(own_accumulate_vars.iter().cloned());
+        let mut iterations = 0;
+        loop {
+            set_loop_vars(g_env, iterations);
+            // a loop in the rest of an iteration that was broken out of doesn't run
+            if g_env.break_label.is_some() || !self.check_condition(g_env) || self.error {
+                break;
+            }
+            if let Some(max_iter) = self.max_iter.filter(|max_iter| iterations >= *max_iter) {
+                self.error = true;
+                let mut output = self.last_output();
+                if !output.is_empty() {
+                    output.push('\n');
+                }
+                output.push_str(&format!("max iterations ({}) reached", max_iter));
+                self.gen_default_output(output);
+                break;
+            }
+            iterations += 1;
+            // call parse_input with ignore_first_loop true to avoid infinite loop
+            g_env.while_depth += 1;
+            self.output = render_results(&g_env.parse_input(&mut self.block.clone().as_bytes(), true));
+            g_env.while_depth -= 1;
             let first_line = self.output.lines().next().unwrap_or("");
             self.error = self.error || error_re.is_match(first_line);
+            if let Some(label) = &g_env.break_label {
+                // an unlabeled @break ends the innermost loop, otherwise the
+                // loops are left until the one with the label
+                if label.is_empty() || self.label.as_ref() == Some(label) {
+                    g_env.break_label = None;
+                }
+                break;
+            }
         }
+        for var in &own_accumulate_vars {
+            g_env.accumulating.remove(var);
+        }
+        restore_var(g_env, saved_loop_vars);
         if self.output.is_empty() {
             self.gen_default_output(String::new());
         }
     }
 
+    /// The output lines of the last loop, without the input or markers
+    fn last_output(&self) -> String {
+        let lines = self.output.lines().collect::<Vec<&str>>();
+        match lines.iter().position(|line| line.starts_with(&self.separator)) {
+            Some(divider) if divider + 1 < lines.len() => lines[divider + 1..lines.len() - 1].join("\n"),
+            _ => String::new(),
+        }
+    }
+
     /// Return the block (input) and output of last loop, with proper formatting.
-    /// res_input: all lines before ########## marker, and last line
-    /// res_output: first line but without { and with only ERROR or RESULT, and
-    /// all lines after ########## marker, with last line without }
     pub fn compile_return(&mut self) -> (String, String) {
-        let mut res_input = String::new();
-        let mut res_output = String::new();
-        let first_line = String::from(self.output.lines().next().unwrap_or(""));
-        let last_line = self.output.lines().last().unwrap_or("");
-        let num_lines = self.output.lines().collect::<Vec<&str>>().len();
-        let mut reached_divider = false;
-        let suffix_re = Regex::new(r" executed \((ERROR|SUCCESS)\)$").unwrap();
-
-        let first_line_formatted = first_line.replacen("{", "", 1);
-        let first_line_formatted = suffix_re.replace(&first_line_formatted, "");
-        let first_line_formatted = format!(
-            "{} {}",
-            first_line_formatted,
-            if self.error {"ERROR"} else {"RESULT"}
-        );
-        let last_line_formatted = last_line.replacen("}", "", 1);
-        res_output.push_str(&format!("{}\n", first_line_formatted));
-        for (i, line) in self.output.lines().enumerate() {
-            if line.starts_with("##########") {
-                reached_divider = true;
-                continue;
-            }
-            if i + 1 == num_lines {
-                break;
-            }
-            if !reached_divider {
-                res_input.push_str(&format!("{}\n", line));
-            } else {
-                res_output.push_str(&format!("{}\n", line))
-            }
-        }
-        res_input.push_str(last_line);
-        res_output.push_str(&last_line_formatted);
-        (res_input, res_output)
+        compile_loop_return(&self.output, &self.separator, self.error)
     }
 
-    /// Evaluates the condition for the while loop. The jq syntax should return
-    /// either true or false.
+    /// Evaluates the condition for the while loop. The loop goes on while the
+    /// jq syntax returns anything other than false, null, 0, "" or "false",
+    /// so a count can be used as the condition.
     fn check_condition(&mut self, g_env: &mut GlobalEnv) -> bool {
-        g_env.parse_selectors(&self.condition)
-            .map_or_else(
-                |err| {
-                    self.error = true;
-                    self.gen_default_output(err.to_string());
-                    false
-                },
-                |res| res.as_str() == "true"
-            )
-
+        eval_condition(g_env, &self.condition)
+            .unwrap_or_else(|err| {
+                self.error = true;
+                self.gen_default_output(err.to_string());
+                false
+            })
     }
 
     /// Creates an output like parse_input, in the case where parse_input wasn't
     /// able to run and it has to be simulated.
     fn gen_default_output(&mut self, output: String) {
-        let suffix_re = Regex::new(r" executed \((ERROR|SUCCESS)\)$").unwrap();
-        let start_marker_re = Regex::new(r"###\{\s*").unwrap();
-        let first_line = String::from(self.block.lines().next().unwrap_or(""));
-        let first_line = suffix_re.replace(&first_line, "");
-        let title = start_marker_re.replace(&first_line, "");
-        let last_line = self.block.lines().last().unwrap_or("");
-        let input = self.block.lines().collect::<Vec<&str>>();
-        let len = input.len();
-        let input = if len > 2 {
-            (&input[1..len-1])
-                .iter()
-                .map(|&l| String::from(l))
-                .reduce(|acc, line| format!("{}\n{}", acc, line)).unwrap()
+        self.output = gen_loop_output(&self.block, &self.separator, self.error, output);
+    }
+}
+
+/// Evaluates the {{}} condition of a while loop or if block with is_truthy.
+/// A variable that isn't set is null, so it is false instead of an error.
+pub(crate) fn eval_condition(g_env: &mut GlobalEnv, condition: &str) -> Result<bool, RestClientError> {
+    let condition = condition
+        .trim_start_matches("{{")
+        .trim_end_matches("}}");
+    let condition = g_env.parse_selectors(&String::from(condition))?;
+    Ok(is_truthy(&g_env.evaluate_or_null(&condition)?))
+}
+
+/// If the value of a condition counts as true, which it does unless it is
+/// false, null, 0, "" or "false". Unlike jq, 0 and "" are false so a count or
+/// a string can be the condition, and "false" is false for a condition that
+/// was substituted as a string.
+pub(crate) fn is_truthy(val: &Value) -> bool {
+    match val {
+        Value::Null | Value::Bool(false) => false,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty() && s != "false",
+        _ => true,
+    }
+}
+
+/// Sets the loop vars for the iteration, as a temporary variable so it isn't
+/// written to the env file. Shared by all loop constructs.
+pub(crate) fn set_loop_vars(g_env: &mut GlobalEnv, index: usize) {
+    let loop_vars = json!({"index": index, "count": index + 1});
+    // the env is always an object, so this can't fail
+    let _ = g_env.set_var(&String::from(LOOP_VAR), &loop_vars, false);
+}
+
+/// A variable as it was before a loop set it, so it can be put back after the
+/// loop, persisted or temporary as it was.
+pub(crate) struct SavedVar {
+    name: String,
+    value: Option<Value>,
+    tmp: bool,
+}
+
+/// Saves the variable before a loop sets it. Shared by all loop constructs.
+pub(crate) fn save_var(g_env: &GlobalEnv, name: &str) -> SavedVar {
+    SavedVar {
+        name: String::from(name),
+        value: g_env.env.get(name).cloned(),
+        tmp: g_env.tmp_vars.contains(name),
+    }
+}
+
+/// Puts back the variable saved before the loop, or removes it if it wasn't
+/// set. Shared by all loop constructs.
+pub(crate) fn restore_var(g_env: &mut GlobalEnv, saved: SavedVar) {
+    let _ = match saved.value {
+        Some(val) => g_env.set_var(&saved.name, &val, !saved.tmp),
+        None if g_env.env.get(&saved.name).is_some() => g_env.delete_var(&saved.name),
+        None => Ok(()),
+    };
+}
+
+/// Removes the status left on the first line of the block by an earlier run,
+/// whichever status it was, so it isn't repeated when run again
+fn strip_status(line: &str) -> String {
+    let status_re = Regex::new(&format!(r" executed \(({})(, \d+ms)?\)$", STATUSES)).unwrap();
+    String::from(status_re.replace(line, ""))
+}
+
+/// Return the block (input) and output of the last loop, with proper formatting
+/// for nesting in a parent fold. Shared by all loop and if constructs.
+/// res_input: all lines before the separator, and last line
+/// res_output: first line but without { and with only ERROR or RESULT, and
+/// all lines after the separator, with last line without }
+pub(crate) fn compile_loop_return(output: &str, separator: &str, error: bool) -> (String, String) {
+    let mut res_input = String::new();
+    let mut res_output = String::new();
+    let first_line = String::from(output.lines().next().unwrap_or(""));
+    let last_line = output.lines().last().unwrap_or("");
+    let num_lines = output.lines().collect::<Vec<&str>>().len();
+    let mut reached_divider = false;
+
+    let first_line_formatted = first_line.replacen("{", "", 1);
+    let first_line_formatted = strip_status(&first_line_formatted);
+    let first_line_formatted = format!(
+        "{} {}",
+        first_line_formatted,
+        if error {"ERROR"} else {"RESULT"}
+    );
+    let last_line_formatted = last_line.replacen("}", "", 1);
+    res_output.push_str(&format!("{}\n", first_line_formatted));
+    for (i, line) in output.lines().enumerate() {
+        if line.starts_with(separator) {
+            reached_divider = true;
+            continue;
+        }
+        if i + 1 == num_lines {
+            break;
+        }
+        if !reached_divider {
+            res_input.push_str(&format!("{}\n", line));
         } else {
-            String::new()
-        };
-        self.output = format!(
-            "{} executed ({})\n{}########## {} {}\n{}{}",
-            first_line,
-            if self.error {"ERROR"} else {"SUCCESS"},
-            if input.is_empty() {String::new()} else {format!("{}\n", input)},
-            title,
-            if self.error {"ERROR"} else {"RESULT"},
-            if output.is_empty() {String::new()} else {format!("{}\n", output)},
-            last_line
-        );
+            res_output.push_str(&format!("{}\n", line))
+        }
     }
+    res_input.push_str(last_line);
+    res_output.push_str(&last_line_formatted);
+    (res_input, res_output)
+}
+
+/// Creates an output like parse_input for the loop block, in the case where
+/// parse_input wasn't able to run and it has to be simulated. Shared by all
+/// loop and if constructs.
+pub(crate) fn gen_loop_output(block: &str, separator: &str, error: bool, output: String) -> String {
+    let start_marker_re = Regex::new(r"###\{\s*").unwrap();
+    let first_line = String::from(block.lines().next().unwrap_or(""));
+    let first_line = strip_status(&first_line);
+    let title = start_marker_re.replace(&first_line, "");
+    let last_line = block.lines().last().unwrap_or("");
+    let input = block.lines().collect::<Vec<&str>>();
+    let len = input.len();
+    let input = if len > 2 {
+        (&input[1..len-1])
+            .iter()
+            .map(|&l| String::from(l))
+            .reduce(|acc, line| format!("{}\n{}", acc, line)).unwrap()
+    } else {
+        String::new()
+    };
+    format!(
+        "{} executed ({})\n{}{} {} {}\n{}{}",
+        first_line,
+        if error {"ERROR"} else {"SUCCESS"},
+        if input.is_empty() {String::new()} else {format!("{}\n", input)},
+        separator,
+        title,
+        if error {"ERROR"} else {"RESULT"},
+        if output.is_empty() {String::new()} else {format!("{}\n", output)},
+        last_line
+    )
 }
 
 
@@ -208,6 +410,7 @@ mod tests {
     use std::fs;
     use serde_json::json;
     use crate::ENV_FILE;
+    use crate::tests::strip_durations;
 
     fn clear_env_file() {
         if let Err(_) = fs::remove_file(ENV_FILE) {
@@ -236,7 +439,7 @@ mod tests {
 @i = 5
 ###} endwhile"#);
             assert_eq!(
-                test_while.output,
+                strip_durations(&test_while.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -259,7 +462,7 @@ mod tests {
 ########## while {{.i < 5}} RESULT
 ###} endwhile"#);
             assert_eq!(
-                test_while.output,
+                strip_durations(&test_while.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -270,18 +473,18 @@ mod tests {
         {
             g_env.env = json!({});
             let mut test_while = While::new();
-            test_while.condition = String::from("{{.j}}");
-            test_while.block = String::from(r#"###{ while {{.j}}
+            test_while.condition = String::from("{{.items[{{.j}}]}}");
+            test_while.block = String::from(r#"###{ while {{.items[{{.j}}]}}
 @j = {{.j + 1}}
 ###} endwhile"#);
             test_while.run(&mut g_env);
-            let expected = String::from(r#"###{ while {{.j}} executed (ERROR)
+            let expected = String::from(r#"###{ while {{.items[{{.j}}]}} executed (ERROR)
 @j = {{.j + 1}}
-########## while {{.j}} ERROR
+########## while {{.items[{{.j}}]}} ERROR
 failed to get resource at .j
 ###} endwhile"#);
             assert_eq!(
-                test_while.output,
+                strip_durations(&test_while.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -293,6 +496,37 @@ failed to get resource at .j
         clear_env_file();
     }
 
+    #[test]
+    fn test_check_condition() {
+        let mut g_env = GlobalEnv::new(None);
+        g_env.env = json!({"i": 0, "count": 2});
+        let cases = [
+            ("{{.i < 5}}", true),
+            ("{{.i > 5}}", false),
+            ("{{0}}", false),
+            ("{{1}}", true),
+            ("{{.count}}", true),
+            (r#"{{""}}"#, false),
+            (r#"{{"text"}}"#, true),
+            (r#"{{"false"}}"#, false),
+            (r#"{{"true"}}"#, true),
+            ("{{.i < 5 | tostring}}", true),
+            ("{{.i > 5 | tostring}}", false),
+            ("{{.x // null}}", false),
+            ("{{.missing}}", false),
+            ("{{null}}", false),
+            ("{{[]}}", true),
+        ];
+        for (condition, expected) in cases {
+            let mut test_while = While::new();
+            test_while.condition = String::from(condition);
+            assert_eq!(test_while.check_condition(&mut g_env), expected, "Condition {}", condition);
+            assert!(!test_while.error, "Condition {}, got:\n{}", condition, test_while.output);
+        }
+
+        clear_env_file();
+    }
+
     #[test]
     fn test_compile_return() {
         let mut g_env = GlobalEnv::new(None);
@@ -314,7 +548,7 @@ failed to get resource at .j
 @i = 5
 ### endwhile 1"#);
             assert_eq!(
-                res_input,
+                strip_durations(&res_input),
                 expected_input,
                 "Expected:\n{}\nGot:\n{}",
                 expected_input,
@@ -345,7 +579,7 @@ failed to get resource at .j
             let expected_output = String::from(r#"### while {{.i < 5}} RESULT
 ### endwhile"#);
             assert_eq!(
-                res_input,
+                strip_durations(&res_input),
                 expected_input,
                 "Expected:\n{}\nGot:\n{}",
                 expected_input,
@@ -362,20 +596,20 @@ failed to get resource at .j
         {
             g_env.env = json!({});
             let mut test_while = While::new();
-            test_while.condition = String::from("{{.j}}");
-            test_while.block = String::from(r#"###{ while {{.j}}
+            test_while.condition = String::from("{{.items[{{.j}}]}}");
+            test_while.block = String::from(r#"###{ while {{.items[{{.j}}]}}
 @j = {{.j + 1}}
 ###} endwhile"#);
             test_while.run(&mut g_env);
             let (res_input, res_output) = test_while.compile_return();
-            let expected_input = String::from(r#"###{ while {{.j}} executed (ERROR)
+            let expected_input = String::from(r#"###{ while {{.items[{{.j}}]}} executed (ERROR)
 @j = {{.j + 1}}
 ###} endwhile"#);
-            let expected_output = String::from(r#"### while {{.j}} ERROR
+            let expected_output = String::from(r#"### while {{.items[{{.j}}]}} ERROR
 failed to get resource at .j
 ### endwhile"#);
             assert_eq!(
-                res_input,
+                strip_durations(&res_input),
                 expected_input,
                 "Expected:\n{}\nGot:\n{}",
                 expected_input,
@@ -393,6 +627,321 @@ failed to get resource at .j
         clear_env_file();
     }
 
+    #[test]
+    fn test_loop_output_status() {
+        // the status of an earlier run is replaced, whichever it was
+        for status in ["SUCCESS", "ERROR, 3ms", "SKIPPED, 12ms", "FIXTURE CREATED, 40ms"] {
+            let block = format!("###{{ for x in {{{{.xs}}}} executed ({})\n@y = 1\n###}} endfor", status);
+            let output = gen_loop_output(&block, DEFAULT_SEPARATOR, false, String::new());
+            let expected = String::from(r#"###{ for x in {{.xs}} executed (SUCCESS)
+@y = 1
+########## for x in {{.xs}} RESULT
+###} endfor"#);
+            assert_eq!(output, expected, "Expected:\n{}\nGot:\n{}", expected, output);
+            let (_, res_output) = compile_loop_return(&block, DEFAULT_SEPARATOR, false);
+            assert_eq!(res_output.lines().next(), Some("### for x in {{.xs}} RESULT"), "Got:\n{}", res_output);
+        }
+    }
+
+    #[test]
+    fn test_max_iter() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({
+                "i": 0
+            });
+            let first_line = String::from("###{ while {{.i >= 0}}");
+            let input = String::from(r#"# @max-iter 3
+@i = {{.i + 1}}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            let expected = String::from(r#"###{ while {{.i >= 0}} executed (ERROR)
+# @max-iter 3
+@i = {{.i + 1}}
+########## while {{.i >= 0}} ERROR
+@i = 3
+max iterations (3) reached
+###} endwhile"#);
+            assert_eq!(
+                strip_durations(&w.output),
+                expected,
+                "Expected:\n{}\nGot:\n{}",
+                expected,
+                w.output
+            );
+            assert!(w.error);
+            assert_eq!(g_env.env["i"], json!(3));
+        }
+        {
+            g_env.env = json!({
+                "i": 0
+            });
+            let first_line = String::from("###{ while {{.i < 3}}");
+            let input = String::from(r#"# @max-iter 3
+@i = {{.i + 1}}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["i"], json!(3));
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut g_env = GlobalEnv::new(None);
+        let nested = |depth: usize| {
+            let mut input = String::from("@i = 1\n");
+            for _ in 1..depth {
+                input.push_str("###{ while {{false}}\n");
+            }
+            input.push_str("@j = 1\n");
+            for _ in 0..depth {
+                input.push_str("###} endwhile\n");
+            }
+            input
+        };
+        let first_line = String::from("###{ while {{.i < 1}}");
+        {
+            g_env.env = json!({"i": 0});
+            let w = While::parse_while(&first_line, &mut nested(WHILE_MAX_DEPTH).as_bytes(), &mut g_env);
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+        }
+        {
+            g_env.env = json!({"i": 0});
+            let input = format!("{}after", nested(WHILE_MAX_DEPTH + 1));
+            let mut reader = input.as_bytes();
+            let w = While::parse_while(&first_line, &mut reader, &mut g_env);
+            assert!(w.error);
+            let output = w.output.lines().collect::<Vec<&str>>();
+            assert_eq!(output[output.len() - 2], "max nesting depth exceeded");
+            assert_eq!(g_env.env["i"], json!(0));
+            // the rest of the block is still read
+            assert_eq!(reader, "after".as_bytes());
+        }
+        {
+            // a while nested in the block of a running loop
+            g_env.env = json!({"i": 0});
+            g_env.while_depth = WHILE_MAX_DEPTH;
+            let w = While::parse_while(&first_line, &mut nested(1).as_bytes(), &mut g_env);
+            assert!(w.error);
+            assert!(w.output.contains("max nesting depth exceeded"), "Got:\n{}", w.output);
+            g_env.while_depth = 0;
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_loop_vars() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({});
+            let first_line = String::from("###{ while {{.loop.count <= 5}}");
+            let input = String::from(r#"@index = {{.loop.index}}
+@count = {{.loop.count}}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["index"], json!(4));
+            assert_eq!(g_env.env["count"], json!(5));
+            assert_eq!(g_env.env.get("loop"), None);
+        }
+        {
+            g_env.env = json!({"outer": []});
+            let first_line = String::from("###{ while {{.loop.index < 2}}");
+            let input = String::from(r#"###{ while {{.loop.index < 3}}
+@inner = {{.loop.index}}
+###} endwhile
+@outer = {{.outer + [.loop.index]}}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["inner"], json!(2));
+            assert_eq!(g_env.env["outer"], json!([0, 1]));
+            assert_eq!(g_env.env.get("loop"), None);
+        }
+        {
+            // a variable of the user named loop is kept
+            g_env.env = json!({});
+            g_env.set_var(&String::from("loop"), &json!("mine"), true).unwrap();
+            let first_line = String::from("###{ while {{.loop.count <= 2}}");
+            let input = String::from(r#"@count = {{.loop.count}}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["count"], json!(2));
+            assert_eq!(g_env.env["loop"], json!("mine"));
+            assert!(!g_env.tmp_vars.contains("loop"), "loop should still be persisted");
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({
+                "i": 0,
+                "results": ["from a previous run"]
+            });
+            let first_line = String::from("###{ while {{.i < 3}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+###{
+# @debug
+# @accumulate results
+GET https://reqbin.com/echo/get/json?i={{.i}}
+###}
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["results"], json!(["", "", ""]));
+        }
+        {
+            // an @accumulate in a nested loop is only for the nested loop
+            g_env.env = json!({"i": 0});
+            let first_line = String::from("###{ while {{.i < 2}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+@j = 0
+###{ while {{.j < 2}}
+@j = {{.j + 1}}
+###{
+# @debug
+# @accumulate results
+GET https://reqbin.com/echo/get/json?i={{.i}}&j={{.j}}
+###}
+###} endwhile
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert!(w.accumulate_vars.is_empty(), "Got {:?}", w.accumulate_vars);
+            assert_eq!(g_env.env["results"], json!(["", ""]));
+        }
+        {
+            // a nested loop adds to the array of the outer loop, instead of
+            // emptying it each time it starts
+            g_env.env = json!({"i": 0});
+            let first_line = String::from("###{ while {{.i < 2}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+@j = 0
+###{
+# @debug
+# @accumulate results
+GET https://reqbin.com/echo/get/json?i={{.i}}
+###}
+###{ while {{.j < 2}}
+@j = {{.j + 1}}
+###{
+# @debug
+# @accumulate results
+GET https://reqbin.com/echo/get/json?i={{.i}}&j={{.j}}
+###}
+###} endwhile
+###} endwhile"#);
+            let w = While::parse_while(
+                &first_line,
+                &mut input.as_bytes(),
+                &mut g_env
+            );
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["results"], json!(["", "", "", "", "", ""]));
+            assert!(g_env.accumulating.is_empty());
+        }
+
+        clear_env_file();
+    }
+
+    #[test]
+    fn test_break() {
+        let mut g_env = GlobalEnv::new(None);
+        {
+            g_env.env = json!({"i": 0});
+            let first_line = String::from("###{ while {{.i < 5}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+###{
+# @skip-if .i < 3
+# @break
+###}
+###{
+@after = {{.i}}
+###}
+###} endwhile"#);
+            let w = While::parse_while(&first_line, &mut input.as_bytes(), &mut g_env);
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["i"], json!(3));
+            assert_eq!(g_env.env["after"], json!(2));
+            assert_eq!(g_env.break_label, None);
+        }
+        {
+            g_env.env = json!({"i": 0});
+            let first_line = String::from("###{ while {{.i < 3}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+@j = 0
+###{ while {{.j < 3}}
+@j = {{.j + 1}}
+###{
+# @skip-if .j < 2
+# @break outer
+###}
+###} endwhile
+###{
+@after = {{.i}}
+###}
+###} endwhile outer"#);
+            let w = While::parse_while(&first_line, &mut input.as_bytes(), &mut g_env);
+            assert!(!w.error, "Expected no error, got:\n{}", w.output);
+            assert_eq!(g_env.env["i"], json!(1));
+            assert_eq!(g_env.env["j"], json!(2));
+            assert_eq!(g_env.env.get("after"), None);
+            assert_eq!(g_env.break_label, None);
+        }
+        {
+            g_env.env = json!({"i": 0});
+            let first_line = String::from("###{ while {{.i < 3}}");
+            let input = String::from(r#"@i = {{.i + 1}}
+###{ while {{false}}
+# @break outer
+###} endwhile
+###} endwhile inner"#);
+            let w = While::parse_while(&first_line, &mut input.as_bytes(), &mut g_env);
+            assert!(w.error);
+            assert!(w.output.contains("no while loop labeled outer"), "Got:\n{}", w.output);
+            assert_eq!(g_env.env["i"], json!(0));
+        }
+
+        clear_env_file();
+    }
+
     #[test]
     fn test_parse_while() {
         let mut g_env = GlobalEnv::new(None);
@@ -414,7 +963,7 @@ failed to get resource at .j
 @i = 5
 ###} endwhile"#);
             assert_eq!(
-                w.output,
+                strip_durations(&w.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -439,7 +988,7 @@ failed to get resource at .j
 ########## while {{.i < 5}} RESULT
 ###} endwhile"#);
             assert_eq!(
-                w.output,
+                strip_durations(&w.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -449,7 +998,7 @@ failed to get resource at .j
         }
         {
             g_env.env = json!({});
-            let first_line = String::from("###{ while {{.j}}");
+            let first_line = String::from("###{ while {{.items[{{.j}}]}}");
             let input = String::from(r#"@j = {{.j + 1}}
 ###} endwhile"#);
             let w = While::parse_while(
@@ -457,13 +1006,13 @@ failed to get resource at .j
                 &mut input.as_bytes(),
                 &mut g_env
             );
-            let expected = String::from(r#"###{ while {{.j}} executed (ERROR)
+            let expected = String::from(r#"###{ while {{.items[{{.j}}]}} executed (ERROR)
 @j = {{.j + 1}}
-########## while {{.j}} ERROR
+########## while {{.items[{{.j}}]}} ERROR
 failed to get resource at .j
 ###} endwhile"#);
             assert_eq!(
-                w.output,
+                strip_durations(&w.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -505,7 +1054,7 @@ failed to get resource at .j
 @i = 5
 ###} endwhile"#);
             assert_eq!(
-                w.output,
+                strip_durations(&w.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
@@ -528,10 +1077,10 @@ failed to get resource at .j
             let expected = String::from(r#"###{ while {{.i < 5}} executed (ERROR)
 @i = {.i + 1}
 ########## while {{.i < 5}} ERROR
-key must be a string at line 1 column 2
+@i: line 1: key must be a string at line 1 column 2
 ###} endwhile"#);
             assert_eq!(
-                w.output,
+                strip_durations(&w.output),
                 expected,
                 "Expected:\n{}\nGot:\n{}",
                 expected,
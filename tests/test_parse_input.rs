@@ -1,7 +1,9 @@
-use vim_rest_client::{GlobalEnv, ENV_FILE};
+use vim_rest_client::{GlobalEnv, FoldResult, EnvScope, ENV_FILE, DEFAULT_SEPARATOR, named_env_file};
 
 use std::fs;
-//use regex::Regex;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use regex::Regex;
 
 fn clear_env_file() {
     if let Err(_) = fs::remove_file(ENV_FILE) {
@@ -11,6 +13,17 @@ fn clear_env_file() {
     }
 }
 
+/// Parses the input and renders the results, as main does
+fn parse(g_env: &mut GlobalEnv, input: &str) -> String {
+    let results: Vec<FoldResult> = g_env.parse_input(&mut input.as_bytes(), false);
+    strip_durations(&results.iter().map(|r| r.render()).collect::<Vec<_>>().join("\n"))
+}
+
+/// Removes the times from the fold statuses, since they change every run
+fn strip_durations(output: &str) -> String {
+    Regex::new(r", \d+ms\)").unwrap().replace_all(output, ")").to_string()
+}
+
 #[test]
 fn test_parse_input() {
     let mut g_env = GlobalEnv::new(None);
@@ -23,7 +36,7 @@ fn test_parse_input() {
 ########## RESULT
 @baseUrl = "https://10.0.0.20:5443/api/v1"
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -46,7 +59,7 @@ fn test_parse_input() {
 @urls = ["https://10.0.0.20:5443/api/v1", "https://reqbin.com"]
 @obj = {"a": "test", "b": "hello"}
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -70,7 +83,7 @@ fn test_parse_input() {
 @url1 = "https://10.0.0.20:5443/api/v1"
 @objA = "test"
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -91,9 +104,9 @@ fn test_parse_input() {
 @wontExecute = "won't execute even if valid"
 ########## ERROR
 @valid = "valid json"
-expected ident at line 1 column 2
+@willErr: line 2: expected ident at line 1 column 2
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -110,7 +123,7 @@ expected ident at line 1 column 2
 //GET https://reqbin.com/echo/get/json
 //########## no selection RESULT
 //"#;
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            result.contains(should_contain),
 //            "Expected output should contain:\n{}\nResponse:\n{}",
@@ -137,7 +150,7 @@ expected ident at line 1 column 2
 //########## selection RESULT
 //@baseUrl = "https://reqbin.com"
 //"#;
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            result.contains(should_contain),
 //            "Expected output should contain:\n{}\nResponse:\n{}",
@@ -160,7 +173,7 @@ expected ident at line 1 column 2
 //########## test response RESULT
 //@test = "true"
 //###}"#;
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert_eq!(
 //            result,
 //            String::from(test_out),
@@ -191,7 +204,7 @@ expected ident at line 1 column 2
 //}
 //########## test post RESULT
 //"#;
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            result.contains(should_contain),
 //            "Expected output should contain:\n{}\nResponse:\n{}",
@@ -214,7 +227,7 @@ expected ident at line 1 column 2
 //########## test response RESULT
 //@test = "true"
 //###}"#;
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert_eq!(
 //            result,
 //            String::from(test_out),
@@ -253,7 +266,7 @@ expected ident at line 1 column 2
 ########## set url RESULT
 @test = "https://reqbin.com/hello"
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -286,7 +299,7 @@ expected ident at line 1 column 2
 //@res = "true"
 //###\}"#;
 //        let test_out_re = Regex::new(test_out).unwrap();
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            test_out_re.is_match(&result),
 //            "Result:\n{}",
@@ -319,10 +332,10 @@ expected ident at line 1 column 2
 @willSucceed = "https://reqbin.com"
 ###
 ### inner error ERROR
-failed to get resource at .dne
+@willFail: line 1: failed to get resource at .dne
 ###
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -355,10 +368,10 @@ failed to get resource at .dne
 @willSucceed = "https://reqbin.com"
 ###
 ### inner error ERROR
-failed to get resource at .dne
+@willFail: line 1: failed to get resource at .dne
 ###
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -391,7 +404,7 @@ failed to get resource at .dne
 //###
 //###\}"#;
 //        let test_out_re = Regex::new(test_out).unwrap();
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            test_out_re.is_match(&result),
 //            "Result:\n{}",
@@ -408,9 +421,9 @@ failed to get resource at .dne
 @willErr = {.i + 1}
 ########## ERROR
 @i = 0
-key must be a string at line 1 column 2
+@willErr: line 2: key must be a string at line 1 column 2
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -426,9 +439,9 @@ key must be a string at line 1 column 2
         let test_out = r#"###{ test executed (ERROR)
 @i = {.i + 1}
 ########## test ERROR
-key must be a string at line 1 column 2
+@i: line 1: key must be a string at line 1 column 2
 ###} end of test"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -444,9 +457,89 @@ key must be a string at line 1 column 2
         let test_out = r#"###{ while {{.i < 5}} executed (ERROR)
 @i = {.i + 1}
 ########## while {{.i < 5}} ERROR
-key must be a string at line 1 column 2
+@i: line 1: key must be a string at line 1 column 2
 ###} endwhile"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{ for id in {{.ids}}
+@ids = [1, 2]
+###} endfor"#;
+        let test_out = r#"###{ for id in {{.ids}} executed (ERROR)
+@ids = [1, 2]
+########## for id in {{.ids}} ERROR
+failed to get resource at .ids
+###} endfor"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{ check
+@count = 2
+###{ if {{.count > 1}}
+@plural = true
+###{ else
+@plural = false
+###} endif
+###}"#;
+        let test_out = r#"###{ check executed (SUCCESS)
+@count = 2
+###{ if {{.count > 1}} executed (SUCCESS)
+@plural = true
+###{ else
+@plural = false
+###} endif
+########## check RESULT
+@count = 2
+### if {{.count > 1}} RESULT
+if branch taken
+@plural = true
+### endif
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{ outer
+@ids = [1, 2, 3]
+@total = 0
+###{ for id in {{.ids}}
+@total = {{.total + .id}}
+###} endfor
+###}"#;
+        let test_out = r#"###{ outer executed (SUCCESS)
+@ids = [1, 2, 3]
+@total = 0
+###{ for id in {{.ids}} executed (SUCCESS)
+@total = {{.total + .id}}
+###} endfor
+########## outer RESULT
+@ids = [1, 2, 3]
+@total = 0
+### for id in {{.ids}} RESULT
+@total = 6
+### endfor
+###}"#;
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -469,7 +562,7 @@ GET {{.baseUrl}}/echo/get/json
 @baseUrl = "https://reqbin.com"
 curl -k --include https://reqbin.com/echo/get/json -X GET
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
         assert_eq!(
             result,
             String::from(test_out),
@@ -498,7 +591,7 @@ curl -k --include https://reqbin.com/echo/get/json -X GET
 //.*
 //###}"#;
 //        let test_out_re = Regex::new(test_out).unwrap();
-//        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+//        let result = parse(&mut g_env, test_in);
 //        assert!(
 //            test_out_re.is_match(&result),
 //            "Result:\n{}",
@@ -508,6 +601,41 @@ curl -k --include https://reqbin.com/echo/get/json -X GET
     {
         let test_in = r#"###{
 # @debug
+# @options --compressed -H "X-Note: a 'quoted' value"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @options --compressed -H "X-Note: a 'quoted' value"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET --compressed -H "X-Note: a 'quoted' value"
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @options --output "unclosed.txt
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert!(
+            result.starts_with("###{ executed (ERROR)") && result.contains("Could not parse options"),
+            "Expected options error, got:\n{}",
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
 # @options --test
 # @options --output test.txt
 @baseUrl = "https://reqbin.com"
@@ -523,7 +651,305 @@ GET {{.baseUrl}}/echo/get/json
 @baseUrl = "https://reqbin.com"
 curl -k https://reqbin.com/echo/get/json -X GET --test --output test.txt
 ###}"#;
-        let result = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @timeout 1
+@baseUrl = "https://reqbin.com"
+GET {{.baseUrl}}/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @timeout 1
+@baseUrl = "https://reqbin.com"
+GET {{.baseUrl}}/echo/get/json
+########## RESULT
+@baseUrl = "https://reqbin.com"
+curl -k --include https://reqbin.com/echo/get/json -X GET --max-time 1
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @connect-timeout 2.5
+GET https://reqbin.com/echo/get/json
+###}
+###{
+# @debug
+# @timeout 10
+# @connect-timeout {{.connectTimeout}}
+@connectTimeout = 3
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @connect-timeout 2.5
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET --connect-timeout 2.5
+###}
+###{ executed (SUCCESS)
+# @debug
+# @timeout 10
+# @connect-timeout {{.connectTimeout}}
+@connectTimeout = 3
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@connectTimeout = 3
+curl -k --include https://reqbin.com/echo/get/json -X GET --max-time 10 --connect-timeout 3
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @connect-timeout soon
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert!(result.starts_with("###{ executed (ERROR)"), "Got:\n{}", result);
+        assert!(result.contains("invalid @connect-timeout soon, expected a number of seconds"), "Got:\n{}", result);
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @proxy {{.proxyUrl}}
+@proxyUrl = "http://proxy.example.com:8080"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @proxy {{.proxyUrl}}
+@proxyUrl = "http://proxy.example.com:8080"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@proxyUrl = "http://proxy.example.com:8080"
+curl -k --include https://reqbin.com/echo/get/json -x http://proxy.example.com:8080 -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @socks5 {{.socksHost}}:1080
+@socksHost = "socks.example.com"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @socks5 {{.socksHost}}:1080
+@socksHost = "socks.example.com"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@socksHost = "socks.example.com"
+curl -k --include https://reqbin.com/echo/get/json --socks5 socks.example.com:1080 -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @colon-params
+@userId = 42
+GET https://reqbin.com/users/:userId/posts
+###}
+###{
+# @debug
+GET https://reqbin.com/users/:userId/posts
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @colon-params
+@userId = 42
+GET https://reqbin.com/users/:userId/posts
+########## RESULT
+@userId = 42
+curl -k --include https://reqbin.com/users/42/posts -X GET
+###}
+###{ executed (SUCCESS)
+# @debug
+GET https://reqbin.com/users/:userId/posts
+########## RESULT
+curl -k --include https://reqbin.com/users/:userId/posts -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @interface eth0
+GET https://reqbin.com/echo/get/json
+###}
+###{
+# @debug
+# @interface {{.netIface}}
+# @proxy http://proxy.example.com:8080
+@netIface = "tun0"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @interface eth0
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json --interface eth0 -X GET
+###}
+###{ executed (SUCCESS)
+# @debug
+# @interface {{.netIface}}
+# @proxy http://proxy.example.com:8080
+@netIface = "tun0"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@netIface = "tun0"
+warning: @interface may be ignored for requests through a @proxy by some curl versions
+curl -k --include https://reqbin.com/echo/get/json -x http://proxy.example.com:8080 --interface tun0 -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @dns-server 10.0.0.53
+GET https://reqbin.com/echo/get/json
+###}
+###{
+# @debug
+# @dns-server 10.0.0.53
+# @dns-server {{.dnsServer}}
+@dnsServer = "10.0.1.53"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @dns-server 10.0.0.53
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json --dns-servers 10.0.0.53 -X GET
+###}
+###{ executed (SUCCESS)
+# @debug
+# @dns-server 10.0.0.53
+# @dns-server {{.dnsServer}}
+@dnsServer = "10.0.1.53"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@dnsServer = "10.0.1.53"
+curl -k --include https://reqbin.com/echo/get/json --dns-servers 10.0.0.53,10.0.1.53 -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @verbose
+# @no-include
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @verbose
+# @no-include
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k https://reqbin.com/echo/get/json -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        g_env.separator = String::from("=====");
+        let test_in = r#"###{
+# @debug
+GET https://reqbin.com/echo/get/json
+===== RESULT
+old output
+###}
+###{
+# @debug
+# @separator -----
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+GET https://reqbin.com/echo/get/json
+===== RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET
+###}
+###{ executed (SUCCESS)
+# @debug
+# @separator -----
+GET https://reqbin.com/echo/get/json
+----- RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        g_env.separator = String::from(DEFAULT_SEPARATOR);
         assert_eq!(
             result,
             String::from(test_out),
@@ -532,6 +958,1422 @@ curl -k https://reqbin.com/echo/get/json -X GET --test --output test.txt
             result
         );
     }
+    {
+        let test_in = r#"###{
+# @debug
+# @separator ###}
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert!(result.starts_with("###{ executed (ERROR)"), "Got:\n{}", result);
+        assert!(result.contains("Invalid separator ###}: it can't start with ###"), "Got:\n{}", result);
+
+        let res = Command::new(env!("CARGO_BIN_EXE_vim-rest-client"))
+            .args(["--separator", "###"])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(!res.status.success());
+        assert!(String::from_utf8(res.stdout).unwrap().starts_with("invalid separator ###"));
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @socks5 socks.example.com:1080
+# @proxy http://proxy.example.com:8080
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert!(result.starts_with("###{ executed (ERROR)"), "Got:\n{}", result);
+        assert!(result.contains("@proxy and @socks5 can't both be set"), "Got:\n{}", result);
+    }
+    {
+        let test_in = r#"###{
+@socks5Proxy = "socks.example.com:1080"
+###}
+###{
+# @debug
+GET https://reqbin.com/echo/get/json
+###}
+###{
+# @debug
+# @proxy http://proxy.example.com:8080
+GET https://reqbin.com/echo/get/json
+###}
+###{
+@unset socks5Proxy
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert!(result.contains("curl -k --include https://reqbin.com/echo/get/json --socks5 socks.example.com:1080 -X GET"), "Got:\n{}", result);
+        assert!(result.contains("curl -k --include https://reqbin.com/echo/get/json -x http://proxy.example.com:8080 -X GET"), "Got:\n{}", result);
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @cert {{.certDir}}/client.pem
+# @key {{.certDir}}/client.key
+@certDir = "/etc/certs"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @cert {{.certDir}}/client.pem
+# @key {{.certDir}}/client.key
+@certDir = "/etc/certs"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@certDir = "/etc/certs"
+curl -k --include https://reqbin.com/echo/get/json -X GET --cert /etc/certs/client.pem --key /etc/certs/client.key
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @cookie-jar {{.cookieDir}}/cookies.txt
+@cookieDir = "/tmp"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @cookie-jar {{.cookieDir}}/cookies.txt
+@cookieDir = "/tmp"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@cookieDir = "/tmp"
+curl -k --include https://reqbin.com/echo/get/json -X GET -c /tmp/cookies.txt -b /tmp/cookies.txt
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @cookie-jar cookies.txt
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let cwd = std::env::current_dir().unwrap();
+        let jar = cwd.join("cookies.txt");
+        let expected = format!("-c {} -b {}", jar.display(), jar.display());
+        let result = parse(&mut g_env, test_in);
+        assert!(
+            result.contains(&expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @follow
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @follow
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET -L
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @follow
+# @max-redirects 3
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @follow
+# @max-redirects 3
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET -L --max-redirs 3
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @bearer {{.token}}
+@token = "abc123"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @bearer {{.token}}
+@token = "abc123"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@token = "abc123"
+curl -k --include https://reqbin.com/echo/get/json -X GET -H "Authorization: Bearer abc123"
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @bearer {{.token}}
+GET https://reqbin.com/echo/get/json
+authorization: Bearer explicit
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @bearer {{.token}}
+GET https://reqbin.com/echo/get/json
+authorization: Bearer explicit
+########## RESULT
+warning: Authorization header is set, so @bearer is ignored
+curl -k --include https://reqbin.com/echo/get/json -X GET -H "authorization: Bearer explicit"
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @if-none-match etag
+# @if-modified-since lastModified
+@etag = "\"33a64df5\""
+@lastModified = "Wed, 21 Oct 2015 07:28:00 GMT"
+GET https://reqbin.com/echo/get/json
+Accept: application/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @if-none-match etag
+# @if-modified-since lastModified
+@etag = "\"33a64df5\""
+@lastModified = "Wed, 21 Oct 2015 07:28:00 GMT"
+GET https://reqbin.com/echo/get/json
+Accept: application/json
+########## RESULT
+@etag = "\"33a64df5\""
+@lastModified = "Wed, 21 Oct 2015 07:28:00 GMT"
+curl -k --include https://reqbin.com/echo/get/json -X GET -H 'If-None-Match: "33a64df5"' -H "If-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT" -H "Accept: application/json"
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @sensitive *password
+@api_password = "hunter2"
+POST https://reqbin.com/echo/post/json
+Content-Type: application/json
+
+{"a":1,"b":[2,{"password":"p"}],"key":"{{.api_password}}"}
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @sensitive *password
+@api_password = "hunter2"
+POST https://reqbin.com/echo/post/json
+Content-Type: application/json
+
+{"a":1,"b":[2,{"password":"p"}],"key":"{{.api_password}}"}
+########## RESULT
+@api_password = [REDACTED]
+curl -k --include https://reqbin.com/echo/post/json -X POST -H "Content-Type: application/json" -d {"a":1,"b":[2,{"password":"p"}],"key":"[REDACTED]"}
+# Body:
+# {
+#   "a": 1,
+#   "b": [
+#     2,
+#     {
+#       "password": "[REDACTED]"
+#     }
+#   ],
+#   "key": "[REDACTED]"
+# }
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @bearer {{.token}}
+# @bearer-refresh https://reqbin.com/oauth/token token
+# @bearer-refresh-body {refresh_token: .refreshToken}
+@token = "expired"
+@refreshToken = "r1"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @bearer {{.token}}
+# @bearer-refresh https://reqbin.com/oauth/token token
+# @bearer-refresh-body {refresh_token: .refreshToken}
+@token = "expired"
+@refreshToken = "r1"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@token = "expired"
+@refreshToken = "r1"
+curl -k --include https://reqbin.com/echo/get/json -X GET -H "Authorization: Bearer expired"
+# on 401, refresh the bearer token with:
+curl -k --include https://reqbin.com/oauth/token -X POST -H "Content-Type: application/json" -d {"refresh_token":"r1"}
+# Body:
+# {
+#   "refresh_token": "r1"
+# }
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @if-none-match unsetEtag
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @if-none-match unsetEtag
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @http2
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @http2
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET --http2
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @http3
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @http3
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET --http3
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @http2
+# @http3
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @http2
+# @http3
+GET https://reqbin.com/echo/get/json
+########## RESULT
+warning: both @http2 and @http3 are set, using HTTP/2
+curl -k --include https://reqbin.com/echo/get/json -X GET --http2
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    for (version, arg) in [("1.0", "--http1.0"), ("1.1", "--http1.1"), ("2", "--http2"), ("3", "--http3")] {
+        let test_in = format!(r#"###{{
+# @debug
+# @http-version {}
+GET https://reqbin.com/echo/get/json
+###}}"#, version);
+        let test_out = format!(r#"###{{ executed (SUCCESS)
+# @debug
+# @http-version {}
+GET https://reqbin.com/echo/get/json
+########## RESULT
+curl -k --include https://reqbin.com/echo/get/json -X GET {}
+###}}"#, version, arg);
+        let result = parse(&mut g_env, &test_in);
+        assert_eq!(
+            result,
+            test_out,
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @http2
+# @http-version 1.1
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @http2
+# @http-version 1.1
+GET https://reqbin.com/echo/get/json
+########## RESULT
+warning: @http-version is set, so @http2 and @http3 are ignored
+curl -k --include https://reqbin.com/echo/get/json -X GET --http1.1
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @http-version 4
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (ERROR)
+# @debug
+# @http-version 4
+GET https://reqbin.com/echo/get/json
+########## ERROR
+invalid @http-version 4, expected 1.0, 1.1, 2 or 3
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @output {{.downloadDir}}/out.json
+@downloadDir = "/tmp"
+GET https://reqbin.com/echo/get/json
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @output {{.downloadDir}}/out.json
+@downloadDir = "/tmp"
+GET https://reqbin.com/echo/get/json
+########## RESULT
+@downloadDir = "/tmp"
+curl -k -D - https://reqbin.com/echo/get/json -X GET -o /tmp/out.json
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+//    {
+//        let test_in = r#"###{ download
+//# @name downloaded
+//# @output out.json
+//GET https://reqbin.com/echo/get/json
+//###}"#;
+//        let result = parse(&mut g_env, test_in);
+//        assert!(
+//            result.contains("Response saved to out.json"),
+//            "Expected output should contain the saved path\nResponse:\n{}",
+//            result
+//        );
+//        assert!(fs::metadata("out.json").is_ok(), "Expected out.json to be created");
+//        fs::remove_file("out.json").unwrap();
+//    }
+    {
+        let test_in = r#"###{
+# @debug
+# @graphql
+# @graphql-variables {"id": {{.userId}}}
+@userId = 7
+POST https://example.com/graphql
+
+query User($id: ID!) {
+  user(id: $id) {
+    name
+  }
+}
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @graphql
+# @graphql-variables {"id": {{.userId}}}
+@userId = 7
+POST https://example.com/graphql
+
+query User($id: ID!) {
+  user(id: $id) {
+    name
+  }
+}
+########## RESULT
+@userId = 7
+curl -k --include https://example.com/graphql -X POST -H "Content-Type: application/json" -d '{"query":"query User($id: ID!) {\n  user(id: $id) {\n    name\n  }\n}","variables":{"id":7}}'
+# Body:
+# {
+#   "query": "query User($id: ID!) {\n  user(id: $id) {\n    name\n  }\n}",
+#   "variables": {
+#     "id": 7
+#   }
+# }
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @form-encoded
+@name = "Jane Doe"
+POST https://reqbin.com/echo/post/form
+
+name={{.name}}&note=a+b=c
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @form-encoded
+@name = "Jane Doe"
+POST https://reqbin.com/echo/post/form
+
+name={{.name}}&note=a+b=c
+########## RESULT
+@name = "Jane Doe"
+curl -k --include https://reqbin.com/echo/post/form -X POST -H "Content-Type: application/x-www-form-urlencoded" --data-urlencode "name=Jane Doe" --data-urlencode note=a+b=c
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @form data=@upload.bin
+# @form meta=@meta.json;type=application/json
+# @form name=report
+# @form-type data=application/octet-stream
+# @form-type meta=text/plain
+POST https://reqbin.com/echo/post/form
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+# @form data=@upload.bin
+# @form meta=@meta.json;type=application/json
+# @form name=report
+# @form-type data=application/octet-stream
+# @form-type meta=text/plain
+POST https://reqbin.com/echo/post/form
+########## RESULT
+curl -k --include https://reqbin.com/echo/post/form -X POST -F 'data=@upload.bin;type=application/octet-stream' -F 'meta=@meta.json;type=application/json' -F name=report
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{
+# @debug
+# @form-encoded
+POST https://reqbin.com/echo/post/form
+content-type: application/x-www-form-urlencoded; charset=utf-8
+
+a=1
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        let expected = r#"-H "content-type: application/x-www-form-urlencoded; charset=utf-8" --data-urlencode a=1"#;
+        assert!(
+            result.contains(expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+    }
+//    {
+//        let test_in = r#"###{ form post
+//# @form-encoded
+//POST https://httpbin.org/post
+//
+//name=Jane Doe
+//###}"#;
+//        let result = parse(&mut g_env, test_in);
+//        assert!(
+//            result.contains(r#""Content-Type": "application/x-www-form-urlencoded""#),
+//            "Expected the echoed content type to be form encoded\nResponse:\n{}",
+//            result
+//        );
+//    }
+    {
+        let body_dir = std::env::temp_dir();
+        let body_file = body_dir.join("vim-rest-client-body.json");
+        fs::write(&body_file, "{\"test\": \"value\"}\n").unwrap();
+        g_env.env["bodyDir"] = serde_json::json!(body_dir.to_string_lossy());
+        let test_in = r#"###{
+# @debug
+# @body-file {{.bodyDir}}/vim-rest-client-body.json
+POST https://reqbin.com/echo/post/json
+Content-Type: application/json
+###}"#;
+        let expected = format!("-H \"Content-Type: application/json\" --data-binary @{}", body_file.display());
+        let result = parse(&mut g_env, test_in);
+        assert!(
+            result.contains(&expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+        fs::remove_file(&body_file).unwrap();
+        let result = parse(&mut g_env, test_in);
+        let expected = format!("body file {} does not exist", body_file.display());
+        assert!(
+            result.contains("executed (ERROR)") && result.contains(&expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+    }
+    {
+        g_env.env["name"] = serde_json::json!("Jane");
+        g_env.env["address"] = serde_json::json!("1 Main St\nSpringfield");
+        let test_in = r#"###{
+# @debug
+POST https://reqbin.com/echo/post/json
+Content-Type: application/json
+
+{
+  "{{.name | ascii_downcase}}": "{{.name}} Doe",
+  "address": "{{.address}}"
+}
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+# @debug
+POST https://reqbin.com/echo/post/json
+Content-Type: application/json
+
+{
+  "{{.name | ascii_downcase}}": "{{.name}} Doe",
+  "address": "{{.address}}"
+}
+########## RESULT
+curl -k --include https://reqbin.com/echo/post/json -X POST -H "Content-Type: application/json" -d '{  "jane": "Jane Doe",  "address": "1 Main St
+Springfield"}'
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let template_dir = std::env::temp_dir();
+        let template_file = template_dir.join("vim-rest-client-template.json");
+        fs::write(&template_file, "{\n  \"name\": \"{{.name}}\",\n  \"id\": {{.id}}\n}\n").unwrap();
+        g_env.env["templateDir"] = serde_json::json!(template_dir.to_string_lossy());
+        g_env.env["id"] = serde_json::json!(7);
+        let test_in = r#"###{
+# @debug
+# @body-template {{.templateDir}}/vim-rest-client-template.json
+POST https://reqbin.com/echo/post/json
+###}"#;
+        let expected = "-X POST -d '{\n  \"name\": \"Jane\",\n  \"id\": 7\n}'";
+        let result = parse(&mut g_env, test_in);
+        assert!(
+            result.contains(expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+        fs::remove_file(&template_file).unwrap();
+        let result = parse(&mut g_env, test_in);
+        let expected = format!("body template {} does not exist", template_file.display());
+        assert!(
+            result.contains("executed (ERROR)") && result.contains(&expected),
+            "Expected output should contain:\n{}\nResponse:\n{}",
+            expected,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{ skipped
+# @skip
+@skipped = "set"
+GET https://reqbin.com/echo/get/json
+###}
+###{ skip if
+@created = false
+# @skip-if .created == false
+@teardown = "done"
+###}
+###{ not skipped
+# @skip-if .created == true
+@created = true
+###}"#;
+        let test_out = r#"###{ skipped executed (SKIPPED)
+# @skip
+@skipped = "set"
+GET https://reqbin.com/echo/get/json
+########## skipped RESULT
+###}
+###{ skip if executed (SKIPPED)
+@created = false
+# @skip-if .created == false
+@teardown = "done"
+########## skip if RESULT
+@created = false
+###}
+###{ not skipped executed (SUCCESS)
+# @skip-if .created == true
+@created = true
+########## not skipped RESULT
+@created = true
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+        let test_in = r#"###{
+# @skip-if .skipped
+###}"#;
+        let test_out = r#"###{ executed (ERROR)
+# @skip-if .skipped
+########## ERROR
+failed to get resource at .skipped
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    {
+        let test_in = r#"###{ partial
+@rolledBack = "set"
+@failed = {{.dne}}
+###}"#;
+        let test_out = r#"###{ partial executed (ERROR)
+@rolledBack = "set"
+@failed = {{.dne}}
+########## partial ERROR
+@rolledBack = "set"
+@failed: line 2: failed to get resource at .dne
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+        let env_file = fs::read_to_string(ENV_FILE).unwrap();
+        assert!(
+            !env_file.contains("rolledBack"),
+            "Expected variables from the failed fold to be rolled back\nEnv file:\n{}",
+            env_file
+        );
+    }
+//    {
+//        let test_in = r#"###{ missing cert
+//# @cert /dne/client.pem
+//GET https://reqbin.com/echo/get/json
+//###}"#;
+//        let should_contain = r#"###{ missing cert executed (ERROR)
+//# @cert /dne/client.pem
+//GET https://reqbin.com/echo/get/json
+//########## missing cert ERROR
+//"#;
+//        let result = parse(&mut g_env, test_in);
+//        assert!(
+//            result.contains(should_contain),
+//            "Expected output should contain:\n{}\nResponse:\n{}",
+//            should_contain,
+//            result
+//        );
+//        assert!(
+//            result.contains("curl: (58)"),
+//            "Expected output should contain curl's certificate error\nResponse:\n{}",
+//            result
+//        );
+//    }
+
+    clear_env_file();
+}
+
+#[test]
+fn test_fold_results() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"text before
+###{ first
+@first = 1
+###}
+
+###{ second
+@second = {{.dne}}
+###}"#;
+    let results = g_env.parse_input(&mut test_in.as_bytes(), false);
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].title, "");
+    assert_eq!(results[0].input, "text before");
+    assert!(!results[0].error);
+    assert_eq!(results[1].title, "first");
+    assert_eq!(results[1].input, "@first = 1\n");
+    assert_eq!(results[1].output, "@first = 1\n");
+    assert!(!results[1].error);
+    assert_eq!(results[2].input, "");
+    assert_eq!(results[3].title, "second");
+    assert_eq!(results[3].output, "@second: line 1: failed to get resource at .dne\n");
+    assert!(results[3].error);
+    assert_eq!(
+        strip_durations(&results[3].render()),
+        r#"###{ second executed (ERROR)
+@second = {{.dne}}
+########## second ERROR
+@second: line 1: failed to get resource at .dne
+###}"#
+    );
+
+    clear_env_file();
+}
+
+#[test]
+fn test_error_line() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"###{ lines
+@lineOne = 1
+# a comment isn't counted
+@lineTwo = 2
+
+@lineThree = {{.dne}}
+@lineFour = 4
+@lineFive = 5
+###}"#;
+    let results = g_env.parse_input(&mut test_in.as_bytes(), false);
+    assert!(results[0].error);
+    assert_eq!(
+        results[0].output,
+        "@lineOne = 1\n@lineTwo = 2\n\n@lineThree: line 3: failed to get resource at .dne\n"
+    );
+
+    clear_env_file();
+}
+
+#[test]
+fn test_fold_stream() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"text before
+more text
+###{ first
+@streamFirst = 1
+###}
+between
+###{ second
+@streamSecond = 2
+###}"#;
+    {
+        let mut stream = g_env.parse_fold_stream(test_in.as_bytes(), false);
+        let text = stream.next().unwrap();
+        assert!(text.is_text);
+        assert_eq!(text.input, "text before\nmore text");
+        let first = stream.next().unwrap();
+        assert!(!first.is_text);
+        assert_eq!(first.title, "first");
+        assert_eq!(first.output, "@streamFirst = 1\n");
+        let text = stream.next().unwrap();
+        assert!(text.is_text);
+        assert_eq!(text.input, "between");
+        let second = stream.next().unwrap();
+        assert_eq!(second.title, "second");
+        assert!(stream.next().is_none());
+    }
+    {
+        // the folds are only executed as the stream is read
+        g_env.env = serde_json::json!({});
+        let mut stream = g_env.parse_fold_stream(test_in.as_bytes(), false);
+        stream.next();
+        stream.next();
+        drop(stream);
+        assert_eq!(g_env.env.get("streamFirst"), Some(&serde_json::json!(1)));
+        assert_eq!(g_env.env.get("streamSecond"), None);
+    }
+    {
+        let results = g_env.parse_fold_stream(test_in.as_bytes(), false)
+            .filter(|result| !result.is_text)
+            .collect::<Vec<FoldResult>>();
+        assert_eq!(results.len(), 2);
+    }
+
+    clear_env_file();
+}
+
+#[test]
+fn test_isolated_scope() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"###{ outer
+@outerVar = 1
+###{ inner
+# @export exported
+@innerVar = 2
+@exported = 3
+###}
+@checkExported = {{.exported}}
+@checkInner = "{{.innerVar // "unset"}}"
+###}"#;
+    {
+        g_env.scope = EnvScope::Isolated;
+        let results = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let rendered = strip_durations(&results[0].render());
+        assert!(rendered.contains("@checkExported = 3\n"), "Got:\n{}", rendered);
+        assert!(rendered.contains("@checkInner = \"unset\"\n"), "Got:\n{}", rendered);
+        assert!(!results[0].error);
+        assert_eq!(g_env.env.get("innerVar"), None);
+        assert_eq!(g_env.env.get("exported"), Some(&serde_json::json!(3)));
+    }
+    {
+        g_env.env = serde_json::json!({});
+        g_env.scope = EnvScope::Inherited;
+        let results = g_env.parse_input(&mut test_in.as_bytes(), false);
+        let rendered = strip_durations(&results[0].render());
+        assert!(rendered.contains("@checkInner = \"2\"\n"), "Got:\n{}", rendered);
+        assert!(!results[0].error);
+    }
+    {
+        // the folds in a loop are nested in it, so they are isolated too
+        g_env.env = serde_json::json!({"i": 0});
+        g_env.scope = EnvScope::Isolated;
+        let loop_in = r#"###{ while {{.i < 2}}
+###{
+# @export i
+@i = {{.i + 1}}
+@loopVar = {{.i}}
+###}
+###} endwhile"#;
+        let results = g_env.parse_input(&mut loop_in.as_bytes(), false);
+        assert!(!results[0].error, "Got:\n{}", results[0].render());
+        assert_eq!(g_env.env.get("i"), Some(&serde_json::json!(2)));
+        assert_eq!(g_env.env.get("loopVar"), None);
+    }
+    {
+        // an exported temporary variable isn't written to the env file
+        g_env.env = serde_json::json!({});
+        let tmp_in = r#"###{
+###{
+# @export tmpExported
+@tmp tmpExported = 4
+###}
+###}"#;
+        let results = g_env.parse_input(&mut tmp_in.as_bytes(), false);
+        assert!(!results[0].error, "Got:\n{}", results[0].render());
+        assert_eq!(g_env.env.get("tmpExported"), Some(&serde_json::json!(4)));
+        let file_str = fs::read_to_string(ENV_FILE).unwrap();
+        assert!(!file_str.contains("tmpExported"), "Got:\n{}", file_str);
+        g_env.scope = EnvScope::Inherited;
+    }
+
+    clear_env_file();
+}
+
+#[test]
+fn test_durations() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"###{ request
+# @debug
+GET https://reqbin.com/echo/get/json
+###}
+###{ vars
+@durationVar = 1
+###{ nested
+@nestedVar = 2
+###}
+###}"#;
+    let results = g_env.parse_input(&mut test_in.as_bytes(), false);
+    let rendered = results.iter().map(|r| r.render()).collect::<Vec<_>>().join("\n");
+    let duration_re = Regex::new(r"executed \(SUCCESS, (\d+)ms\)").unwrap();
+    let durations = duration_re.captures_iter(&rendered)
+        .map(|caps| caps[1].parse::<u64>())
+        .collect::<Vec<_>>();
+    assert_eq!(durations.len(), 3, "Expected 3 durations, got:\n{}", rendered);
+    assert!(durations.iter().all(|ms| ms.is_ok()), "Got:\n{}", rendered);
+
+    clear_env_file();
+}
+
+#[test]
+fn test_named_env() {
+    // without a base env in the home directory, only the named env is used
+    let home = std::env::temp_dir().join(format!("vim-rest-client-home-{}", std::process::id()));
+    fs::create_dir_all(&home).unwrap();
+    std::env::set_var("HOME", &home);
+    let staging = named_env_file("staging");
+    assert_eq!(staging, ".env.staging.json");
+    fs::write(&staging, r#"{"baseUrl": "https://staging.example.com"}"#).unwrap();
+    {
+        let mut g_env = GlobalEnv::new(Some(&staging));
+        let test_in = r#"###{
+@testUrl = "{{.baseUrl}}/test"
+###}"#;
+        let test_out = r#"###{ executed (SUCCESS)
+@testUrl = "{{.baseUrl}}/test"
+########## RESULT
+@testUrl = "https://staging.example.com/test"
+###}"#;
+        let result = parse(&mut g_env, test_in);
+        assert_eq!(
+            result,
+            String::from(test_out),
+            "Expected:\n{}\nGot:\n{}",
+            test_out,
+            result
+        );
+    }
+    let file_str = fs::read_to_string(&staging).unwrap();
+    assert!(file_str.contains("testUrl"), "{} should contain testUrl", staging);
+    assert!(fs::metadata(ENV_FILE).is_err(), "{} should not be written", ENV_FILE);
+    fs::remove_file(&staging).unwrap();
+    fs::remove_dir_all(&home).unwrap();
+
+    // --env needs the name of the environment
+    let res = Command::new(env!("CARGO_BIN_EXE_vim-rest-client"))
+        .arg("--env")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!res.status.success());
+    assert!(String::from_utf8(res.stdout).unwrap().starts_with("Usage"));
+}
+
+#[test]
+fn test_sensitive() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"###{
+# @sensitive token
+# @sensitive *_key
+@token = "s3cr3t-value"
+@auth = "Bearer {{.token}}"
+@api_key = 12345
+@user = "jane"
+###}"#;
+    let test_out = r#"###{ executed (SUCCESS)
+# @sensitive token
+# @sensitive *_key
+@token = "s3cr3t-value"
+@auth = "Bearer {{.token}}"
+@api_key = 12345
+@user = "jane"
+########## RESULT
+@token = [REDACTED]
+@auth = "Bearer [REDACTED]"
+@api_key = [REDACTED]
+@user = "jane"
+###}"#;
+    let result = parse(&mut g_env, test_in);
+    assert_eq!(
+        result,
+        String::from(test_out),
+        "Expected:\n{}\nGot:\n{}",
+        test_out,
+        result
+    );
+    let output = result.split("########## RESULT").last().unwrap();
+    assert!(!output.contains("s3cr3t-value"), "Secret in output:\n{}", output);
+    // the values are still usable
+    let auth = g_env.parse_selectors(&String::from("{{.auth}} {{.api_key}}")).unwrap();
+    assert_eq!(auth, "Bearer s3cr3t-value 12345");
+
+    clear_env_file();
+}
+
+#[test]
+fn test_sensitive_short_values() {
+    let mut g_env = GlobalEnv::new(None);
+    let test_in = r#"###{
+# @sensitive pin
+# @sensitive api_key
+@pin = 1
+@api_key = 12345
+@version = "1.1"
+@count = 10
+@order = 123456
+@header = "X-Key: {{.api_key}}"
+###}"#;
+    let test_out = r#"###{ executed (SUCCESS)
+# @sensitive pin
+# @sensitive api_key
+@pin = 1
+@api_key = 12345
+@version = "1.1"
+@count = 10
+@order = 123456
+@header = "X-Key: {{.api_key}}"
+########## RESULT
+@pin = [REDACTED]
+@api_key = [REDACTED]
+@version = "1.1"
+@count = 10
+@order = 123456
+@header = "X-Key: [REDACTED]"
+###}"#;
+    let result = parse(&mut g_env, test_in);
+    assert_eq!(
+        result,
+        String::from(test_out),
+        "Expected:\n{}\nGot:\n{}",
+        test_out,
+        result
+    );
+
+    clear_env_file();
+}
+
+#[test]
+fn test_import() {
+    let mut g_env = GlobalEnv::new(None);
+    let dir = std::env::temp_dir();
+    let first = dir.join("vim-rest-client-first.json");
+    let second = dir.join("vim-rest-client-second.env");
+    fs::write(&first, format!(r#"{{"importDir": "{}", "host": "example.com"}}"#, dir.display())).unwrap();
+    fs::write(&second, "API_PATH=/api/v1\nPORT=8080\n").unwrap();
+    let test_in = format!(r#"###{{
+# @import {}
+# @import {{{{.importDir}}}}/vim-rest-client-second.env
+@url = "https://{{{{.host}}}}:{{{{.PORT}}}}{{{{.API_PATH}}}}"
+###}}"#, first.display());
+    let test_out = format!(r#"###{{ executed (SUCCESS)
+# @import {0}
+# @import {{{{.importDir}}}}/vim-rest-client-second.env
+@url = "https://{{{{.host}}}}:{{{{.PORT}}}}{{{{.API_PATH}}}}"
+########## RESULT
+imported {0}
+imported {1}
+@url = "https://example.com:8080/api/v1"
+###}}"#, first.display(), second.display());
+    let result = parse(&mut g_env, &test_in);
+    assert_eq!(
+        result,
+        test_out,
+        "Expected:\n{}\nGot:\n{}",
+        test_out,
+        result
+    );
+    // imported variables aren't saved to the env file
+    let file_str = fs::read_to_string(ENV_FILE).unwrap();
+    assert!(!file_str.contains("importDir"), "Imported variable in env file: {}", file_str);
+    assert!(file_str.contains("url"), "Env file should contain url: {}", file_str);
+
+    fs::remove_file(&second).unwrap();
+    let result = parse(&mut g_env, &test_in);
+    assert!(
+        result.starts_with("###{ executed (ERROR)") && result.contains("No such file"),
+        "Expected import error, got:\n{}",
+        result
+    );
+    fs::remove_file(&first).unwrap();
+
+    clear_env_file();
+}
+
+#[test]
+fn test_dry_run() {
+    let mut g_env = GlobalEnv::new(None);
+    g_env.dry_run = true;
+    let test_in = r#"###{
+@id = 5
+# @name resp
+GET https://reqbin.com/echo/get/json?id={{.id}}
+###{ nested
+POST https://reqbin.com/echo/post/json
+###}
+###}
+@i = 0
+###{ while {{.i < 2}}
+@i = {{.i + 1}}
+###{
+# @name loopResp
+GET https://reqbin.com/echo/get/json?i={{.i}}
+###}
+###} endwhile"#;
+    let test_out = r#"###{ executed (SUCCESS)
+@id = 5
+# @name resp
+GET https://reqbin.com/echo/get/json?id={{.id}}
+###{ nested executed (SUCCESS)
+POST https://reqbin.com/echo/post/json
+###}
+########## RESULT
+@id = 5
+curl -k --include https://reqbin.com/echo/get/json?id=5 -X GET
+### nested RESULT
+curl -k --include https://reqbin.com/echo/post/json -X POST
+###
+###}
+@i = 0
+###{ while {{.i < 2}} executed (SUCCESS)
+@i = {{.i + 1}}
+###{ executed (SUCCESS)
+# @name loopResp
+GET https://reqbin.com/echo/get/json?i={{.i}}
+###}
+########## while {{.i < 2}} RESULT
+@i = 2
+### RESULT
+curl -k --include https://reqbin.com/echo/get/json?i=2 -X GET
+###
+###} endwhile"#;
+    let result = parse(&mut g_env, test_in);
+    assert_eq!(
+        result,
+        String::from(test_out),
+        "Expected:\n{}\nGot:\n{}",
+        test_out,
+        result
+    );
+    let file_str = fs::read_to_string(ENV_FILE).unwrap();
+    assert!(file_str.contains("\"id\": 5"), "Variables should be set: {}", file_str);
+    assert!(!file_str.contains("resp"), "Responses should not be stored: {}", file_str);
+
+    clear_env_file();
+}
+
+#[test]
+fn test_output_file() {
+    let output_file = std::env::temp_dir().join("vim-rest-client-output.txt");
+    fs::write(&output_file, "old output").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_vim-rest-client"))
+        .arg("--output")
+        .arg(&output_file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"###{\n@outputVar = 1\n###}\n").unwrap();
+    let res = child.wait_with_output().unwrap();
+    assert!(res.status.success());
+    let stdout = String::from_utf8(res.stdout).unwrap();
+    assert!(stdout.contains("@outputVar = 1\n###}"), "Got:\n{}", stdout);
+    assert_eq!(fs::read_to_string(&output_file).unwrap(), stdout);
+    fs::remove_file(&output_file).unwrap();
+
+    clear_env_file();
+}
+
+#[test]
+fn test_batch() {
+    let dir = std::env::temp_dir().join(format!("vim-rest-client-batch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.rest"), "###{\n@batchVar = 1\n###}\n").unwrap();
+    fs::write(dir.join("b.rest"), "###{\n@batchOther = {{batchMissing}}\n###}\n").unwrap();
+    fs::write(dir.join("c.rest"), "###{\n@batchCopy = {{.batchVar}}\n###}\n").unwrap();
+    let pattern = dir.join("*.rest");
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_vim-rest-client"))
+            .arg("--batch")
+            .arg(&pattern)
+            .args(args)
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap()
+    };
+
+    // the error in b.rest doesn't stop a.rest, but fails the run
+    let res = run(&[]);
+    assert!(!res.status.success());
+    let stdout = String::from_utf8(res.stdout).unwrap();
+    assert!(stdout.contains("@batchVar = 1\n###}"), "Got:\n{}", stdout);
+    assert!(stdout.contains("########## ERROR"), "Got:\n{}", stdout);
+    let rows = stdout.lines()
+        .map(|line| line.split_whitespace().take(3).collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    assert!(rows.contains(&format!("{} 1 0", dir.join("a.rest").display())), "Got:\n{}", stdout);
+    assert!(rows.contains(&format!("{} 0 1", dir.join("b.rest").display())), "Got:\n{}", stdout);
+    // c.rest doesn't see the variable a.rest saved to the env file
+    assert!(rows.contains(&format!("{} 0 1", dir.join("c.rest").display())), "Got:\n{}", stdout);
+    assert!(rows.contains(&String::from("TOTAL 1 2")), "Got:\n{}", stdout);
+
+    let res = run(&["--shared-env"]);
+    assert!(!res.status.success());
+    let stdout = String::from_utf8(res.stdout).unwrap();
+    let rows = stdout.lines()
+        .map(|line| line.split_whitespace().take(3).collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    assert!(rows.contains(&format!("{} 1 0", dir.join("c.rest").display())), "Got:\n{}", stdout);
+    assert!(stdout.contains("@batchCopy = 1\n###}"), "Got:\n{}", stdout);
+
+    let res = run(&["--batch-summary"]);
+    assert!(!res.status.success());
+    let stdout = String::from_utf8(res.stdout).unwrap();
+    assert!(stdout.starts_with("FILE"), "Got:\n{}", stdout);
+    assert!(!stdout.contains("###{"), "Got:\n{}", stdout);
+
+    for arg in ["--diff", "--validate-syntax"] {
+        let res = run(&[arg]);
+        assert!(!res.status.success());
+        assert!(String::from_utf8(res.stdout).unwrap().starts_with("Usage"), "{} should be rejected", arg);
+    }
+
+    let res = Command::new(env!("CARGO_BIN_EXE_vim-rest-client"))
+        .arg("--batch")
+        .arg(dir.join("*.missing"))
+        .stdout(Stdio::piped())
+        .output()
+        .unwrap();
+    assert!(!res.status.success());
+    assert_eq!(String::from_utf8(res.stdout).unwrap(), format!("no files matched {}\n", dir.join("*.missing").display()));
+
+    fs::remove_dir_all(&dir).unwrap();
+    clear_env_file();
+}
+
+#[test]
+fn test_verify() {
+    let mut g_env = GlobalEnv::new(None);
+    let cases = [
+        (true, "", "curl -k --include"),
+        (true, "# @verify\n", "curl --include"),
+        (true, "# @no-verify\n", "curl -k --include"),
+        (false, "", "curl --include"),
+        (false, "# @verify\n", "curl --include"),
+        (false, "# @no-verify\n", "curl -k --include"),
+    ];
+    for (insecure, flag, expected) in cases {
+        g_env.insecure = insecure;
+        let test_in = format!("###{{\n# @debug\n{}GET https://reqbin.com/echo/get/json\n###}}", flag);
+        let result = parse(&mut g_env, &test_in);
+        assert!(
+            result.contains(&format!("{} https://reqbin.com", expected)),
+            "Expected {} with insecure {} and flag {:?}, got:\n{}",
+            expected,
+            insecure,
+            flag,
+            result
+        );
+    }
 
     clear_env_file();
 }